@@ -0,0 +1,156 @@
+// Helpers for measuring and adjusting the level of rendered audio.
+
+/// Returns the largest absolute sample value.
+pub fn peak(samples: &[f32]) -> f32 {
+    samples
+        .iter()
+        .fold(0.0, |peak, sample| f32::max(peak, sample.abs()))
+}
+
+/// Returns the root-mean-square level of the samples, which is a rough
+/// measure of their perceived loudness.
+pub fn rms(samples: &[f32]) -> f32 {
+    if samples.is_empty() {
+        return 0.0;
+    }
+    let sum_of_squares: f64 = samples.iter().map(|s| (*s as f64) * (*s as f64)).sum();
+    (sum_of_squares / samples.len() as f64).sqrt() as f32
+}
+
+/// Converts an amplitude (where 1.0 is full scale) to dBFS.
+pub fn amplitude_to_db(amplitude: f32) -> f32 {
+    20.0 * amplitude.log10()
+}
+
+/// Converts dBFS to an amplitude (where 1.0 is full scale).
+pub fn db_to_amplitude(db: f32) -> f32 {
+    10.0f32.powf(db / 20.0)
+}
+
+/// Keeps track of the peak level of each channel of some audio, as it's
+/// rendered, and whether any of it clipped (went past full scale).
+#[derive(Clone, Debug)]
+pub struct PeakMeter {
+    peaks: Vec<f32>,
+}
+
+impl PeakMeter {
+    pub fn new(channels: usize) -> Self {
+        PeakMeter {
+            peaks: vec![0.0; channels],
+        }
+    }
+
+    /// Measures interleaved samples with the given number of channels.
+    pub fn measure(samples: &[f32], channels: usize) -> Self {
+        let mut meter = PeakMeter::new(channels);
+        for frame in samples.chunks(channels) {
+            meter.add_frame(frame);
+        }
+        meter
+    }
+
+    /// Adds one sample for each channel.
+    pub fn add_frame(&mut self, frame: &[f32]) {
+        for (peak, sample) in self.peaks.iter_mut().zip(frame) {
+            *peak = peak.max(sample.abs());
+        }
+    }
+
+    /// Returns the peak level of each channel, where 1.0 is full scale.
+    pub fn peaks(&self) -> &[f32] {
+        &self.peaks
+    }
+
+    pub fn has_clipped(&self) -> bool {
+        self.peaks.iter().any(|peak| *peak > 1.0)
+    }
+}
+
+fn apply_gain(samples: &mut [f32], current_level: f32, target_db: f32) {
+    if current_level == 0.0 {
+        // It's silence, there's nothing we can do.
+        return;
+    }
+    let gain = db_to_amplitude(target_db) / current_level;
+    for sample in samples.iter_mut() {
+        *sample *= gain;
+    }
+}
+
+/// Scales the samples so that the loudest one hits the target level.
+/// This guarantees there's no clipping, but songs that peak at the same
+/// level can still sound very different in volume.
+pub fn normalize_peak(samples: &mut [f32], target_db: f32) {
+    apply_gain(samples, peak(samples), target_db);
+}
+
+/// Scales the samples so that their overall RMS level hits the target.
+/// This is better at matching perceived volume across songs, but can
+/// push peaks past full scale if the target is too high.
+pub fn normalize_loudness(samples: &mut [f32], target_db: f32) {
+    apply_gain(samples, rms(samples), target_db);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        amplitude_to_db, db_to_amplitude, normalize_loudness, normalize_peak, peak, rms, PeakMeter,
+    };
+
+    fn assert_close(a: f32, b: f32) {
+        assert!((a - b).abs() < 0.001, "{} is not close to {}", a, b);
+    }
+
+    #[test]
+    fn test_peak_works() {
+        assert_eq!(peak(&[0.1, -0.5, 0.25]), 0.5);
+        assert_eq!(peak(&[]), 0.0);
+    }
+
+    #[test]
+    fn test_rms_works() {
+        assert_close(rms(&[0.5, -0.5, 0.5, -0.5]), 0.5);
+        assert_close(rms(&[1.0, 0.0]), 0.5f32.sqrt());
+        assert_eq!(rms(&[]), 0.0);
+    }
+
+    #[test]
+    fn test_db_conversion_works() {
+        assert_close(amplitude_to_db(1.0), 0.0);
+        assert_close(amplitude_to_db(0.5), -6.0206);
+        assert_close(db_to_amplitude(-6.0206), 0.5);
+    }
+
+    #[test]
+    fn test_normalize_peak_works() {
+        let mut samples = [0.1, -0.25, 0.2];
+        normalize_peak(&mut samples, 0.0);
+        assert_close(peak(&samples), 1.0);
+        assert_close(samples[0], 0.4);
+    }
+
+    #[test]
+    fn test_normalize_loudness_works() {
+        let mut samples = [0.1, -0.1, 0.1, -0.1];
+        normalize_loudness(&mut samples, -6.0206);
+        assert_close(rms(&samples), 0.5);
+    }
+
+    #[test]
+    fn test_normalize_leaves_silence_alone() {
+        let mut samples = [0.0, 0.0];
+        normalize_loudness(&mut samples, 0.0);
+        normalize_peak(&mut samples, 0.0);
+        assert_eq!(samples, [0.0, 0.0]);
+    }
+
+    #[test]
+    fn test_peak_meter_works() {
+        let meter = PeakMeter::measure(&[0.5, -0.25, -0.75, 0.1], 2);
+        assert_eq!(meter.peaks(), &[0.75, 0.25]);
+        assert!(!meter.has_clipped());
+        assert!(PeakMeter::measure(&[0.5, -1.5], 2).has_clipped());
+        assert!(!PeakMeter::measure(&[1.0, -1.0], 2).has_clipped());
+    }
+}
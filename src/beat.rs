@@ -1,7 +1,7 @@
 pub const THREE_FOUR: TimeSignature = TimeSignature(3, Beat::Quarter);
 pub const FOUR_FOUR: TimeSignature = TimeSignature(4, Beat::Quarter);
 
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Copy, Clone, PartialEq)]
 #[allow(dead_code)]
 pub enum Beat {
     Whole,
@@ -35,9 +35,111 @@ impl Beat {
             Beat::SixtyFourth => 1,
         }
     }
+
+    /// Returns the undotted beat that's written as the given denominator
+    /// of a time signature, e.g. `Beat::Eighth` for 8.
+    pub fn from_denominator(denominator: u64) -> Option<Beat> {
+        match denominator {
+            1 => Some(Beat::Whole),
+            2 => Some(Beat::Half),
+            4 => Some(Beat::Quarter),
+            8 => Some(Beat::Eighth),
+            16 => Some(Beat::Sixteenth),
+            32 => Some(Beat::ThirtySecond),
+            64 => Some(Beat::SixtyFourth),
+            _ => None,
+        }
+    }
+
+    /// Returns this beat with a dot, which makes it half again as long,
+    /// e.g. a dotted half is as long as three quarters.
+    pub fn dotted(self) -> NoteValue {
+        NoteValue::from(self).dotted()
+    }
+
+    /// Returns this beat as part of a triplet, i.e. three of them in the
+    /// time of two.
+    pub fn triplet(self) -> NoteValue {
+        NoteValue::from(self).tuplet(3, 2)
+    }
 }
 
-#[derive(Copy, Clone)]
+/// How many ticks `BeatCounter` splits each 64th beat into. It's divisible
+/// by 3, 5 and 7 (and by 8, for up to three dots on a 64th), so the usual
+/// tuplets add up exactly.
+pub const TICKS_PER_SIXTY_FOURTH_BEAT: u64 = 840;
+
+/// The length of a note as it's written in sheet music: a beat, along
+/// with any dots and tuplet it's part of. Plain beats convert into one, so
+/// anything that takes a `NoteValue` also takes a `Beat`.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct NoteValue {
+    pub beat: Beat,
+    /// Each dot adds half the length added by the one before it, so one
+    /// dot makes the note 1.5 times as long, and two make it 1.75.
+    pub dots: u8,
+    /// For a note that's part of a tuplet, how many of those notes are
+    /// played (first) in the time it usually takes to play some other
+    /// number of them (second), e.g. `(3, 2)` for a triplet.
+    pub tuplet: Option<(u64, u64)>,
+}
+
+impl NoteValue {
+    /// Adds another dot to the note.
+    pub fn dotted(self) -> NoteValue {
+        NoteValue {
+            dots: self.dots + 1,
+            ..self
+        }
+    }
+
+    /// Makes the note part of a tuplet of `notes` in the time of
+    /// `in_time_of`, e.g. `tuplet(5, 4)` for a quintuplet.
+    pub fn tuplet(self, notes: u64, in_time_of: u64) -> NoteValue {
+        NoteValue {
+            tuplet: Some((notes, in_time_of)),
+            ..self
+        }
+    }
+
+    /// Returns the length of the note in ticks (see
+    /// `TICKS_PER_SIXTY_FOURTH_BEAT`), rounded to the nearest tick for the
+    /// rare tuplets that don't divide evenly.
+    pub fn ticks(&self) -> u64 {
+        let base = self.beat.sixty_fourth_beats() * TICKS_PER_SIXTY_FOURTH_BEAT;
+        let mut ticks = base;
+        let mut dot = base;
+        for _ in 0..self.dots {
+            dot /= 2;
+            ticks += dot;
+        }
+        match self.tuplet {
+            Some((notes, in_time_of)) if notes > 0 => {
+                let scaled = ticks as u128 * in_time_of as u128;
+                ((scaled + notes as u128 / 2) / notes as u128) as u64
+            }
+            _ => ticks,
+        }
+    }
+
+    /// Returns the length of the note in 64th beats, which may be
+    /// fractional.
+    pub fn sixty_fourth_beats(&self) -> f64 {
+        self.ticks() as f64 / TICKS_PER_SIXTY_FOURTH_BEAT as f64
+    }
+}
+
+impl From<Beat> for NoteValue {
+    fn from(beat: Beat) -> Self {
+        NoteValue {
+            beat,
+            dots: 0,
+            tuplet: None,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
 pub struct TimeSignature(pub u64, pub Beat);
 
 impl TimeSignature {
@@ -48,6 +150,54 @@ impl TimeSignature {
     pub fn beat_unit(&self) -> Beat {
         self.1
     }
+
+    pub fn sixty_fourth_beats_per_measure(&self) -> u64 {
+        self.0 * self.1.sixty_fourth_beats()
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum TimeSignatureParseError {
+    InvalidFormat,
+    InvalidBeatsPerMeasure,
+    InvalidBeatUnit,
+}
+
+impl TryFrom<&str> for TimeSignature {
+    type Error = TimeSignatureParseError;
+
+    /// Parses a time signature like "6/8". The denominator must be a power
+    /// of two, from 1 (whole notes) to 64.
+    fn try_from(value: &str) -> Result<TimeSignature, TimeSignatureParseError> {
+        let (beats, unit) = value
+            .split_once('/')
+            .ok_or(TimeSignatureParseError::InvalidFormat)?;
+        let beats = match beats.trim().parse::<u64>() {
+            Ok(beats) if beats > 0 => beats,
+            _ => return Err(TimeSignatureParseError::InvalidBeatsPerMeasure),
+        };
+        let unit = unit
+            .trim()
+            .parse::<u64>()
+            .ok()
+            .and_then(Beat::from_denominator)
+            .ok_or(TimeSignatureParseError::InvalidBeatUnit)?;
+        Ok(TimeSignature(beats, unit))
+    }
+}
+
+impl std::fmt::Display for TimeSignature {
+    /// Writes the time signature the way it's notated, e.g. "6/8". A dotted
+    /// beat unit is written in terms of the undotted beat a third its
+    /// length, so two dotted quarters per measure is also "6/8".
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let unit = self.1.sixty_fourth_beats();
+        if 64 % unit == 0 {
+            write!(f, "{}/{}", self.0, 64 / unit)
+        } else {
+            write!(f, "{}/{}", self.0 * 3, 64 / (unit / 3))
+        }
+    }
 }
 
 #[derive(Copy, Clone)]
@@ -57,20 +207,29 @@ pub struct BeatSettings {
 }
 
 impl BeatSettings {
-    pub fn new(bpm: u64, time_signature: TimeSignature) -> Self {
+    pub const fn new(bpm: u64, time_signature: TimeSignature) -> Self {
         Self {
             bpm,
             time_signature,
         }
     }
 
-    fn beats_in_duration(&self, length: Beat) -> f64 {
-        let beat_unit_64th_beats = self.time_signature.beat_unit().sixty_fourth_beats();
-        let length_64th_beats = length.sixty_fourth_beats();
-        length_64th_beats as f64 / beat_unit_64th_beats as f64
+    /// Returns these settings with the tempo multiplied by the given
+    /// factor, e.g. 0.5 for half speed. The result is rounded to the
+    /// nearest whole BPM.
+    pub fn with_tempo_scale(&self, factor: f64) -> Self {
+        Self {
+            bpm: ((self.bpm as f64 * factor).round() as u64).max(1),
+            ..*self
+        }
     }
 
-    pub fn duration_in_millis(&self, length: Beat) -> f64 {
+    fn beats_in_duration<L: Into<NoteValue>>(&self, length: L) -> f64 {
+        let beat_unit_ticks = NoteValue::from(self.time_signature.beat_unit()).ticks();
+        length.into().ticks() as f64 / beat_unit_ticks as f64
+    }
+
+    pub fn duration_in_millis<L: Into<NoteValue>>(&self, length: L) -> f64 {
         let beats_per_second = 60.0 / self.bpm as f64;
         let ms_per_beat = beats_per_second * 1000.0;
         ms_per_beat * self.beats_in_duration(length)
@@ -82,34 +241,125 @@ impl BeatSettings {
     }
 }
 
+/// A gradual change in tempo, from one BPM to another, over a span of
+/// music measured in ticks.
+#[derive(Copy, Clone)]
+struct TempoChange {
+    start: u64,
+    length: u64,
+    from_bpm: f64,
+    to_bpm: f64,
+}
+
+impl TempoChange {
+    fn bpm_at(&self, ticks: f64) -> f64 {
+        let progress = (ticks - self.start as f64) / self.length as f64;
+        self.from_bpm + (self.to_bpm - self.from_bpm) * progress.clamp(0.0, 1.0)
+    }
+}
+
 #[derive(Copy, Clone)]
 pub struct BeatCounter {
     settings: BeatSettings,
-    sixty_fourth_beats: u64,
+    ticks: u64,
+    total_millis: f64,
+    tempo_change: Option<TempoChange>,
 }
 
 impl BeatCounter {
     pub fn new(settings: BeatSettings) -> Self {
         BeatCounter {
             settings,
-            sixty_fourth_beats: 0,
+            ticks: 0,
+            total_millis: 0.0,
+            tempo_change: None,
+        }
+    }
+
+    /// Smoothly changes the tempo from the current BPM to the given one,
+    /// over the given length of music starting from the current position.
+    /// If an earlier change is still underway, the new one takes over from
+    /// wherever that one has got to.
+    pub fn change_tempo_gradually<L: Into<NoteValue>>(&mut self, to_bpm: u64, over: L) {
+        self.tempo_change = Some(TempoChange {
+            start: self.ticks,
+            length: over.into().ticks(),
+            from_bpm: self.bpm(),
+            to_bpm: to_bpm as f64,
+        });
+        self.settings.bpm = to_bpm;
+    }
+
+    /// Returns the tempo in effect at the current position, which is
+    /// partway between two tempos during a gradual change.
+    pub fn bpm(&self) -> f64 {
+        match self.tempo_change {
+            Some(change) => change.bpm_at(self.ticks as f64),
+            None => self.settings.bpm as f64,
         }
     }
 
     /// Increment the counter by the given length, returning the
     /// length's duration in milliseconds.
-    pub fn increment(&mut self, length: Beat) -> f64 {
-        self.sixty_fourth_beats += length.sixty_fourth_beats();
-        self.settings.duration_in_millis(length)
+    pub fn increment<L: Into<NoteValue>>(&mut self, length: L) -> f64 {
+        let length = length.into();
+        let start = self.ticks;
+        self.ticks += length.ticks();
+        let ms = match self.tempo_change {
+            Some(change) if start < change.start + change.length => {
+                // Add up the length of each 64th beat (or the part of one
+                // we're covering) at the tempo in effect halfway through it.
+                let at_60_bpm = BeatSettings::new(60, self.settings.time_signature);
+                let ms_per_tick_at_60_bpm = at_60_bpm.duration_in_millis(Beat::SixtyFourth)
+                    / TICKS_PER_SIXTY_FOURTH_BEAT as f64;
+                let mut ms = 0.0;
+                let mut position = start;
+                while position < self.ticks {
+                    let next_sixty_fourth =
+                        (position / TICKS_PER_SIXTY_FOURTH_BEAT + 1) * TICKS_PER_SIXTY_FOURTH_BEAT;
+                    let end = next_sixty_fourth.min(self.ticks);
+                    let midpoint = (position + end) as f64 / 2.0;
+                    ms += (end - position) as f64 * ms_per_tick_at_60_bpm * 60.0
+                        / change.bpm_at(midpoint);
+                    position = end;
+                }
+                ms
+            }
+            _ => self.settings.duration_in_millis(length),
+        };
+        self.total_millis += ms;
+        ms
+    }
+
+    /// Moves the counter back to the very beginning, e.g. to loop.
+    pub fn reset(&mut self) {
+        self.set_sixty_fourth_beats(0);
+    }
+
+    /// Moves the counter to the given number of measures from the
+    /// beginning, rounded to the nearest tick.
+    pub fn set_measures(&mut self, measures: f64) {
+        let ticks = measures * self.ticks_per_measure() as f64;
+        self.set_ticks(ticks.round().max(0.0) as u64);
+    }
+
+    /// Moves the counter to the given number of 64th beats from the
+    /// beginning. Since it's a jump rather than something that was
+    /// played, any gradual tempo change is dropped, and the elapsed time
+    /// is worked out as if everything up to there was at the current tempo.
+    pub fn set_sixty_fourth_beats(&mut self, sixty_fourth_beats: u64) {
+        self.set_ticks(sixty_fourth_beats * TICKS_PER_SIXTY_FOURTH_BEAT);
+    }
+
+    fn set_ticks(&mut self, ticks: u64) {
+        self.ticks = ticks;
+        self.tempo_change = None;
+        self.total_millis = self.settings.duration_in_millis(Beat::SixtyFourth) * ticks as f64
+            / TICKS_PER_SIXTY_FOURTH_BEAT as f64;
     }
 
     pub fn total_beats(&self) -> f64 {
-        self.sixty_fourth_beats as f64
-            / self
-                .settings
-                .time_signature
-                .beat_unit()
-                .sixty_fourth_beats() as f64
+        self.ticks as f64 / NoteValue::from(self.settings.time_signature.beat_unit()).ticks() as f64
     }
 
     pub fn total_measures(&self) -> f64 {
@@ -117,7 +367,33 @@ impl BeatCounter {
     }
 
     pub fn total_millis(&self) -> f64 {
-        self.total_measures() * self.settings.measure_in_millis()
+        self.total_millis
+    }
+
+    /// Returns how far into the current measure the counter is, from 0.0
+    /// (on the barline) up to, but not including, 1.0.
+    pub fn position_in_measure(&self) -> f64 {
+        (self.ticks % self.ticks_per_measure()) as f64 / self.ticks_per_measure() as f64
+    }
+
+    /// Returns how many whole 64th beats are left until the next barline.
+    /// This is a whole measure's worth on the barline itself.
+    pub fn sixty_fourth_beats_left_in_measure(&self) -> u64 {
+        self.ticks_left_in_measure() / TICKS_PER_SIXTY_FOURTH_BEAT
+    }
+
+    /// Like `sixty_fourth_beats_left_in_measure`, but in ticks, so it's
+    /// exact even partway through a tuplet.
+    pub fn ticks_left_in_measure(&self) -> u64 {
+        let per_measure = self.ticks_per_measure();
+        per_measure - self.ticks % per_measure
+    }
+
+    fn ticks_per_measure(&self) -> u64 {
+        self.settings
+            .time_signature
+            .sixty_fourth_beats_per_measure()
+            * TICKS_PER_SIXTY_FOURTH_BEAT
     }
 }
 
@@ -125,7 +401,46 @@ impl BeatCounter {
 mod tests {
     use crate::beat::Beat;
 
-    use super::{BeatCounter, BeatSettings, FOUR_FOUR};
+    use super::{
+        BeatCounter, BeatSettings, NoteValue, TimeSignature, TimeSignatureParseError, FOUR_FOUR,
+        THREE_FOUR,
+    };
+
+    #[test]
+    fn test_time_signature_parsing_works() {
+        assert_eq!("4/4".try_into(), Ok(FOUR_FOUR));
+        assert_eq!("3/4".try_into(), Ok(THREE_FOUR));
+        assert_eq!("6/8".try_into(), Ok(TimeSignature(6, Beat::Eighth)));
+        assert_eq!("2/2".try_into(), Ok(TimeSignature(2, Beat::Half)));
+        assert_eq!(" 7 / 16 ".try_into(), Ok(TimeSignature(7, Beat::Sixteenth)));
+    }
+
+    #[test]
+    fn test_invalid_time_signatures_are_rejected() {
+        let parse = |value| TimeSignature::try_from(value);
+        assert_eq!(parse("4"), Err(TimeSignatureParseError::InvalidFormat));
+        assert_eq!(
+            parse("x/4"),
+            Err(TimeSignatureParseError::InvalidBeatsPerMeasure)
+        );
+        assert_eq!(
+            parse("0/4"),
+            Err(TimeSignatureParseError::InvalidBeatsPerMeasure)
+        );
+        assert_eq!(parse("3/6"), Err(TimeSignatureParseError::InvalidBeatUnit));
+        assert_eq!(
+            parse("3/128"),
+            Err(TimeSignatureParseError::InvalidBeatUnit)
+        );
+    }
+
+    #[test]
+    fn test_time_signature_display_works() {
+        assert_eq!(FOUR_FOUR.to_string(), "4/4");
+        assert_eq!(TimeSignature(6, Beat::Eighth).to_string(), "6/8");
+        assert_eq!(TimeSignature(2, Beat::DottedQuarter).to_string(), "6/8");
+        assert_eq!(TimeSignature(1, Beat::DottedHalf).to_string(), "3/4");
+    }
 
     #[test]
     fn test_beat_settings_works() {
@@ -136,6 +451,18 @@ mod tests {
         assert_eq!(bs.measure_in_millis(), 4000.0);
     }
 
+    #[test]
+    fn test_with_tempo_scale_works() {
+        let bs = BeatSettings::new(80, FOUR_FOUR);
+        assert_eq!(bs.with_tempo_scale(0.5).bpm, 40);
+        assert_eq!(bs.with_tempo_scale(1.5).bpm, 120);
+        assert_eq!(bs.with_tempo_scale(0.0).bpm, 1);
+        assert_eq!(
+            bs.with_tempo_scale(0.5).measure_in_millis(),
+            bs.measure_in_millis() * 2.0
+        );
+    }
+
     #[test]
     fn test_beat_counter_works() {
         let bs = BeatSettings::new(60, FOUR_FOUR);
@@ -151,5 +478,131 @@ mod tests {
 
         assert_eq!(bc.total_measures(), 1.0);
         assert_eq!(bc.total_millis(), 4000.0);
+        assert_eq!(bc.position_in_measure(), 0.0);
+
+        assert_eq!(bc.sixty_fourth_beats_left_in_measure(), 64);
+
+        bc.increment(Beat::DottedQuarter);
+        assert_eq!(bc.position_in_measure(), 0.375);
+        assert_eq!(bc.sixty_fourth_beats_left_in_measure(), 40);
+    }
+
+    #[test]
+    fn test_note_values_work() {
+        let bs = BeatSettings::new(60, FOUR_FOUR);
+        assert_eq!(bs.duration_in_millis(Beat::Half.dotted()), 3000.0);
+        assert_eq!(bs.duration_in_millis(Beat::Half.dotted().dotted()), 3500.0);
+        assert_eq!(
+            NoteValue::from(Beat::DottedHalf).ticks(),
+            Beat::Half.dotted().ticks()
+        );
+        assert_eq!(
+            Beat::Eighth.triplet().ticks() * 3,
+            NoteValue::from(Beat::Quarter).ticks()
+        );
+        assert_eq!(
+            NoteValue::from(Beat::Sixteenth).tuplet(5, 4).ticks() * 5,
+            NoteValue::from(Beat::Quarter).ticks()
+        );
+        assert_eq!(Beat::SixtyFourth.triplet().sixty_fourth_beats(), 2.0 / 3.0);
+    }
+
+    #[test]
+    fn test_triplets_and_dotted_notes_add_up_to_a_measure() {
+        let mut bc = BeatCounter::new(BeatSettings::new(90, FOUR_FOUR));
+        for _ in 0..3 {
+            bc.increment(Beat::Eighth.triplet());
+        }
+        bc.increment(Beat::Quarter.dotted());
+        bc.increment(Beat::Eighth);
+        for _ in 0..6 {
+            bc.increment(Beat::Sixteenth.triplet());
+        }
+        assert_eq!(bc.total_measures(), 1.0);
+        assert_eq!(bc.position_in_measure(), 0.0);
+        assert_eq!(bc.sixty_fourth_beats_left_in_measure(), 64);
+
+        bc.increment(Beat::Quarter.triplet());
+        assert_eq!(bc.sixty_fourth_beats_left_in_measure(), 53);
+        assert_eq!(
+            bc.ticks_left_in_measure() % super::TICKS_PER_SIXTY_FOURTH_BEAT,
+            280
+        );
+    }
+
+    fn quarter_note_millis_during_tempo_change(to_bpm: u64) -> Vec<f64> {
+        let mut bc = BeatCounter::new(BeatSettings::new(60, FOUR_FOUR));
+        bc.increment(Beat::Quarter);
+        bc.change_tempo_gradually(to_bpm, Beat::Whole);
+        (0..6).map(|_| bc.increment(Beat::Quarter)).collect()
+    }
+
+    #[test]
+    fn test_slowing_down_works() {
+        let millis = quarter_note_millis_during_tempo_change(30);
+        assert!(millis[0] > 1000.0);
+        for i in 1..4 {
+            assert!(millis[i] > millis[i - 1], "{:?}", millis);
+        }
+        assert_eq!(&millis[4..], &[2000.0, 2000.0]);
+    }
+
+    #[test]
+    fn test_speeding_up_works() {
+        let millis = quarter_note_millis_during_tempo_change(120);
+        assert!(millis[0] < 1000.0);
+        for i in 1..4 {
+            assert!(millis[i] < millis[i - 1], "{:?}", millis);
+        }
+        assert_eq!(&millis[4..], &[500.0, 500.0]);
+    }
+
+    #[test]
+    fn test_back_to_back_tempo_changes_do_not_jump() {
+        let mut bc = BeatCounter::new(BeatSettings::new(60, FOUR_FOUR));
+        bc.change_tempo_gradually(120, Beat::Whole);
+        bc.increment(Beat::Half);
+        assert_eq!(bc.bpm(), 90.0);
+        // Halfway through, turn around and slow back down.
+        bc.change_tempo_gradually(60, Beat::Whole);
+        assert_eq!(bc.bpm(), 90.0);
+        let before = bc.total_millis();
+        let next = bc.increment(Beat::SixtyFourth);
+        // A 64th beat at 90 bpm, give or take the ramp within it.
+        let at_90_bpm = 1000.0 * 60.0 / 90.0 / 16.0;
+        assert!((next - at_90_bpm).abs() < 0.5, "{}", next);
+        assert!(bc.total_millis() > before);
+        bc.increment(Beat::Whole);
+        assert_eq!(bc.bpm(), 60.0);
+        assert_eq!(bc.increment(Beat::Quarter), 1000.0);
+    }
+
+    #[test]
+    fn test_set_position_works() {
+        let mut counter = BeatCounter::new(BeatSettings::new(120, FOUR_FOUR));
+        counter.increment(Beat::Whole);
+        counter.set_measures(2.5);
+        assert_eq!(counter.total_measures(), 2.5);
+        assert_eq!(counter.total_millis(), 5000.0);
+
+        counter.set_sixty_fourth_beats(16);
+        assert_eq!(counter.total_measures(), 0.25);
+        assert_eq!(counter.total_millis(), 500.0);
+
+        counter.increment(Beat::Quarter);
+        assert_eq!(counter.total_measures(), 0.5);
+
+        counter.reset();
+        assert_eq!(counter.total_measures(), 0.0);
+        assert_eq!(counter.total_millis(), 0.0);
+    }
+
+    #[test]
+    fn test_set_position_uses_tempo_after_change() {
+        let mut counter = BeatCounter::new(BeatSettings::new(120, FOUR_FOUR));
+        counter.change_tempo_gradually(60, Beat::Whole);
+        counter.set_measures(1.0);
+        assert_eq!(counter.total_millis(), 4000.0);
+        assert_eq!(counter.increment(Beat::Quarter), 1000.0);
     }
 }
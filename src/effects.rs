@@ -0,0 +1,565 @@
+// Effects that process rendered audio one sample at a time.
+//
+// Any randomness an effect needs (e.g. the starting phase of an LFO) is
+// drawn from the `Rng` it's created with. As long as that `Rng` is the one
+// created from `--seed`, running the same effect chain with the same seed
+// over the same audio always produces the same output, while changing the
+// seed varies it.
+
+use crate::beat::{Beat, BeatSettings};
+use crate::filter::{Biquad, Coefficients};
+use crate::rng::Rng;
+
+const TWO_PI: f64 = 2.0 * std::f64::consts::PI;
+
+pub trait Effect {
+    fn process(&mut self, sample: f32) -> f32;
+}
+
+pub trait StereoEffect {
+    fn process(&mut self, left: f32, right: f32) -> (f32, f32);
+}
+
+/// Runs every sample through each effect in the chain, in order.
+pub fn apply_effects(samples: &mut [f32], effects: &mut [Box<dyn Effect>]) {
+    for sample in samples.iter_mut() {
+        for effect in effects.iter_mut() {
+            *sample = effect.process(*sample);
+        }
+    }
+}
+
+/// Runs interleaved stereo samples (e.g. from `Player::render_to_stereo_vec`)
+/// through each effect in the chain, in order.
+pub fn apply_stereo_effects(samples: &mut [f32], effects: &mut [Box<dyn StereoEffect>]) {
+    for frame in samples.chunks_mut(2) {
+        for effect in effects.iter_mut() {
+            (frame[0], frame[1]) = effect.process(frame[0], frame[1]);
+        }
+    }
+}
+
+/// Applies a separate instance of a mono effect to each channel.
+pub struct PerChannel<E: Effect> {
+    left: E,
+    right: E,
+}
+
+impl<E: Effect> PerChannel<E> {
+    pub fn new(left: E, right: E) -> Self {
+        PerChannel { left, right }
+    }
+}
+
+impl<E: Effect> StereoEffect for PerChannel<E> {
+    fn process(&mut self, left: f32, right: f32) -> (f32, f32) {
+        (self.left.process(left), self.right.process(right))
+    }
+}
+
+/// A circular buffer of recent samples.
+pub struct DelayLine {
+    buffer: Vec<f32>,
+    write_pos: usize,
+}
+
+impl DelayLine {
+    pub fn new(max_delay_samples: usize) -> Self {
+        DelayLine {
+            buffer: vec![0.0; max_delay_samples + 2],
+            write_pos: 0,
+        }
+    }
+
+    pub fn push(&mut self, sample: f32) {
+        self.buffer[self.write_pos] = sample;
+        self.write_pos = (self.write_pos + 1) % self.buffer.len();
+    }
+
+    fn at(&self, delay: usize) -> f32 {
+        let len = self.buffer.len();
+        self.buffer[(self.write_pos + len - 1 - delay % len) % len]
+    }
+
+    /// Returns the sample pushed `delay` samples before the most recent
+    /// one, interpolating between samples if `delay` is fractional.
+    pub fn read(&self, delay: f64) -> f32 {
+        let whole = delay.floor();
+        let fraction = (delay - whole) as f32;
+        let a = self.at(whole as usize);
+        let b = self.at(whole as usize + 1);
+        a + (b - a) * fraction
+    }
+}
+
+/// A delay whose length is swept back and forth by an LFO, which is the
+/// basis of chorus and flanger effects.
+pub struct ModulatedDelay {
+    delay_line: DelayLine,
+    sample_rate: f64,
+    base_delay_ms: f64,
+    depth_ms: f64,
+    lfo_rate_hz: f64,
+    lfo_phase: f64,
+    feedback: f32,
+    mix: f32,
+}
+
+impl ModulatedDelay {
+    fn new(
+        sample_rate: usize,
+        rng: &mut Rng,
+        base_delay_ms: f64,
+        depth_ms: f64,
+        lfo_rate_hz: f64,
+        feedback: f32,
+        mix: f32,
+    ) -> Self {
+        let max_delay_ms = base_delay_ms + depth_ms;
+        ModulatedDelay {
+            delay_line: DelayLine::new((max_delay_ms * sample_rate as f64 / 1000.0) as usize + 1),
+            sample_rate: sample_rate as f64,
+            base_delay_ms,
+            depth_ms,
+            lfo_rate_hz,
+            lfo_phase: rng.next_f64(),
+            feedback,
+            mix,
+        }
+    }
+
+    pub fn chorus(sample_rate: usize, rng: &mut Rng) -> Self {
+        ModulatedDelay::new(sample_rate, rng, 20.0, 5.0, 0.8, 0.0, 0.5)
+    }
+
+    pub fn flanger(sample_rate: usize, rng: &mut Rng) -> Self {
+        ModulatedDelay::new(sample_rate, rng, 3.0, 2.0, 0.25, 0.5, 0.5)
+    }
+}
+
+impl Effect for ModulatedDelay {
+    fn process(&mut self, sample: f32) -> f32 {
+        let delay_ms = self.base_delay_ms + self.depth_ms * (self.lfo_phase * TWO_PI).sin();
+        let delayed = self.delay_line.read(delay_ms * self.sample_rate / 1000.0);
+        self.delay_line.push(sample + delayed * self.feedback);
+        self.lfo_phase = (self.lfo_phase + self.lfo_rate_hz / self.sample_rate) % 1.0;
+        sample * (1.0 - self.mix) + delayed * self.mix
+    }
+}
+
+/// A delay whose echoes bounce back and forth between the left and right
+/// channels, starting on the left. Each echo is `feedback` times as loud
+/// as the one before it.
+pub struct PingPongDelay {
+    left: DelayLine,
+    right: DelayLine,
+    delay_samples: f64,
+    feedback: f32,
+    mix: f32,
+}
+
+impl PingPongDelay {
+    pub fn new(sample_rate: usize, delay_ms: f64, feedback: f32, mix: f32) -> Self {
+        let delay_samples = (delay_ms * sample_rate as f64 / 1000.0).max(1.0);
+        PingPongDelay {
+            left: DelayLine::new(delay_samples as usize + 1),
+            right: DelayLine::new(delay_samples as usize + 1),
+            delay_samples,
+            feedback,
+            mix,
+        }
+    }
+
+    /// Returns a ping-pong delay whose echoes are the given length of music
+    /// apart.
+    pub fn with_beat(
+        sample_rate: usize,
+        beat_settings: BeatSettings,
+        length: Beat,
+        feedback: f32,
+        mix: f32,
+    ) -> Self {
+        let delay_ms = beat_settings.duration_in_millis(length);
+        PingPongDelay::new(sample_rate, delay_ms, feedback, mix)
+    }
+}
+
+impl StereoEffect for PingPongDelay {
+    fn process(&mut self, left: f32, right: f32) -> (f32, f32) {
+        // We haven't pushed this sample yet, so the most recent one is
+        // already a sample old.
+        let delayed_left = self.left.read(self.delay_samples - 1.0);
+        let delayed_right = self.right.read(self.delay_samples - 1.0);
+        self.left
+            .push((left + right) / 2.0 + delayed_right * self.feedback);
+        self.right.push(delayed_left * self.feedback);
+        (
+            left * (1.0 - self.mix) + delayed_left * self.mix,
+            right * (1.0 - self.mix) + delayed_right * self.mix,
+        )
+    }
+}
+
+// Only the lows bleed into the other channel, the way the head shadows
+// the highs from the far speaker.
+const CROSSFEED_CUTOFF_HZ: f64 = 700.0;
+
+/// Bleeds a delayed, low-passed bit of each channel into the other, the
+/// way each ear hears both speakers. This makes hard-panned sounds less
+/// tiring on headphones.
+pub struct Crossfeed {
+    left: DelayLine,
+    right: DelayLine,
+    delay_samples: f64,
+    amount: f32,
+    lowpass_coefficient: f32,
+    left_lowpassed: f32,
+    right_lowpassed: f32,
+}
+
+impl Crossfeed {
+    /// `amount` is how loud the bleed is relative to the original, and
+    /// `delay_ms` is how much later it arrives (a fraction of a
+    /// millisecond is realistic).
+    pub fn new(sample_rate: usize, amount: f32, delay_ms: f64) -> Self {
+        let delay_samples = delay_ms * sample_rate as f64 / 1000.0;
+        let lowpass_coefficient =
+            1.0 - (-2.0 * std::f64::consts::PI * CROSSFEED_CUTOFF_HZ / sample_rate as f64).exp();
+        Crossfeed {
+            left: DelayLine::new(delay_samples as usize + 1),
+            right: DelayLine::new(delay_samples as usize + 1),
+            delay_samples,
+            amount,
+            lowpass_coefficient: lowpass_coefficient as f32,
+            left_lowpassed: 0.0,
+            right_lowpassed: 0.0,
+        }
+    }
+}
+
+impl StereoEffect for Crossfeed {
+    fn process(&mut self, left: f32, right: f32) -> (f32, f32) {
+        self.left_lowpassed += (left - self.left_lowpassed) * self.lowpass_coefficient;
+        self.right_lowpassed += (right - self.right_lowpassed) * self.lowpass_coefficient;
+        self.left.push(self.left_lowpassed);
+        self.right.push(self.right_lowpassed);
+        let bleed_into_left = self.right.read(self.delay_samples) * self.amount;
+        let bleed_into_right = self.left.read(self.delay_samples) * self.amount;
+        // Turn the result down so centered sounds keep their level.
+        let gain = 1.0 / (1.0 + self.amount);
+        (
+            (left + bleed_into_left) * gain,
+            (right + bleed_into_right) * gain,
+        )
+    }
+}
+
+/// Tracks the level of a signal, rising quickly when it gets louder and
+/// falling more slowly when it gets quieter.
+struct EnvelopeFollower {
+    level: f32,
+    attack: f32,
+    release: f32,
+}
+
+impl EnvelopeFollower {
+    fn new(sample_rate: usize, attack_ms: f64, release_ms: f64) -> Self {
+        let coefficient = |ms: f64| (1.0 - (-1000.0 / (ms * sample_rate as f64)).exp()) as f32;
+        EnvelopeFollower {
+            level: 0.0,
+            attack: coefficient(attack_ms),
+            release: coefficient(release_ms),
+        }
+    }
+
+    fn process(&mut self, sample: f32) -> f32 {
+        let input = sample.abs();
+        let coefficient = if input > self.level {
+            self.attack
+        } else {
+            self.release
+        };
+        self.level += (input - self.level) * coefficient;
+        self.level
+    }
+}
+
+// The frequency an auto-wah's filter rests at when the input is silent.
+const AUTO_WAH_BASE_FREQUENCY: f64 = 200.0;
+
+/// A resonant band-pass filter whose frequency follows the level of the
+/// input, so louder sounds open up the filter.
+pub struct AutoWah {
+    envelope: EnvelopeFollower,
+    filter: Biquad,
+    sample_rate: usize,
+    sensitivity: f32,
+    range_hz: f64,
+    q: f64,
+    frequency: f64,
+}
+
+impl AutoWah {
+    /// The filter sweeps up from 200 Hz by as much as `range_hz` as the
+    /// input level, multiplied by `sensitivity`, goes from 0 to 1.
+    pub fn new(sample_rate: usize, sensitivity: f32, range_hz: f64, q: f64) -> Self {
+        AutoWah {
+            envelope: EnvelopeFollower::new(sample_rate, 5.0, 50.0),
+            filter: Biquad::new(Coefficients::band_pass(
+                sample_rate,
+                AUTO_WAH_BASE_FREQUENCY,
+                q,
+            )),
+            sample_rate,
+            sensitivity,
+            range_hz,
+            q,
+            frequency: AUTO_WAH_BASE_FREQUENCY,
+        }
+    }
+
+    /// Returns the frequency the filter is currently centered on.
+    pub fn frequency(&self) -> f64 {
+        self.frequency
+    }
+}
+
+impl Effect for AutoWah {
+    fn process(&mut self, sample: f32) -> f32 {
+        let amount = (self.envelope.process(sample) * self.sensitivity).min(1.0) as f64;
+        self.frequency = AUTO_WAH_BASE_FREQUENCY + self.range_hz * amount;
+        self.filter.set_coefficients(Coefficients::band_pass(
+            self.sample_rate,
+            self.frequency,
+            self.q,
+        ));
+        self.filter.process(sample)
+    }
+}
+
+// How long a gate takes to open or close, to avoid clicks.
+const GATE_FADE_MS: f64 = 5.0;
+
+/// Rhythmically mutes the signal. The pattern says whether each step
+/// (e.g. each sixteenth note) is on or off, and repeats for as long as
+/// the music lasts.
+pub struct Gate {
+    pattern: Vec<bool>,
+    samples_per_step: f64,
+    fade_per_sample: f32,
+    position: usize,
+    gain: f32,
+}
+
+impl Gate {
+    pub fn new(
+        sample_rate: usize,
+        beat_settings: BeatSettings,
+        step: Beat,
+        pattern: Vec<bool>,
+    ) -> Self {
+        assert!(!pattern.is_empty());
+        let samples_per_ms = sample_rate as f64 / 1000.0;
+        Gate {
+            pattern,
+            samples_per_step: beat_settings.duration_in_millis(step) * samples_per_ms,
+            fade_per_sample: (1.0 / (GATE_FADE_MS * samples_per_ms)) as f32,
+            position: 0,
+            gain: 0.0,
+        }
+    }
+}
+
+impl Effect for Gate {
+    fn process(&mut self, sample: f32) -> f32 {
+        let step = (self.position as f64 / self.samples_per_step) as usize;
+        self.position += 1;
+        if self.pattern[step % self.pattern.len()] {
+            self.gain = f32::min(self.gain + self.fade_per_sample, 1.0);
+        } else {
+            self.gain = f32::max(self.gain - self.fade_per_sample, 0.0);
+        }
+        sample * self.gain
+    }
+}
+
+// The corner frequencies of the equalizer's low and high shelves, and the
+// center frequency of its mid band.
+const EQ_LOW_FREQUENCY: f64 = 250.0;
+const EQ_MID_FREQUENCY: f64 = 1000.0;
+const EQ_MID_Q: f64 = 0.7;
+const EQ_HIGH_FREQUENCY: f64 = 4000.0;
+
+/// A three-band equalizer: a low shelf, a peaking mid band, and a high
+/// shelf, each boosted or cut by the given number of decibels.
+pub struct Equalizer {
+    low: Biquad,
+    mid: Biquad,
+    high: Biquad,
+}
+
+impl Equalizer {
+    pub fn new(sample_rate: usize, low_db: f64, mid_db: f64, high_db: f64) -> Self {
+        Equalizer {
+            low: Biquad::new(Coefficients::low_shelf(
+                sample_rate,
+                EQ_LOW_FREQUENCY,
+                low_db,
+            )),
+            mid: Biquad::new(Coefficients::peaking(
+                sample_rate,
+                EQ_MID_FREQUENCY,
+                EQ_MID_Q,
+                mid_db,
+            )),
+            high: Biquad::new(Coefficients::high_shelf(
+                sample_rate,
+                EQ_HIGH_FREQUENCY,
+                high_db,
+            )),
+        }
+    }
+}
+
+impl Effect for Equalizer {
+    fn process(&mut self, sample: f32) -> f32 {
+        self.high
+            .process(self.mid.process(self.low.process(sample)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        apply_effects, apply_stereo_effects, AutoWah, Crossfeed, DelayLine, Effect, Equalizer,
+        Gate, ModulatedDelay, PingPongDelay, StereoEffect,
+    };
+    use crate::{
+        analysis::{peak, rms},
+        beat::{Beat, BeatSettings, FOUR_FOUR},
+        rng::Rng,
+    };
+
+    #[test]
+    fn test_delay_line_works() {
+        let mut delay_line = DelayLine::new(4);
+        for sample in [1.0, 2.0, 3.0] {
+            delay_line.push(sample);
+        }
+        assert_eq!(delay_line.read(0.0), 3.0);
+        assert_eq!(delay_line.read(2.0), 1.0);
+        assert_eq!(delay_line.read(0.5), 2.5);
+        assert_eq!(delay_line.read(3.0), 0.0);
+    }
+
+    fn render_chorus(seed: u64) -> Vec<f32> {
+        let mut samples: Vec<f32> = (0..44100)
+            .map(|i| ((i % 100) as f32 / 50.0) - 1.0)
+            .collect();
+        let mut effects: Vec<Box<dyn Effect>> =
+            vec![Box::new(ModulatedDelay::chorus(44100, &mut Rng::new(seed)))];
+        apply_effects(&mut samples, &mut effects);
+        samples
+    }
+
+    #[test]
+    fn test_ping_pong_echoes_alternate_channels() {
+        let mut samples = vec![0.0; 2 * 50];
+        samples[0] = 1.0;
+        samples[1] = 1.0;
+        let mut effects: Vec<Box<dyn StereoEffect>> =
+            vec![Box::new(PingPongDelay::new(1000, 10.0, 0.5, 1.0))];
+        apply_stereo_effects(&mut samples, &mut effects);
+        let frame = |i: usize| (samples[i * 2], samples[i * 2 + 1]);
+        assert_eq!(frame(10), (1.0, 0.0));
+        assert_eq!(frame(20), (0.0, 0.5));
+        assert_eq!(frame(30), (0.25, 0.0));
+        assert_eq!(frame(40), (0.0, 0.125));
+        let echoes = (0..50).filter(|i| frame(*i) != (0.0, 0.0)).count();
+        assert_eq!(echoes, 4);
+    }
+
+    #[test]
+    fn test_flat_equalizer_leaves_audio_alone() {
+        let original: Vec<f32> = (0..4410).map(|i| ((i % 100) as f32 / 50.0) - 1.0).collect();
+        let mut samples = original.clone();
+        let mut effects: Vec<Box<dyn Effect>> =
+            vec![Box::new(Equalizer::new(44100, 0.0, 0.0, 0.0))];
+        apply_effects(&mut samples, &mut effects);
+        for (sample, original) in samples.iter().zip(original) {
+            assert!((sample - original).abs() < 0.0001);
+        }
+    }
+
+    #[test]
+    fn test_crossfeed_narrows_hard_panned_audio() {
+        // A low tone, hard left, so it isn't filtered out of the bleed.
+        let mut samples: Vec<f32> = (0..4410)
+            .flat_map(|i| [(i as f32 * 0.01).sin(), 0.0])
+            .collect();
+        let difference = |samples: &[f32]| {
+            let differences: Vec<f32> =
+                samples.chunks(2).map(|frame| frame[0] - frame[1]).collect();
+            rms(&differences)
+        };
+        let before = difference(&samples);
+        let mut effects: Vec<Box<dyn StereoEffect>> =
+            vec![Box::new(Crossfeed::new(44100, 0.3, 0.3))];
+        apply_stereo_effects(&mut samples, &mut effects);
+        let after = difference(&samples);
+        assert!(after < before * 0.8, "{} vs. {}", after, before);
+        let right: Vec<f32> = samples.iter().skip(1).step_by(2).copied().collect();
+        assert!(peak(&right) > 0.1);
+    }
+
+    fn auto_wah_frequency_for_level(level: f32) -> f64 {
+        let mut auto_wah = AutoWah::new(44100, 2.0, 2000.0, 3.0);
+        for i in 0..4410 {
+            let sample = if (i / 50) % 2 == 0 { level } else { -level };
+            auto_wah.process(sample);
+        }
+        auto_wah.frequency()
+    }
+
+    #[test]
+    fn test_auto_wah_follows_input_level() {
+        let silent = auto_wah_frequency_for_level(0.0);
+        let quiet = auto_wah_frequency_for_level(0.1);
+        let loud = auto_wah_frequency_for_level(0.4);
+        let very_loud = auto_wah_frequency_for_level(1.0);
+        assert_eq!(silent, 200.0);
+        assert!(quiet > silent);
+        assert!(loud > quiet);
+        // The sweep tops out at the end of its range.
+        assert_eq!(very_loud, 2200.0);
+    }
+
+    #[test]
+    fn test_gate_is_silent_during_off_steps() {
+        // At 60 BPM, each quarter note step lasts 1000 samples.
+        let beat_settings = BeatSettings::new(60, FOUR_FOUR);
+        let mut gate = Gate::new(1000, beat_settings, Beat::Quarter, vec![true, false, false]);
+        let samples: Vec<f32> = (0..6000).map(|_| gate.process(1.0)).collect();
+        // Each step starts with a 5 ms fade.
+        for step in [0, 3] {
+            assert!(samples[step * 1000 + 5..(step + 1) * 1000]
+                .iter()
+                .all(|sample| *sample == 1.0));
+        }
+        for step in [1, 2, 4, 5] {
+            let start = step * 1000 + 5;
+            assert_eq!(peak(&samples[start..start + 995]), 0.0);
+        }
+        assert!(samples[1002] > 0.0 && samples[1002] < 1.0);
+    }
+
+    #[test]
+    fn test_same_seed_produces_same_output() {
+        assert_eq!(render_chorus(1), render_chorus(1));
+    }
+
+    #[test]
+    fn test_different_seeds_produce_different_output() {
+        assert_ne!(render_chorus(1), render_chorus(2));
+    }
+}
@@ -0,0 +1,311 @@
+// Biquad filters, using the formulas from Robert Bristow-Johnson's Audio
+// EQ Cookbook: https://www.w3.org/TR/audio-eq-cookbook/
+
+const TWO_PI: f64 = 2.0 * std::f64::consts::PI;
+
+/// The coefficients of a biquad filter, normalized so that a0 is 1.
+#[derive(Copy, Clone, Debug)]
+pub struct Coefficients {
+    b0: f64,
+    b1: f64,
+    b2: f64,
+    a1: f64,
+    a2: f64,
+}
+
+impl Coefficients {
+    fn normalized(b0: f64, b1: f64, b2: f64, a0: f64, a1: f64, a2: f64) -> Self {
+        Coefficients {
+            b0: b0 / a0,
+            b1: b1 / a0,
+            b2: b2 / a0,
+            a1: a1 / a0,
+            a2: a2 / a0,
+        }
+    }
+
+    /// A resonant band-pass filter that passes the given frequency at its
+    /// original level. Higher values of `q` make the band narrower.
+    pub fn band_pass(sample_rate: usize, frequency: f64, q: f64) -> Self {
+        let w0 = TWO_PI * frequency / sample_rate as f64;
+        let alpha = w0.sin() / (2.0 * q);
+        Coefficients::normalized(
+            alpha,
+            0.0,
+            -alpha,
+            1.0 + alpha,
+            -2.0 * w0.cos(),
+            1.0 - alpha,
+        )
+    }
+
+    /// A resonant low-pass filter that rolls off above the given frequency.
+    /// A `q` of 1/sqrt(2) is as flat as it gets without a resonant bump.
+    pub fn low_pass(sample_rate: usize, frequency: f64, q: f64) -> Self {
+        let w0 = TWO_PI * frequency / sample_rate as f64;
+        let alpha = w0.sin() / (2.0 * q);
+        let cos_w0 = w0.cos();
+        Coefficients::normalized(
+            (1.0 - cos_w0) / 2.0,
+            1.0 - cos_w0,
+            (1.0 - cos_w0) / 2.0,
+            1.0 + alpha,
+            -2.0 * cos_w0,
+            1.0 - alpha,
+        )
+    }
+
+    /// Boosts or cuts the given frequency and the ones around it by
+    /// `gain_db`. Higher values of `q` affect a narrower band.
+    pub fn peaking(sample_rate: usize, frequency: f64, q: f64, gain_db: f64) -> Self {
+        let a = 10.0f64.powf(gain_db / 40.0);
+        let w0 = TWO_PI * frequency / sample_rate as f64;
+        let alpha = w0.sin() / (2.0 * q);
+        let cos_w0 = w0.cos();
+        Coefficients::normalized(
+            1.0 + alpha * a,
+            -2.0 * cos_w0,
+            1.0 - alpha * a,
+            1.0 + alpha / a,
+            -2.0 * cos_w0,
+            1.0 - alpha / a,
+        )
+    }
+
+    /// Boosts or cuts everything below the given frequency by `gain_db`.
+    pub fn low_shelf(sample_rate: usize, frequency: f64, gain_db: f64) -> Self {
+        let (a, cos_w0, beta) = shelf_parameters(sample_rate, frequency, gain_db);
+        Coefficients::normalized(
+            a * ((a + 1.0) - (a - 1.0) * cos_w0 + beta),
+            2.0 * a * ((a - 1.0) - (a + 1.0) * cos_w0),
+            a * ((a + 1.0) - (a - 1.0) * cos_w0 - beta),
+            (a + 1.0) + (a - 1.0) * cos_w0 + beta,
+            -2.0 * ((a - 1.0) + (a + 1.0) * cos_w0),
+            (a + 1.0) + (a - 1.0) * cos_w0 - beta,
+        )
+    }
+
+    /// Boosts or cuts everything above the given frequency by `gain_db`.
+    pub fn high_shelf(sample_rate: usize, frequency: f64, gain_db: f64) -> Self {
+        let (a, cos_w0, beta) = shelf_parameters(sample_rate, frequency, gain_db);
+        Coefficients::normalized(
+            a * ((a + 1.0) + (a - 1.0) * cos_w0 + beta),
+            -2.0 * a * ((a - 1.0) + (a + 1.0) * cos_w0),
+            a * ((a + 1.0) + (a - 1.0) * cos_w0 - beta),
+            (a + 1.0) - (a - 1.0) * cos_w0 + beta,
+            2.0 * ((a - 1.0) - (a + 1.0) * cos_w0),
+            (a + 1.0) - (a - 1.0) * cos_w0 - beta,
+        )
+    }
+}
+
+/// Returns the values shared by both kinds of shelf filter, using the
+/// steepest slope that doesn't overshoot (S = 1 in the cookbook).
+fn shelf_parameters(sample_rate: usize, frequency: f64, gain_db: f64) -> (f64, f64, f64) {
+    let a = 10.0f64.powf(gain_db / 40.0);
+    let w0 = TWO_PI * frequency / sample_rate as f64;
+    let alpha = w0.sin() / 2.0 * std::f64::consts::SQRT_2;
+    (a, w0.cos(), 2.0 * a.sqrt() * alpha)
+}
+
+pub struct Biquad {
+    coefficients: Coefficients,
+    x1: f64,
+    x2: f64,
+    y1: f64,
+    y2: f64,
+}
+
+impl Biquad {
+    pub fn new(coefficients: Coefficients) -> Self {
+        Biquad {
+            coefficients,
+            x1: 0.0,
+            x2: 0.0,
+            y1: 0.0,
+            y2: 0.0,
+        }
+    }
+
+    /// Changes the filter's response without resetting its state, so it
+    /// can be swept smoothly.
+    pub fn set_coefficients(&mut self, coefficients: Coefficients) {
+        self.coefficients = coefficients;
+    }
+
+    pub fn process(&mut self, sample: f32) -> f32 {
+        let c = &self.coefficients;
+        let x = sample as f64;
+        let y = c.b0 * x + c.b1 * self.x1 + c.b2 * self.x2 - c.a1 * self.y1 - c.a2 * self.y2;
+        self.x2 = self.x1;
+        self.x1 = x;
+        self.y2 = self.y1;
+        self.y1 = y;
+        y as f32
+    }
+}
+
+/// The `q` of each stage of an 8th-order Butterworth low-pass filter,
+/// which rolls off steeply without any ripple below its cutoff.
+const BUTTERWORTH_8_QS: [f64; 4] = [0.5098, 0.6013, 0.9000, 2.5629];
+
+/// The cutoff of the low-pass filter in `downsample`, as a fraction of the
+/// sample rate it's downsampling to. It's a bit under the Nyquist
+/// frequency (0.5) so that the filter has room to roll off.
+const DOWNSAMPLE_CUTOFF: f64 = 0.4;
+
+/// Takes interleaved samples with the given number of channels, rendered
+/// at `factor` times `sample_rate`, and brings them down to `sample_rate`.
+/// Everything too high to be represented at `sample_rate` is filtered out
+/// first, so that it's dropped rather than aliasing back down.
+pub fn downsample(samples: &[f32], channels: usize, factor: usize, sample_rate: usize) -> Vec<f32> {
+    let coefficients = BUTTERWORTH_8_QS.map(|q| {
+        Coefficients::low_pass(
+            sample_rate * factor,
+            sample_rate as f64 * DOWNSAMPLE_CUTOFF,
+            q,
+        )
+    });
+    let mut filters: Vec<Vec<Biquad>> = (0..channels)
+        .map(|_| coefficients.iter().map(|c| Biquad::new(*c)).collect())
+        .collect();
+    let mut downsampled = Vec::with_capacity(samples.len() / factor + channels);
+    for (i, frame) in samples.chunks_exact(channels).enumerate() {
+        for (sample, stages) in frame.iter().zip(filters.iter_mut()) {
+            let filtered = stages
+                .iter_mut()
+                .fold(*sample, |value, stage| stage.process(value));
+            if i % factor == 0 {
+                downsampled.push(filtered);
+            }
+        }
+    }
+    downsampled
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{downsample, Biquad, Coefficients};
+    use crate::analysis::peak;
+
+    /// Returns the peak level of a full-scale sine wave at the given
+    /// frequency after it's gone through the filter (and settled).
+    fn filtered_level(coefficients: Coefficients, frequency: f64) -> f32 {
+        let mut biquad = Biquad::new(coefficients);
+        let samples: Vec<f32> = (0..44100)
+            .map(|i| {
+                let t = i as f64 / 44100.0;
+                biquad.process((t * frequency * 2.0 * std::f64::consts::PI).sin() as f32)
+            })
+            .collect();
+        peak(&samples[22050..])
+    }
+
+    fn assert_level(coefficients: Coefficients, frequency: f64, expected: f32) {
+        let level = filtered_level(coefficients, frequency);
+        assert!(
+            (level - expected).abs() < expected * 0.05,
+            "level at {} Hz is {}, expected {}",
+            frequency,
+            level,
+            expected
+        );
+    }
+
+    // 6 dB is (about) a doubling or halving of amplitude.
+    const BOOST: f32 = 1.995;
+    const CUT: f32 = 0.501;
+
+    #[test]
+    fn test_low_shelf_works() {
+        assert_level(Coefficients::low_shelf(44100, 250.0, 6.0), 30.0, BOOST);
+        assert_level(Coefficients::low_shelf(44100, 250.0, 6.0), 8000.0, 1.0);
+        assert_level(Coefficients::low_shelf(44100, 250.0, -6.0), 30.0, CUT);
+    }
+
+    #[test]
+    fn test_peaking_works() {
+        assert_level(
+            Coefficients::peaking(44100, 1000.0, 1.0, 6.0),
+            1000.0,
+            BOOST,
+        );
+        assert_level(Coefficients::peaking(44100, 1000.0, 1.0, 6.0), 30.0, 1.0);
+        assert_level(Coefficients::peaking(44100, 1000.0, 1.0, 6.0), 15000.0, 1.0);
+        assert_level(Coefficients::peaking(44100, 1000.0, 1.0, -6.0), 1000.0, CUT);
+    }
+
+    #[test]
+    fn test_high_shelf_works() {
+        assert_level(Coefficients::high_shelf(44100, 4000.0, 6.0), 15000.0, BOOST);
+        assert_level(Coefficients::high_shelf(44100, 4000.0, 6.0), 100.0, 1.0);
+        assert_level(Coefficients::high_shelf(44100, 4000.0, -6.0), 15000.0, CUT);
+    }
+
+    #[test]
+    fn test_low_pass_works() {
+        let low_pass = Coefficients::low_pass(44100, 1000.0, std::f64::consts::FRAC_1_SQRT_2);
+        assert!((filtered_level(low_pass, 100.0) - 1.0).abs() < 0.01);
+        assert!(filtered_level(low_pass, 10000.0) < 0.02);
+    }
+
+    /// Returns the energy of a hard-clipped (as if overdriven) 5 kHz tone
+    /// that isn't at one of the tone's harmonics, i.e. the energy of its
+    /// aliasing, after rendering it at `factor` times 44.1 kHz and
+    /// downsampling it to 44.1 kHz.
+    fn aliasing_of_distorted_tone(factor: usize) -> f64 {
+        const FREQUENCY: f64 = 5000.0;
+        // A tenth of a second, so every DFT bin is 10 Hz wide.
+        const LEN: usize = 4410;
+        // Leave time for the downsampling filter to settle.
+        const SKIP: usize = 1000;
+        let sample_rate = 44100 * factor;
+        let rendered: Vec<f32> = (0..(SKIP + LEN) * factor)
+            .map(|i| {
+                let t = i as f64 / sample_rate as f64;
+                let tone = (t * FREQUENCY * 2.0 * std::f64::consts::PI).sin();
+                (tone * 4.0).clamp(-1.0, 1.0) as f32
+            })
+            .collect();
+        let samples = downsample(&rendered, 1, factor, 44100);
+        let samples = &samples[SKIP..];
+        (1..LEN / 2)
+            .filter(|bin| (*bin as f64 * 10.0) % FREQUENCY != 0.0)
+            .map(|bin| {
+                let (mut re, mut im) = (0.0, 0.0);
+                for (i, sample) in samples.iter().enumerate() {
+                    let angle = 2.0 * std::f64::consts::PI * (bin * i) as f64 / LEN as f64;
+                    re += *sample as f64 * angle.cos();
+                    im -= *sample as f64 * angle.sin();
+                }
+                re * re + im * im
+            })
+            .sum()
+    }
+
+    #[test]
+    fn test_oversampling_reduces_aliasing() {
+        let plain = aliasing_of_distorted_tone(1);
+        let oversampled = aliasing_of_distorted_tone(4);
+        assert!(oversampled < plain / 10.0, "{} vs {}", oversampled, plain);
+    }
+
+    #[test]
+    fn test_downsample_keeps_channels_apart() {
+        let samples: Vec<f32> = (0..800)
+            .map(|i| if i % 2 == 0 { 0.5 } else { -0.25 })
+            .collect();
+        let downsampled = downsample(&samples, 2, 4, 44100);
+        assert_eq!(downsampled.len(), 200);
+        assert!((downsampled[198] - 0.5).abs() < 1e-3);
+        assert!((downsampled[199] + 0.25).abs() < 1e-3);
+    }
+
+    #[test]
+    fn test_band_pass_works() {
+        let band_pass = Coefficients::band_pass(44100, 1000.0, 2.0);
+        assert!((filtered_level(band_pass, 1000.0) - 1.0).abs() < 0.01);
+        assert!(filtered_level(band_pass, 100.0) < 0.1);
+        assert!(filtered_level(band_pass, 10000.0) < 0.1);
+    }
+}
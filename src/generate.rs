@@ -0,0 +1,197 @@
+use crate::{
+    beat::Beat,
+    note::{scale_degree, MidiNote, Semitones},
+    rng::Rng,
+};
+
+// How far (in scale degrees) a random walk is allowed to wander from its tonic.
+const MIN_DEGREE: i32 = -7;
+const MAX_DEGREE: i32 = 14;
+
+/// Relative likelihood of each step size (in scale degrees) taken by a
+/// random walk: mostly stepwise motion, with the occasional skip or
+/// repeated note.
+pub const DEFAULT_STEP_WEIGHTS: [(i32, f64); 5] =
+    [(-2, 1.0), (-1, 3.0), (0, 1.0), (1, 3.0), (2, 1.0)];
+
+/// Generates a melody that starts on the tonic and then random-walks
+/// along the given scale, choosing each step from `step_weights`.
+///
+/// Panics if `step_weights` is empty.
+pub fn random_walk_melody(
+    rng: &mut Rng,
+    tonic: MidiNote,
+    scale: &[Semitones],
+    length: usize,
+    step_weights: &[(i32, f64)],
+) -> Vec<MidiNote> {
+    let weights: Vec<f64> = step_weights.iter().map(|(_, weight)| *weight).collect();
+    let mut degree = 0;
+    let mut melody = Vec::with_capacity(length);
+    for _ in 0..length {
+        melody.push(scale_degree(tonic, scale, degree));
+        let (step, _) = step_weights[rng.choose_weighted(&weights)];
+        degree = (degree + step).clamp(MIN_DEGREE, MAX_DEGREE);
+    }
+    melody
+}
+
+/// A note along with how long it's held for.
+pub type MelodyNote = (MidiNote, Beat);
+
+/// A first-order Markov chain over (note, length) pairs.
+pub struct MarkovModel {
+    start: MelodyNote,
+    // Every state seen in training, along with each state that followed it.
+    // Successors are stored once per occurrence, so picking one uniformly
+    // at random weights them by how often they occurred.
+    transitions: Vec<(MelodyNote, Vec<MelodyNote>)>,
+}
+
+impl MarkovModel {
+    pub fn train(sequence: &[MelodyNote]) -> Self {
+        let mut model = MarkovModel {
+            start: *sequence
+                .first()
+                .expect("training sequence must not be empty"),
+            transitions: vec![],
+        };
+        for pair in sequence.windows(2) {
+            model.successors_mut(pair[0]).push(pair[1]);
+        }
+        model
+    }
+
+    fn successors_mut(&mut self, state: MelodyNote) -> &mut Vec<MelodyNote> {
+        let index = match self.transitions.iter().position(|(s, _)| *s == state) {
+            Some(index) => index,
+            None => {
+                self.transitions.push((state, vec![]));
+                self.transitions.len() - 1
+            }
+        };
+        &mut self.transitions[index].1
+    }
+
+    fn successors(&self, state: MelodyNote) -> &[MelodyNote] {
+        self.transitions
+            .iter()
+            .find(|(s, _)| *s == state)
+            .map(|(_, successors)| &successors[..])
+            .unwrap_or(&[])
+    }
+
+    /// Generates a melody that starts where the training sequence started.
+    /// If we reach a state that was never followed by anything (i.e. the
+    /// end of the training sequence), we start over from the beginning.
+    pub fn generate(&self, rng: &mut Rng, length: usize) -> Vec<MelodyNote> {
+        let mut state = self.start;
+        let mut melody = Vec::with_capacity(length);
+        for _ in 0..length {
+            melody.push(state);
+            let successors = self.successors(state);
+            state = if successors.is_empty() {
+                self.start
+            } else {
+                successors[rng.next_index(successors.len())]
+            };
+        }
+        melody
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{random_walk_melody, MarkovModel, DEFAULT_STEP_WEIGHTS};
+    use crate::{
+        beat::Beat,
+        note::{scale_degree, MidiNote, MAJOR_SCALE},
+        rng::Rng,
+    };
+
+    fn c4() -> MidiNote {
+        "C4".try_into().unwrap()
+    }
+
+    #[test]
+    fn test_random_walk_is_reproducible() {
+        let a = random_walk_melody(
+            &mut Rng::new(1),
+            c4(),
+            &MAJOR_SCALE,
+            32,
+            &DEFAULT_STEP_WEIGHTS,
+        );
+        let b = random_walk_melody(
+            &mut Rng::new(1),
+            c4(),
+            &MAJOR_SCALE,
+            32,
+            &DEFAULT_STEP_WEIGHTS,
+        );
+        assert_eq!(a, b);
+        assert_eq!(a.len(), 32);
+        assert_eq!(a[0], c4());
+    }
+
+    #[test]
+    fn test_random_walk_stays_in_scale() {
+        let scale_notes: Vec<MidiNote> = (-7..=14)
+            .map(|degree| scale_degree(c4(), &MAJOR_SCALE, degree))
+            .collect();
+        let melody = random_walk_melody(
+            &mut Rng::new(7),
+            c4(),
+            &MAJOR_SCALE,
+            200,
+            &DEFAULT_STEP_WEIGHTS,
+        );
+        for note in melody {
+            assert!(scale_notes.contains(&note));
+        }
+    }
+
+    #[test]
+    fn test_random_walk_respects_step_weights() {
+        let melody = random_walk_melody(&mut Rng::new(3), c4(), &MAJOR_SCALE, 8, &[(0, 1.0)]);
+        assert_eq!(melody, vec![c4(); 8]);
+    }
+
+    fn parse_melody(melody: &[(&str, Beat)]) -> Vec<(MidiNote, Beat)> {
+        melody
+            .iter()
+            .map(|(note, beat)| (MidiNote::parse(note).unwrap(), *beat))
+            .collect()
+    }
+
+    #[test]
+    fn test_markov_model_only_uses_observed_transitions() {
+        let training = parse_melody(&crate::songs::captain_silver_melody());
+        let model = MarkovModel::train(&training);
+        let melody = model.generate(&mut Rng::new(9), 64);
+        assert_eq!(melody.len(), 64);
+        assert_eq!(melody[0], training[0]);
+        for pair in melody.windows(2) {
+            let observed = training.windows(2).any(|t| t == pair);
+            let restarted = pair[1] == training[0];
+            assert!(observed || restarted);
+        }
+    }
+
+    #[test]
+    fn test_markov_model_is_reproducible() {
+        let model = MarkovModel::train(&parse_melody(&crate::songs::tuna_melody()));
+        assert_eq!(
+            model.generate(&mut Rng::new(4), 32),
+            model.generate(&mut Rng::new(4), 32)
+        );
+    }
+
+    #[test]
+    fn test_markov_model_follows_deterministic_chain() {
+        let training = parse_melody(&[("C4", Beat::Quarter), ("D4", Beat::Half)]);
+        let model = MarkovModel::train(&training);
+        let melody = model.generate(&mut Rng::new(0), 4);
+        assert_eq!(melody, [training.clone(), training].concat());
+    }
+}
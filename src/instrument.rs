@@ -1,62 +1,308 @@
-use std::sync::{Arc, Mutex};
+use std::{
+    future::Future,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex, MutexGuard, Weak,
+    },
+    task::{Context, Poll},
+};
 
 use crate::{
-    beat::{Beat, BeatCounter, BeatSettings},
-    note::MidiNoteLike,
-    player::{AudioShapeProxy, Player},
-    synth::{AudioShape, Waveform},
+    beat::{Beat, BeatCounter, BeatSettings, NoteValue, TICKS_PER_SIXTY_FOURTH_BEAT},
+    note::{MidiNote, MidiNoteLike},
+    player::{AudioShapeProxy, Player, StereoAudioShapeProxy},
+    recording::{record_note, NoteEvent},
+    sequence::{parse_sequence, SequenceParseError, SequenceStep},
+    synth::{AudioShape, Envelope, StereoAudioShape, VolumeMode, Waveform, FULL_VOLUME_STEPS},
 };
 
 // Amount of time to pause between notes (when not slurring)
 const PAUSE_MS: f64 = 50.0;
 
+// How long the burst of noise at the start of each note lasts, when an
+// instrument has an attack transient.
+const TRANSIENT_MS: f64 = 10.0;
+
+// How many times a second a chord played as an arpeggio moves to its next
+// note, like the once-per-frame updates of an old console's sound driver.
+const ARPEGGIO_RATE_HZ: f64 = 60.0;
+
+const LOCK_INVARIANT_VIOLATED: &str =
+    "Instrument state was locked by someone else; is it being polled from multiple threads?";
+
+/// A single step of music that an instrument can play.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Event {
+    Note(MidiNote, Beat),
+    Chord(Vec<MidiNote>, Beat),
+    Rest(Beat),
+}
+
+/// How an instrument plays chords.
+#[derive(Debug, Copy, Clone, Default, PartialEq)]
+pub enum ChordMode {
+    /// Each note of the chord gets a voice of its own.
+    #[default]
+    Voices,
+    /// A single voice rapidly cycles through the notes of the chord, the
+    /// way chiptunes fake chords on one channel.
+    Arpeggio,
+}
+
+/// The synth voice an instrument plays its notes with. Most instruments
+/// are mono, but a voice can also be placed in the stereo field.
+#[derive(Clone)]
+enum Voice {
+    Mono(AudioShapeProxy),
+    Panned {
+        proxy: StereoAudioShapeProxy,
+        shape: AudioShape,
+        pan: f64,
+    },
+}
+
+impl Voice {
+    fn set_frequency(&mut self, frequency: f64) {
+        match self {
+            Voice::Mono(proxy) => proxy.set_frequency(frequency),
+            Voice::Panned { shape, .. } => {
+                shape.frequency = frequency;
+                self.update_panned_shape();
+            }
+        }
+    }
+
+    fn set_volume(&mut self, volume: u8) {
+        match self {
+            Voice::Mono(proxy) => proxy.set_volume(volume),
+            Voice::Panned { shape, .. } => {
+                shape.volume = volume;
+                self.update_panned_shape();
+            }
+        }
+    }
+
+    fn set_volume_mode(&mut self, volume_mode: VolumeMode) {
+        match self {
+            Voice::Mono(proxy) => proxy.set_volume_mode(volume_mode),
+            Voice::Panned { shape, .. } => {
+                shape.volume_mode = volume_mode;
+                self.update_panned_shape();
+            }
+        }
+    }
+
+    fn set_volume_steps(&mut self, volume_steps: u16) {
+        match self {
+            Voice::Mono(proxy) => proxy.set_volume_steps(volume_steps),
+            Voice::Panned { shape, .. } => {
+                shape.volume_steps = volume_steps;
+                self.update_panned_shape();
+            }
+        }
+    }
+
+    fn set_crossfade(&mut self, crossfade_ms: f64) {
+        match self {
+            Voice::Mono(proxy) => proxy.set_crossfade(crossfade_ms),
+            Voice::Panned { shape, .. } => {
+                shape.crossfade_ms = crossfade_ms;
+                self.update_panned_shape();
+            }
+        }
+    }
+
+    fn set_frequency_smoothing(&mut self, frequency_smoothing_ms: f64) {
+        match self {
+            Voice::Mono(proxy) => proxy.set_frequency_smoothing(frequency_smoothing_ms),
+            Voice::Panned { shape, .. } => {
+                shape.frequency_smoothing_ms = frequency_smoothing_ms;
+                self.update_panned_shape();
+            }
+        }
+    }
+
+    fn update_panned_shape(&mut self) {
+        if let Voice::Panned { proxy, shape, pan } = self {
+            proxy.set_shape(StereoAudioShape::panned(*shape, *pan));
+        }
+    }
+}
+
+/// Keeps track of the notes of a chord that are played by programs of
+/// their own, so they can be silenced before they finish.
+#[derive(Default)]
+struct ChordTails {
+    /// Incremented every time the tails are stopped. Programs started
+    /// before then see that it's changed and end themselves.
+    generation: u64,
+    voices: Vec<Weak<Mutex<Voice>>>,
+}
+
+/// A program playing one note of a chord, which ends early if the chord
+/// is stopped.
+struct ChordTail<F: Future<Output = ()>> {
+    program: Pin<Box<F>>,
+    tails: Arc<Mutex<ChordTails>>,
+    generation: u64,
+}
+
+impl<F: Future<Output = ()>> Future for ChordTail<F> {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        let this = self.get_mut();
+        let generation = this
+            .tails
+            .try_lock()
+            .expect(LOCK_INVARIANT_VIOLATED)
+            .generation;
+        if generation != this.generation {
+            Poll::Ready(())
+        } else {
+            this.program.as_mut().poll(cx)
+        }
+    }
+}
+
+/// Clones of an instrument share its state, which is why that state is
+/// behind `Arc<Mutex<..>>`. However, all player programs are polled one at a
+/// time on a single (audio) thread, and the instrument never holds a lock
+/// across an `.await`, so these locks are never actually contended. We
+/// rely on this by using `try_lock()`, which panics with a clear message if
+/// the invariant is ever broken, rather than `lock()`, which could silently
+/// deadlock the audio thread.
 #[derive(Clone)]
 pub struct Instrument {
     beat_counter: Arc<Mutex<BeatCounter>>,
-    shape: Arc<Mutex<AudioShapeProxy>>,
+    shape: Arc<Mutex<Voice>>,
+    chord_tails: Arc<Mutex<ChordTails>>,
+    waveform: Waveform,
+    envelope: Option<Envelope>,
+    volume_mode: VolumeMode,
+    volume_steps: u16,
+    crossfade_ms: f64,
+    frequency_smoothing_ms: f64,
     max_volume: u8,
+    attack_transient: bool,
+    chord_mode: ChordMode,
     start_time: f64,
 }
 
 impl Instrument {
     pub fn new(beat_settings: BeatSettings, max_volume: u8, waveform: Waveform) -> Self {
+        Instrument::new_with_envelope(beat_settings, max_volume, waveform, None)
+    }
+
+    /// Like `new`, but every note the instrument plays is shaped by the
+    /// given envelope, e.g. to make it plucky or let notes swell in.
+    pub fn new_with_envelope(
+        beat_settings: BeatSettings,
+        max_volume: u8,
+        waveform: Waveform,
+        envelope: Option<Envelope>,
+    ) -> Self {
         Instrument {
             beat_counter: Arc::new(Mutex::new(BeatCounter::new(beat_settings))),
-            shape: Arc::new(Mutex::new(Player::new_shape(AudioShape {
+            shape: Arc::new(Mutex::new(Voice::Mono(Player::new_shape(AudioShape {
                 waveform,
+                envelope,
                 ..Default::default()
-            }))),
+            })))),
+            chord_tails: Default::default(),
+            waveform,
+            envelope,
+            volume_mode: VolumeMode::Smooth,
+            volume_steps: FULL_VOLUME_STEPS,
+            crossfade_ms: 0.0,
+            frequency_smoothing_ms: 0.0,
             max_volume,
+            attack_transient: false,
+            chord_mode: ChordMode::Voices,
             start_time: Player::current_time(),
         }
     }
 
-    pub fn skip(&mut self, length: Beat) {
+    fn beat_counter(&self) -> MutexGuard<'_, BeatCounter> {
+        self.beat_counter.try_lock().expect(LOCK_INVARIANT_VIOLATED)
+    }
+
+    fn shape(&self) -> MutexGuard<'_, Voice> {
+        self.shape.try_lock().expect(LOCK_INVARIANT_VIOLATED)
+    }
+
+    fn chord_tails(&self) -> MutexGuard<'_, ChordTails> {
+        self.chord_tails.try_lock().expect(LOCK_INVARIANT_VIOLATED)
+    }
+
+    pub fn skip<L: Into<NoteValue>>(&mut self, length: L) {
         let ms = {
-            let mut beat_counter = self.beat_counter.try_lock().unwrap();
+            let mut beat_counter = self.beat_counter();
             beat_counter.increment(length);
             beat_counter.total_millis()
         };
         self.start_time -= ms;
     }
 
-    fn duplicate(&self) -> Self {
-        let cloned_shape = self.shape.try_lock().unwrap().clone();
-        let shape = Arc::new(Mutex::new(cloned_shape));
-        let cloned_beat_counter = self.beat_counter.try_lock().unwrap().clone();
+    /// Returns a new, independent voice of this instrument, which can be
+    /// used to play a separate part in parallel with this one (e.g. from a
+    /// program started with `Player::start_program`).
+    ///
+    /// The fork gets a copy of this instrument's beat counter, so it starts
+    /// at the same position in the music and shares the same timing
+    /// baseline, but advances independently from then on. It also gets a
+    /// fresh shape (a new synth voice) that starts out with the same
+    /// settings as this instrument's shape, so the two can play different
+    /// notes at the same time.
+    pub fn fork(&self) -> Self {
+        let cloned_shape = self.shape().clone();
+        self.fork_with_voice(cloned_shape)
+    }
+
+    /// Like `fork`, but the new voice is placed in the stereo field, from
+    /// -1.0 (hard left) through 0.0 (center) to 1.0 (hard right).
+    pub fn fork_panned(&self, pan: f64) -> Self {
+        let shape = AudioShape {
+            waveform: self.waveform,
+            envelope: self.envelope,
+            volume_mode: self.volume_mode,
+            volume_steps: self.volume_steps,
+            crossfade_ms: self.crossfade_ms,
+            frequency_smoothing_ms: self.frequency_smoothing_ms,
+            ..Default::default()
+        };
+        self.fork_with_voice(Voice::Panned {
+            proxy: Player::new_stereo_shape(StereoAudioShape::panned(shape, pan)),
+            shape,
+            pan,
+        })
+    }
+
+    fn fork_with_voice(&self, voice: Voice) -> Self {
+        let cloned_beat_counter = *self.beat_counter();
         let beat_counter = Arc::new(Mutex::new(cloned_beat_counter));
         Instrument {
             beat_counter,
-            shape,
+            shape: Arc::new(Mutex::new(voice)),
+            chord_tails: Default::default(),
+            waveform: self.waveform,
+            envelope: self.envelope,
+            volume_mode: self.volume_mode,
+            volume_steps: self.volume_steps,
+            crossfade_ms: self.crossfade_ms,
+            frequency_smoothing_ms: self.frequency_smoothing_ms,
             max_volume: self.max_volume,
+            attack_transient: self.attack_transient,
+            chord_mode: self.chord_mode,
             start_time: self.start_time,
         }
     }
 
-    async fn wait_for_beat(&mut self, length: Beat, offset: f64) {
+    async fn wait_for_beat(&mut self, length: NoteValue, offset: f64) {
         let mut final_offset = offset;
         let ms = {
-            let mut beat_counter = self.beat_counter.try_lock().unwrap();
+            let mut beat_counter = self.beat_counter();
             if beat_counter.total_measures().fract() == 0.0 {
                 // The way our algorithm currently works, we're bound to
                 // slowly veer off our ideal timeline due to rounding
@@ -78,45 +324,842 @@ impl Instrument {
         }
     }
 
-    async fn play_note_impl<N: MidiNoteLike>(&mut self, note: N, length: Beat, release_ms: f64) {
-        {
-            let mut shape = self.shape.try_lock().unwrap();
-            shape.set_frequency(note.into_midi_note_or_panic().frequency());
-            shape.set_volume(self.max_volume);
+    /// Sets how the instrument's volume changes, e.g. to
+    /// `VolumeMode::CHIPTUNE` for the stepped volume of an 8-bit console.
+    pub fn set_volume_mode(&mut self, volume_mode: VolumeMode) {
+        self.volume_mode = volume_mode;
+        self.shape().set_volume_mode(volume_mode);
+    }
+
+    /// Rounds the instrument's volume to the given number of evenly spaced
+    /// levels, e.g. 16 like the NES. Unlike `VolumeMode::Stepped`, this
+    /// still follows the instrument's envelope, just in coarser steps.
+    pub fn set_volume_steps(&mut self, volume_steps: u16) {
+        self.volume_steps = volume_steps;
+        self.shape().set_volume_steps(volume_steps);
+    }
+
+    /// Sets how long the instrument takes to fade from one pitch to the
+    /// next when it changes notes without going silent in between, e.g.
+    /// with `play_note_without_release`. A few milliseconds is enough to
+    /// avoid clicks while still sounding legato. This is separate from the
+    /// release, which is about the volume, and is 0 (off) by default.
+    pub fn set_crossfade(&mut self, crossfade_ms: f64) {
+        self.crossfade_ms = crossfade_ms;
+        self.shape().set_crossfade(crossfade_ms);
+    }
+
+    /// Eases the instrument's pitch into each new frequency over roughly
+    /// the given number of milliseconds, instead of jumping there. This is
+    /// meant to be short, around a millisecond, to take the "zipper" out of
+    /// quick runs of pitch changes; it's 0 (off) by default.
+    pub fn set_frequency_smoothing(&mut self, frequency_smoothing_ms: f64) {
+        self.frequency_smoothing_ms = frequency_smoothing_ms;
+        self.shape().set_frequency_smoothing(frequency_smoothing_ms);
+    }
+
+    /// Sets whether each note starts with a short burst of noise, like
+    /// the pluck of a string or the strike of a hammer. This makes
+    /// plucked and struck instruments sound much more realistic.
+    pub fn set_attack_transient(&mut self, enabled: bool) {
+        self.attack_transient = enabled;
+    }
+
+    /// Sets how `play_chord` plays chords, e.g. `ChordMode::Arpeggio` to
+    /// only ever use the instrument's own voice.
+    pub fn set_chord_mode(&mut self, chord_mode: ChordMode) {
+        self.chord_mode = chord_mode;
+    }
+
+    /// Starts playing the note, returning the time it started at.
+    fn start_note(&mut self, note: MidiNote) -> f64 {
+        self.start_frequency(note.frequency())
+    }
+
+    /// Starts playing the frequency, returning the time it started at.
+    fn start_frequency(&mut self, frequency: f64) -> f64 {
+        if self.attack_transient {
+            let volume = self.max_volume / 2;
+            Player::start_program(async move {
+                let _noise = Player::new_shape(AudioShape {
+                    waveform: Waveform::Noise,
+                    frequency: 0.0,
+                    volume,
+                    ..Default::default()
+                });
+                Player::wait(TRANSIENT_MS).await;
+            });
         }
-        self.wait_for_beat(length, -release_ms).await;
+        let mut shape = self.shape();
+        shape.set_frequency(frequency);
+        shape.set_volume(self.max_volume);
+        Player::current_time()
+    }
+
+    async fn release_note(&mut self, note: MidiNote, start_ms: f64, release_ms: f64) {
+        record_note(NoteEvent {
+            note,
+            start_ms,
+            end_ms: Player::current_time(),
+        });
+        self.release(release_ms).await;
+    }
+
+    async fn release(&mut self, release_ms: f64) {
         if release_ms > 0.0 {
-            self.shape.try_lock().unwrap().set_volume(0);
+            self.shape().set_volume(0);
             Player::wait(release_ms).await;
         }
     }
 
-    pub async fn play_note<N: MidiNoteLike>(&mut self, note: N, length: Beat) {
-        self.play_note_impl(note, length, PAUSE_MS).await;
+    async fn play_note_impl<N: MidiNoteLike>(
+        &mut self,
+        note: N,
+        length: NoteValue,
+        release_ms: f64,
+    ) {
+        let note = note.into_midi_note_or_panic();
+        let start_ms = self.start_note(note);
+        self.wait_for_beat(length, -release_ms).await;
+        self.release_note(note, start_ms, release_ms).await;
     }
 
-    pub async fn play_note_without_release<N: MidiNoteLike>(&mut self, note: N, length: Beat) {
-        self.play_note_impl(note, length, 0.0).await;
+    pub async fn play_note<N: MidiNoteLike, L: Into<NoteValue>>(&mut self, note: N, length: L) {
+        self.play_note_impl(note, length.into(), PAUSE_MS).await;
     }
 
-    pub async fn play_chord<N: MidiNoteLike>(&mut self, notes: &[N], length: Beat) {
-        for note in notes.iter().skip(1) {
-            let mut instrument = self.duplicate();
-            let midi_note = (*note).into_midi_note_or_panic();
+    pub async fn play_note_without_release<N: MidiNoteLike, L: Into<NoteValue>>(
+        &mut self,
+        note: N,
+        length: L,
+    ) {
+        self.play_note_impl(note, length.into(), 0.0).await;
+    }
+
+    /// Plays any frequency, in Hz, for the given length of music, like
+    /// `play_note`. This is handy for sounds that aren't notes, or notes
+    /// between the usual ones, but since it isn't a note, it won't show up
+    /// in recordings (e.g. piano rolls).
+    pub async fn play_frequency<L: Into<NoteValue>>(&mut self, frequency: f64, length: L) {
+        self.start_frequency(frequency);
+        self.wait_for_beat(length.into(), -PAUSE_MS).await;
+        self.release(PAUSE_MS).await;
+    }
+
+    /// Plays the note for exactly the given number of milliseconds
+    /// (including the pause after it), regardless of tempo. This doesn't
+    /// advance the instrument's position in the music, so `total_measures`
+    /// stays the same, and any music played afterwards is simply pushed
+    /// back by `ms`.
+    pub async fn play_note_ms<N: MidiNoteLike>(&mut self, note: N, ms: f64) {
+        let note = note.into_midi_note_or_panic();
+        let release_ms = f64::min(PAUSE_MS, ms);
+        let start_ms = self.start_note(note);
+        Player::wait(ms - release_ms).await;
+        self.release_note(note, start_ms, release_ms).await;
+        self.start_time += ms;
+    }
+
+    pub async fn play_melody<N: MidiNoteLike, L: Into<NoteValue> + Copy>(
+        &mut self,
+        melody: &[(N, L)],
+    ) {
+        for (note, length) in melody {
+            self.play_note(*note, *length).await;
+        }
+    }
+
+    pub async fn play_events(&mut self, events: &[Event]) {
+        for event in events {
+            match event {
+                Event::Note(note, length) => self.play_note(*note, *length).await,
+                Event::Chord(notes, length) => self.play_chord(notes, *length).await,
+                Event::Rest(length) => self.rest(*length).await,
+            }
+        }
+    }
+
+    /// Plays a sequence written in a compact notation, e.g.
+    /// `"E4:h E4:q~ F4:q r:h"`; see `parse_sequence` for the details. The
+    /// whole sequence is parsed before anything is played, so a mistake
+    /// anywhere in it means none of it plays.
+    pub async fn play_sequence(&mut self, sequence: &str) -> Result<(), SequenceParseError> {
+        for step in parse_sequence(sequence)? {
+            match step {
+                SequenceStep::Note {
+                    note,
+                    length,
+                    slur: false,
+                } => self.play_note(note, length).await,
+                SequenceStep::Note {
+                    note,
+                    length,
+                    slur: true,
+                } => self.play_note_without_release(note, length).await,
+                SequenceStep::Rest(length) => self.rest(length).await,
+            }
+        }
+        Ok(())
+    }
+
+    pub async fn play_chord<N: MidiNoteLike, L: Into<NoteValue>>(
+        &mut self,
+        notes: &[N],
+        length: L,
+    ) {
+        match self.chord_mode {
+            ChordMode::Voices => {
+                self.play_chord_with_release_stagger(notes, length, 0.0)
+                    .await
+            }
+            ChordMode::Arpeggio => self.play_arpeggio(notes, length.into()).await,
+        }
+    }
+
+    async fn play_arpeggio<N: MidiNoteLike>(&mut self, notes: &[N], length: NoteValue) {
+        let notes: Vec<MidiNote> = notes
+            .iter()
+            .map(|note| (*note).into_midi_note_or_panic())
+            .collect();
+        let start_ms = self.start_note(notes[0]);
+        let finished = Arc::new(AtomicBool::new(false));
+        {
+            let shape = self.shape.clone();
+            let notes = notes.clone();
+            let finished = finished.clone();
             Player::start_program(async move {
-                instrument.play_note(midi_note, length).await;
+                for note in notes.iter().cycle().skip(1) {
+                    Player::wait(1000.0 / ARPEGGIO_RATE_HZ).await;
+                    if finished.load(Ordering::Relaxed) {
+                        break;
+                    }
+                    shape
+                        .try_lock()
+                        .expect(LOCK_INVARIANT_VIOLATED)
+                        .set_frequency(note.frequency());
+                }
             });
         }
-        let first_note = (*notes.get(0).unwrap()).into_midi_note_or_panic();
-        self.play_note(first_note, length).await;
+        self.wait_for_beat(length, -PAUSE_MS).await;
+        finished.store(true, Ordering::Relaxed);
+        for note in notes {
+            record_note(NoteEvent {
+                note,
+                start_ms,
+                end_ms: Player::current_time(),
+            });
+        }
+        self.release(PAUSE_MS).await;
+    }
+
+    /// Plays a chord whose notes are released one after another, like a
+    /// piano's dampers, rather than all at once. Each note is released
+    /// `stagger_ms` after the one before it. Notes are never held past the
+    /// end of the chord, though, so the total stagger is limited by the
+    /// pause between notes.
+    pub async fn play_chord_with_release_stagger<N: MidiNoteLike, L: Into<NoteValue>>(
+        &mut self,
+        notes: &[N],
+        length: L,
+        stagger_ms: f64,
+    ) {
+        let length = length.into();
+        let release_ms = |i: usize| f64::max(PAUSE_MS - i as f64 * stagger_ms, 0.0);
+        for (i, note) in notes.iter().enumerate().skip(1) {
+            let midi_note = (*note).into_midi_note_or_panic();
+            self.start_chord_tail(self.fork(), midi_note, length, release_ms(i));
+        }
+        let first_note = (*notes.first().unwrap()).into_midi_note_or_panic();
+        self.play_note_impl(first_note, length, release_ms(0)).await;
     }
 
-    pub async fn rest(&mut self, length: Beat) {
-        self.shape.try_lock().unwrap().set_volume(0);
-        self.wait_for_beat(length, 0.0).await;
+    /// Plays a chord with its notes spread across the stereo field, from
+    /// the lowest note on the left to the highest on the right. A `spread`
+    /// of 1.0 pans the outermost notes hard left and right, smaller values
+    /// keep them closer to the center, and negative values put the low
+    /// notes on the right instead.
+    pub async fn play_chord_spread<N: MidiNoteLike, L: Into<NoteValue>>(
+        &mut self,
+        notes: &[N],
+        length: L,
+        spread: f64,
+    ) {
+        let length = length.into();
+        let mut notes: Vec<MidiNote> = notes
+            .iter()
+            .map(|note| (*note).into_midi_note_or_panic())
+            .collect();
+        notes.sort();
+        let count = notes.len();
+        for (i, note) in notes.into_iter().enumerate() {
+            let pan = if count > 1 {
+                spread * (i as f64 * 2.0 / (count - 1) as f64 - 1.0)
+            } else {
+                0.0
+            };
+            self.start_chord_tail(self.fork_panned(pan), note, length, PAUSE_MS);
+        }
+        // Our own voice isn't panned, so it just keeps time.
+        self.rest(length).await;
+    }
+
+    /// Plays one note of a chord on the given fork, in a program of its
+    /// own, which `all_notes_off` can stop.
+    fn start_chord_tail(
+        &self,
+        mut instrument: Instrument,
+        note: MidiNote,
+        length: NoteValue,
+        release_ms: f64,
+    ) {
+        let generation = {
+            let mut tails = self.chord_tails();
+            tails.voices.retain(|voice| voice.strong_count() > 0);
+            tails.voices.push(Arc::downgrade(&instrument.shape));
+            tails.generation
+        };
+        Player::start_program(ChordTail {
+            program: Box::pin(async move {
+                instrument.play_note_impl(note, length, release_ms).await;
+            }),
+            tails: self.chord_tails.clone(),
+            generation,
+        });
+    }
+
+    /// Immediately silences whatever the instrument is playing, including
+    /// the other notes of any chord it's in the middle of. Those notes'
+    /// programs end too, so they won't start sounding again.
+    pub fn all_notes_off(&mut self) {
+        let voices = {
+            let mut tails = self.chord_tails();
+            tails.generation += 1;
+            std::mem::take(&mut tails.voices)
+        };
+        for voice in voices.iter().filter_map(Weak::upgrade) {
+            voice
+                .try_lock()
+                .expect(LOCK_INVARIANT_VIOLATED)
+                .set_volume(0);
+        }
+        self.shape().set_volume(0);
+    }
+
+    /// Repeats the note at the given subdivision until the end of the
+    /// current measure (or, on a barline, for a whole measure), returning
+    /// how many times it was played. If the space left isn't a whole number
+    /// of subdivisions, the rest of it is filled with rests, so the
+    /// instrument always ends up exactly on the next barline.
+    pub async fn fill_measure_with<N: MidiNoteLike, L: Into<NoteValue>>(
+        &mut self,
+        note: N,
+        subdivision: L,
+    ) -> u64 {
+        let subdivision = subdivision.into();
+        let left = self.beat_counter().ticks_left_in_measure();
+        let count = left / subdivision.ticks();
+        for _ in 0..count {
+            self.play_note(note, subdivision).await;
+        }
+        let left = left % subdivision.ticks();
+        let mut sixty_fourths_left = left / TICKS_PER_SIXTY_FOURTH_BEAT;
+        while sixty_fourths_left > 0 {
+            // Rest for the longest plain (undotted) beat that still fits.
+            let sixty_fourths = 1 << sixty_fourths_left.ilog2();
+            self.rest(Beat::from_denominator(64 / sixty_fourths).unwrap())
+                .await;
+            sixty_fourths_left -= sixty_fourths;
+        }
+        let ticks_left = left % TICKS_PER_SIXTY_FOURTH_BEAT;
+        if ticks_left > 0 {
+            // Whatever's left is less than a 64th beat, which can happen
+            // partway through a tuplet, so rest for just that many ticks.
+            self.rest(
+                NoteValue::from(Beat::SixtyFourth).tuplet(TICKS_PER_SIXTY_FOURTH_BEAT, ticks_left),
+            )
+            .await;
+        }
+        count
+    }
+
+    pub async fn rest<L: Into<NoteValue>>(&mut self, length: L) {
+        self.shape().set_volume(0);
+        self.wait_for_beat(length.into(), 0.0).await;
+    }
+
+    /// Gradually slows down to the given tempo over the given length of
+    /// music, starting with the next note. The instrument stays at the new
+    /// tempo afterwards. Panics if the given tempo is faster than the
+    /// current one.
+    pub fn ritardando<L: Into<NoteValue>>(&mut self, to_bpm: u64, over: L) {
+        let mut beat_counter = self.beat_counter();
+        assert!(
+            to_bpm as f64 <= beat_counter.bpm(),
+            "A ritardando can't speed up from {} to {} bpm",
+            beat_counter.bpm(),
+            to_bpm
+        );
+        beat_counter.change_tempo_gradually(to_bpm, over);
+    }
+
+    /// Gradually speeds up to the given tempo over the given length of
+    /// music, starting with the next note. The instrument stays at the new
+    /// tempo afterwards. Panics if the given tempo is slower than the
+    /// current one.
+    pub fn accelerando<L: Into<NoteValue>>(&mut self, to_bpm: u64, over: L) {
+        let mut beat_counter = self.beat_counter();
+        assert!(
+            to_bpm as f64 >= beat_counter.bpm(),
+            "An accelerando can't slow down from {} to {} bpm",
+            beat_counter.bpm(),
+            to_bpm
+        );
+        beat_counter.change_tempo_gradually(to_bpm, over);
     }
 
     pub fn total_measures(&self) -> f64 {
-        self.beat_counter.try_lock().unwrap().total_measures()
+        self.beat_counter().total_measures()
+    }
+
+    /// Returns how far into the current measure the instrument is, from
+    /// 0.0 (on the barline) up to, but not including, 1.0.
+    pub fn position_in_measure(&self) -> f64 {
+        self.beat_counter().position_in_measure()
+    }
+
+    /// Returns how much of the current measure is left to play, as a
+    /// fraction of a measure. This is 1.0 on the barline.
+    pub fn measures_remaining_in_current(&self) -> f64 {
+        1.0 - self.position_in_measure()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ChordMode, Event, Instrument, ARPEGGIO_RATE_HZ};
+    use crate::{
+        analysis::peak,
+        beat::{Beat, BeatSettings, FOUR_FOUR},
+        note::MidiNote,
+        player::{Player, SilentClock, WAV_SAMPLE_RATE},
+        synth::Waveform,
+    };
+    use std::sync::{Arc, Mutex};
+
+    #[test]
+    fn test_fork_advances_independently() {
+        Player::render_to_vec(async {
+            let mut instrument =
+                Instrument::new(BeatSettings::new(240, FOUR_FOUR), 63, Waveform::Sine);
+            instrument.play_note("C4", Beat::Half).await;
+
+            let mut fork = instrument.fork();
+            assert_eq!(fork.total_measures(), 0.5);
+            fork.play_note("E4", Beat::Half).await;
+            assert_eq!(fork.total_measures(), 1.0);
+            assert_eq!(instrument.total_measures(), 0.5);
+        });
+    }
+
+    #[test]
+    fn test_forks_play_in_parallel() {
+        let samples = Player::render_to_vec(async {
+            let mut instrument =
+                Instrument::new(BeatSettings::new(240, FOUR_FOUR), 63, Waveform::Sine);
+            let mut fork = instrument.fork();
+            Player::start_program(async move {
+                fork.play_note("E4", Beat::Whole).await;
+            });
+            instrument.play_note("C4", Beat::Whole).await;
+        });
+        // A whole note at 240 BPM lasts one second.
+        let seconds = samples.len() as f64 / WAV_SAMPLE_RATE as f64;
+        assert!(seconds > 0.99 && seconds < 1.01, "{}", seconds);
+    }
+
+    #[test]
+    fn test_chords_share_state_without_contention() {
+        Player::render_to_vec(async {
+            let mut instrument =
+                Instrument::new(BeatSettings::new(480, FOUR_FOUR), 63, Waveform::Sine);
+            let mut clone = instrument.clone();
+            Player::start_program(async move {
+                for _ in 0..4 {
+                    clone.play_chord(&["C3", "E3", "G3"], Beat::Quarter).await;
+                }
+            });
+            for _ in 0..4 {
+                instrument
+                    .play_chord(&["C4", "E4", "G4"], Beat::Eighth)
+                    .await;
+            }
+        });
+    }
+
+    #[test]
+    fn test_play_note_ms_works() {
+        Player::render_to_vec(async {
+            let mut instrument =
+                Instrument::new(BeatSettings::new(240, FOUR_FOUR), 63, Waveform::Sine);
+            instrument.play_note_ms("C4", 300.0).await;
+            assert_eq!(instrument.total_measures(), 0.0);
+            assert!((Player::current_time() - 300.0).abs() < 1.0);
+
+            // The beats that follow aren't shortened to make up for it.
+            for _ in 0..4 {
+                instrument.play_note("C4", Beat::Quarter).await;
+            }
+            let start = Player::current_time();
+            instrument.play_note("C4", Beat::Quarter).await;
+            assert!((Player::current_time() - start - 250.0).abs() < 1.0);
+        });
+    }
+
+    #[test]
+    fn test_position_in_measure_works() {
+        Player::render_to_vec(async {
+            let mut instrument =
+                Instrument::new(BeatSettings::new(480, FOUR_FOUR), 63, Waveform::Sine);
+            for _ in 0..3 {
+                instrument.play_note("C4", Beat::Quarter).await;
+            }
+            assert_eq!(instrument.position_in_measure(), 0.75);
+            assert_eq!(instrument.measures_remaining_in_current(), 0.25);
+            instrument.play_note("C4", Beat::Quarter).await;
+            assert_eq!(instrument.position_in_measure(), 0.0);
+            assert_eq!(instrument.measures_remaining_in_current(), 1.0);
+        });
+    }
+
+    #[test]
+    fn test_fill_measure_with_lands_on_barline() {
+        Player::render_to_vec(async {
+            let mut instrument =
+                Instrument::new(BeatSettings::new(480, FOUR_FOUR), 63, Waveform::Sine);
+            instrument.play_note("C4", Beat::Half).await;
+            assert_eq!(instrument.fill_measure_with("E4", Beat::Eighth).await, 4);
+            assert_eq!(instrument.total_measures(), 1.0);
+
+            // Two dotted quarters leave a quarter's worth of rest.
+            assert_eq!(
+                instrument
+                    .fill_measure_with("E4", Beat::DottedQuarter)
+                    .await,
+                2
+            );
+            assert_eq!(instrument.total_measures(), 2.0);
+        });
+    }
+
+    #[test]
+    fn test_triplets_and_dotted_notes_keep_time() {
+        Player::render_to_vec(async {
+            let mut instrument =
+                Instrument::new(BeatSettings::new(480, FOUR_FOUR), 63, Waveform::Sine);
+            instrument.play_note("C4", Beat::Half.dotted()).await;
+            instrument.play_note("D4", Beat::Eighth.triplet()).await;
+            assert_eq!(
+                instrument
+                    .fill_measure_with("E4", Beat::Eighth.triplet())
+                    .await,
+                2
+            );
+            assert_eq!(instrument.total_measures(), 1.0);
+
+            // Partway through a triplet, the leftover is filled with rests.
+            instrument.play_note("C4", Beat::Eighth.triplet()).await;
+            assert_eq!(instrument.fill_measure_with("E4", Beat::Half).await, 1);
+            assert_eq!(instrument.total_measures(), 2.0);
+        });
+    }
+
+    #[test]
+    fn test_play_sequence_works() {
+        Player::render_to_vec(async {
+            let mut instrument =
+                Instrument::new(BeatSettings::new(480, FOUR_FOUR), 63, Waveform::Sine);
+            let result = instrument.play_sequence("E4:h E4:q~ F4:e r:e G4:w").await;
+            assert_eq!(result, Ok(()));
+            assert_eq!(instrument.total_measures(), 2.0);
+
+            let result = instrument.play_sequence("C4 D4:z E4").await;
+            assert_eq!(result.unwrap_err().index, 1);
+            assert_eq!(instrument.total_measures(), 2.0);
+        });
+    }
+
+    #[test]
+    fn test_arpeggio_cycles_one_voice_through_chord() {
+        let samples = Player::render_to_vec(async {
+            let mut instrument =
+                Instrument::new(BeatSettings::new(60, FOUR_FOUR), 63, Waveform::Sine);
+            instrument.set_chord_mode(ChordMode::Arpeggio);
+            instrument
+                .play_chord(&["C6", "E6", "G6"], Beat::Quarter)
+                .await;
+            assert_eq!(instrument.total_measures(), 0.25);
+        });
+        let note = |name: &str| MidiNote::parse(&name).unwrap();
+        let chord = [note("C6"), note("E6"), note("G6")];
+        let frame_samples = WAV_SAMPLE_RATE as f64 / ARPEGGIO_RATE_HZ;
+        let heard: Vec<MidiNote> = (1..50)
+            .map(|frame| {
+                // Skip the edges of each frame, so only one note is heard.
+                let start = (frame as f64 * frame_samples) as usize + 100;
+                let end = ((frame + 1) as f64 * frame_samples) as usize - 100;
+                let frequency = zero_crossing_frequency(&samples[start..end]);
+                *chord
+                    .iter()
+                    .min_by(|a, b| {
+                        let distance = |note: &&MidiNote| (note.frequency() - frequency).abs();
+                        distance(a).partial_cmp(&distance(b)).unwrap()
+                    })
+                    .unwrap()
+            })
+            .collect();
+        let expected: Vec<MidiNote> = chord.iter().cycle().skip(1).take(49).copied().collect();
+        assert_eq!(heard, expected);
+    }
+
+    fn render_slurred_octave(crossfade_ms: f64) -> Vec<f32> {
+        Player::render_to_vec(async move {
+            let mut instrument =
+                Instrument::new(BeatSettings::new(60, FOUR_FOUR), 127, Waveform::Sine);
+            instrument.set_crossfade(crossfade_ms);
+            instrument
+                .play_note_without_release("A4", Beat::Quarter)
+                .await;
+            instrument.play_note("A5", Beat::Quarter).await;
+        })
+    }
+
+    /// Returns the biggest change in slope from one sample to the next,
+    /// which spikes wherever the wave has a kink.
+    fn max_slope_change(samples: &[f32]) -> f32 {
+        samples
+            .windows(3)
+            .map(|window| (window[2] - 2.0 * window[1] + window[0]).abs())
+            .fold(0.0, f32::max)
+    }
+
+    #[test]
+    fn test_crossfade_smooths_slurred_note_changes() {
+        // The first note lasts exactly a second, a whole number of cycles
+        // of A4, so the wave is at its steepest when the note changes.
+        let boundary = WAV_SAMPLE_RATE as usize;
+        let around_boundary = boundary - 100..boundary + 100;
+        let plain = render_slurred_octave(0.0);
+        let crossfaded = render_slurred_octave(5.0);
+        let plain_kink = max_slope_change(&plain[around_boundary.clone()]);
+        let crossfaded_kink = max_slope_change(&crossfaded[around_boundary]);
+        assert!(
+            crossfaded_kink < plain_kink / 2.0,
+            "{} vs {}",
+            crossfaded_kink,
+            plain_kink
+        );
+        // Before the change, the two are the same.
+        assert_eq!(plain[..boundary - 1], crossfaded[..boundary - 1]);
+    }
+
+    fn render_note_onset(attack_transient: bool) -> Vec<f32> {
+        let samples = Player::render_to_vec(async move {
+            let mut instrument =
+                Instrument::new(BeatSettings::new(240, FOUR_FOUR), 127, Waveform::Sine);
+            instrument.set_attack_transient(attack_transient);
+            instrument.play_note("C3", Beat::Quarter).await;
+        });
+        samples[..WAV_SAMPLE_RATE as usize * 5 / 1000].to_vec()
+    }
+
+    /// Returns the average size of the jumps between consecutive samples,
+    /// which is larger the more high-frequency energy there is.
+    fn mean_abs_difference(samples: &[f32]) -> f32 {
+        let total: f32 = samples
+            .windows(2)
+            .map(|pair| (pair[1] - pair[0]).abs())
+            .sum();
+        total / (samples.len() - 1) as f32
+    }
+
+    #[test]
+    fn test_attack_transient_adds_high_frequencies_to_onset() {
+        let plain = mean_abs_difference(&render_note_onset(false));
+        let transient = mean_abs_difference(&render_note_onset(true));
+        assert!(transient > plain * 10.0, "{} vs. {}", transient, plain);
+    }
+
+    #[test]
+    fn test_play_events_works() {
+        let note = |name: &str| MidiNote::parse(&name).unwrap();
+        let events = [
+            Event::Note(note("C4"), Beat::Quarter),
+            Event::Rest(Beat::Quarter),
+            Event::Chord(vec![note("C4"), note("E4"), note("G4")], Beat::Half),
+            Event::Rest(Beat::Whole),
+        ];
+        Player::render_to_vec(async move {
+            let mut instrument =
+                Instrument::new(BeatSettings::new(480, FOUR_FOUR), 63, Waveform::Sine);
+            instrument.play_events(&events).await;
+            assert_eq!(instrument.total_measures(), 2.0);
+        });
+    }
+
+    #[test]
+    fn test_ritardando_slows_notes_down() {
+        Player::render_to_vec(async {
+            let mut instrument =
+                Instrument::new(BeatSettings::new(240, FOUR_FOUR), 63, Waveform::Sine);
+            instrument.ritardando(120, Beat::Half);
+            let mut last_note_ms = 0.0;
+            for _ in 0..4 {
+                let start = Player::current_time();
+                instrument.play_note("C4", Beat::Eighth).await;
+                let note_ms = Player::current_time() - start;
+                assert!(note_ms > last_note_ms, "{} <= {}", note_ms, last_note_ms);
+                last_note_ms = note_ms;
+            }
+        });
+    }
+
+    #[test]
+    #[should_panic(expected = "can't speed up")]
+    fn test_ritardando_rejects_faster_tempo() {
+        Player::render_to_vec(async {
+            let mut instrument =
+                Instrument::new(BeatSettings::new(120, FOUR_FOUR), 63, Waveform::Sine);
+            instrument.ritardando(200, Beat::Half);
+        });
+    }
+
+    /// Estimates the frequency of a sine wave by counting how often it
+    /// crosses zero.
+    fn zero_crossing_frequency(samples: &[f32]) -> f64 {
+        let crossings = samples
+            .windows(2)
+            .filter(|pair| (pair[0] < 0.0) != (pair[1] < 0.0))
+            .count();
+        crossings as f64 / 2.0 / (samples.len() as f64 / WAV_SAMPLE_RATE as f64)
+    }
+
+    #[test]
+    fn test_chord_spread_puts_lowest_and_highest_notes_on_opposite_sides() {
+        let samples = Player::render_to_stereo_vec(async {
+            let mut instrument =
+                Instrument::new(BeatSettings::new(60, FOUR_FOUR), 63, Waveform::Sine);
+            instrument
+                .play_chord_spread(&["G4", "C4"], Beat::Quarter, 1.0)
+                .await;
+        });
+        // Skip the attack and release, and look at the sustained middle.
+        let frames = samples.len() / 2;
+        let middle = &samples[frames / 4 * 2..frames / 2 * 2];
+        let left: Vec<f32> = middle.iter().step_by(2).copied().collect();
+        let right: Vec<f32> = middle.iter().skip(1).step_by(2).copied().collect();
+        let c4 = MidiNote::parse(&"C4").unwrap().frequency();
+        let g4 = MidiNote::parse(&"G4").unwrap().frequency();
+        assert!((zero_crossing_frequency(&left) - c4).abs() < 2.0);
+        assert!((zero_crossing_frequency(&right) - g4).abs() < 2.0);
+    }
+
+    #[test]
+    fn test_centered_chord_spread_is_as_loud_as_play_chord() {
+        let render = |spread: bool| {
+            Player::render_to_stereo_vec(async move {
+                let mut instrument =
+                    Instrument::new(BeatSettings::new(60, FOUR_FOUR), 63, Waveform::Sine);
+                if spread {
+                    instrument
+                        .play_chord_spread(&["C4", "E4", "G4"], Beat::Quarter, 0.0)
+                        .await;
+                } else {
+                    instrument
+                        .play_chord(&["C4", "E4", "G4"], Beat::Quarter)
+                        .await;
+                }
+            })
+        };
+        let ratio = peak(&render(true)) / peak(&render(false));
+        assert!((ratio - 1.0).abs() < 0.01, "{}", ratio);
+    }
+
+    #[test]
+    fn test_play_frequency_works() {
+        let samples = Player::render_to_vec(async {
+            let mut instrument =
+                Instrument::new(BeatSettings::new(60, FOUR_FOUR), 63, Waveform::Sine);
+            instrument.play_frequency(452.5, Beat::Quarter).await;
+            assert_eq!(instrument.total_measures(), 0.25);
+        });
+        let middle = &samples[samples.len() / 4..samples.len() / 2];
+        assert!((zero_crossing_frequency(middle) - 452.5).abs() < 2.0);
+    }
+
+    #[test]
+    fn test_all_notes_off_silences_chords() {
+        let samples = Player::render_to_vec(async {
+            let mut instrument =
+                Instrument::new(BeatSettings::new(60, FOUR_FOUR), 63, Waveform::Sine);
+            let mut clone = instrument.clone();
+            Player::start_program(async move {
+                Player::wait(500.0).await;
+                clone.all_notes_off();
+            });
+            instrument
+                .play_chord(&["C4", "E4", "G4"], Beat::Quarter)
+                .await;
+        });
+        let ms_to_samples = |ms: usize| ms * WAV_SAMPLE_RATE as usize / 1000;
+        assert!(peak(&samples[ms_to_samples(400)..ms_to_samples(500)]) > 0.1);
+        assert_eq!(peak(&samples[ms_to_samples(510)..]), 0.0);
+    }
+
+    fn render_chord_with_release_stagger(stagger_ms: f64) -> Vec<f32> {
+        Player::render_to_vec(async move {
+            let mut instrument =
+                Instrument::new(BeatSettings::new(60, FOUR_FOUR), 63, Waveform::Sine);
+            instrument
+                .play_chord_with_release_stagger(&["C4", "G4"], Beat::Quarter, stagger_ms)
+                .await;
+        })
+    }
+
+    #[test]
+    fn test_chord_release_stagger_works() {
+        let ms_to_samples = |ms: usize| ms * WAV_SAMPLE_RATE as usize / 1000;
+        // The quarter note lasts 1000 ms, and is released 50 ms before that.
+        let between_releases = ms_to_samples(955)..ms_to_samples(975);
+        let after_releases = ms_to_samples(985)..ms_to_samples(1000);
+
+        let unstaggered = render_chord_with_release_stagger(0.0);
+        assert_eq!(peak(&unstaggered[between_releases.clone()]), 0.0);
+        assert_eq!(peak(&unstaggered[after_releases.clone()]), 0.0);
+
+        let staggered = render_chord_with_release_stagger(30.0);
+        assert!(peak(&staggered[between_releases]) > 0.1);
+        assert_eq!(peak(&staggered[after_releases]), 0.0);
+    }
+
+    #[test]
+    fn test_notes_and_rests_end_on_the_beat() {
+        let times = Arc::new(Mutex::new(vec![]));
+        let program_times = times.clone();
+        let mut clock = SilentClock::new(async move {
+            let mut instrument =
+                Instrument::new(BeatSettings::new(120, FOUR_FOUR), 63, Waveform::Sine);
+            instrument.play_note("C4", Beat::Quarter).await;
+            program_times.lock().unwrap().push(Player::current_time());
+            instrument.rest(Beat::Half).await;
+            program_times.lock().unwrap().push(Player::current_time());
+            instrument.play_note("D4", Beat::Eighth).await;
+            program_times.lock().unwrap().push(Player::current_time());
+        });
+        clock.run_to_end();
+        let times = times.lock().unwrap();
+        assert_eq!(times.len(), 3);
+        for (time, expected) in times.iter().zip([500.0, 1500.0, 1750.0]) {
+            assert!((time - expected).abs() < 1e-9, "{} != {}", time, expected);
+        }
     }
 }
@@ -0,0 +1,49 @@
+// Without the `std` feature, only the oscillator math in `synth` (and the
+// `rng` its noise comes from) is available, which makes it usable on
+// embedded targets.
+#![cfg_attr(not(any(feature = "std", test)), no_std)]
+
+#[cfg(not(any(feature = "std", feature = "libm")))]
+compile_error!("Building without the `std` feature requires the `libm` feature.");
+
+#[cfg(all(target_arch = "wasm32", feature = "cpal"))]
+compile_error!(
+    "The `cpal` feature isn't supported on wasm32; build with `--no-default-features --features wasm`."
+);
+
+#[cfg(feature = "std")]
+pub mod analysis;
+#[cfg(feature = "std")]
+pub mod beat;
+#[cfg(feature = "std")]
+mod dummy_waker;
+#[cfg(feature = "std")]
+pub mod effects;
+#[cfg(feature = "std")]
+pub mod filter;
+#[cfg(feature = "std")]
+pub mod generate;
+#[cfg(feature = "std")]
+pub mod instrument;
+#[cfg(feature = "std")]
+pub mod metronome;
+#[cfg(feature = "std")]
+pub mod note;
+#[cfg(feature = "std")]
+pub mod piano_roll;
+#[cfg(feature = "std")]
+pub mod player;
+#[cfg(feature = "std")]
+pub mod recording;
+pub mod rng;
+#[cfg(feature = "std")]
+pub mod sequence;
+#[cfg(feature = "std")]
+pub mod songs;
+pub mod synth;
+#[cfg(feature = "std")]
+mod synth_registry;
+#[cfg(feature = "std")]
+mod waiter;
+#[cfg(feature = "wasm")]
+pub mod wasm;
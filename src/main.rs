@@ -1,23 +1,47 @@
+use std::ffi::OsString;
+#[cfg(feature = "cpal")]
+use std::io::Write;
+use std::path::{Path, PathBuf};
 use std::process::Command;
+#[cfg(feature = "cpal")]
+use std::time::Duration;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
 
-use beat::{Beat, BeatSettings, FOUR_FOUR, THREE_FOUR};
-use clap::{AppSettings, ArgEnum, Parser, Subcommand};
+use clap::{AppSettings, ArgEnum, CommandFactory, Parser, Subcommand};
+use clap_complete::Shell;
+#[cfg(feature = "cpal")]
 use cpal::traits::{DeviceTrait, HostTrait};
+#[cfg(feature = "cpal")]
 use cpal::SampleFormat;
 
-mod beat;
-mod dummy_waker;
-mod instrument;
-mod note;
-mod player;
-mod synth;
-mod synth_registry;
-mod waiter;
-
-use instrument::Instrument;
-use note::{MidiNote, MAJOR_SCALE, MINOR_HARMONIC_SCALE, OCTAVE};
-use player::{Player, PlayerProgram, PlayerProxy, WAV_CHANNELS, WAV_SAMPLE_RATE};
-use synth::{AudioShape, Waveform};
+use rust_synth::analysis::{amplitude_to_db, normalize_loudness, normalize_peak, peak, PeakMeter};
+use rust_synth::beat::{Beat, BeatSettings, FOUR_FOUR, THREE_FOUR};
+use rust_synth::effects::{
+    apply_stereo_effects, AutoWah, Crossfeed, Equalizer, Gate, ModulatedDelay, PerChannel,
+    PingPongDelay, StereoEffect,
+};
+use rust_synth::filter::downsample;
+use rust_synth::generate::{random_walk_melody, MarkovModel, DEFAULT_STEP_WEIGHTS};
+use rust_synth::instrument::Instrument;
+use rust_synth::metronome::metronome;
+use rust_synth::note::{
+    chord, snap_to_scale, ChordQuality, MidiNote, MidiNoteLike, Semitones, MAJOR_SCALE,
+    MINOR_HARMONIC_SCALE, MINOR_MELODIC_SCALE, MINOR_NATURAL_SCALE, OCTAVE,
+};
+use rust_synth::piano_roll::piano_roll_svg;
+use rust_synth::player::{
+    downmix_to_mono, Player, PlayerProgram, RenderReport, WavOptions, DEFAULT_WAV_FLUSH_INTERVAL,
+    WAV_SAMPLE_RATE,
+};
+#[cfg(feature = "cpal")]
+use rust_synth::player::{Levels, PlayerProxy};
+use rust_synth::recording::{record_events, record_notes, ScheduledEvent};
+use rust_synth::rng::Rng;
+use rust_synth::songs::{
+    self, CAPTAIN_SILVER_1, CAPTAIN_SILVER_2, CAPTAIN_SILVER_3, EVRYBODY_LIKES_IT_SO,
+    ITS_A_FAVRITE_DISH, SING_A_TUNE_OF_TUNA_FISH, TUNA_FISH_TUNA_FISH, WITCH_MELODY,
+};
+use rust_synth::synth::{AudioShape, Release, StereoAudioShape, Waveform};
 
 #[derive(Parser, Debug)]
 #[clap(author, version, about, long_about = None)]
@@ -25,10 +49,132 @@ use synth::{AudioShape, Waveform};
 #[clap(global_setting(AppSettings::UseLongFormatForHelpSubcommand))]
 struct Args {
     #[clap(subcommand)]
-    command: Commands,
+    command: Option<Commands>,
     #[clap(long, short = 'o', global = true)]
     /// Output to WAV or MP3 file (MP3 requires ffmpeg).
     output: Option<String>,
+    #[clap(
+        long,
+        global = true,
+        allow_hyphen_values = true,
+        conflicts_with = "normalize-loudness"
+    )]
+    /// Scale the output so its loudest sample hits this level in dBFS
+    /// (e.g. -1). Prevents clipping, but doesn't make songs sound equally
+    /// loud. Requires --output.
+    normalize_peak: Option<f32>,
+    #[clap(long, global = true, allow_hyphen_values = true)]
+    /// Scale the output so its overall RMS level hits this level in dBFS
+    /// (e.g. -18). Better for matching perceived loudness across songs,
+    /// but may clip if set too high. Requires --output.
+    normalize_loudness: Option<f32>,
+    #[clap(long, global = true)]
+    /// Write a click track that follows the beat for the length of the song
+    /// to this WAV file. The song itself is only written if --output is
+    /// also given, and isn't played.
+    click_track: Option<String>,
+    #[clap(long, global = true)]
+    /// Write a piano roll of the notes in the song to this SVG file. As
+    /// with --click-track, the song itself is only written if --output is
+    /// also given, and isn't played.
+    piano_roll: Option<String>,
+    #[clap(
+        long,
+        global = true,
+        arg_enum,
+        min_values = 0,
+        require_equals = true,
+        default_missing_value = "table"
+    )]
+    /// Print everything the song does to its synth voices, with timings,
+    /// as a table (the default) or as JSON (--dump-events=json), without
+    /// generating any audio. As with --click-track, the song itself is
+    /// only written if --output is also given, and isn't played.
+    dump_events: Option<EventFormat>,
+    #[clap(long, global = true)]
+    /// Seed for the random number generator, which controls everything
+    /// random, such as generated melodies and effect modulation (default
+    /// based on the current time).
+    seed: Option<u64>,
+    #[clap(long, global = true)]
+    /// Apply a chorus effect. Requires --output.
+    chorus: bool,
+    #[clap(long, global = true)]
+    /// Apply a flanger effect. Requires --output.
+    flanger: bool,
+    #[clap(long, global = true)]
+    /// Apply a delay whose echoes bounce between the left and right
+    /// channels, this many milliseconds apart. Requires --output.
+    ping_pong: Option<f64>,
+    #[clap(long, global = true)]
+    /// Apply an auto-wah, a filter that opens up as the music gets louder.
+    /// Requires --output.
+    auto_wah: bool,
+    #[clap(long, global = true)]
+    /// Rhythmically mute the music in time with the beat, using a pattern
+    /// of sixteenth notes where 'x' is on and '.' is off (e.g. "x.xx").
+    /// Requires --output.
+    gate: Option<String>,
+    #[clap(long, global = true, allow_hyphen_values = true)]
+    /// Boost (or, if negative, cut) the bass below 250 Hz by this many
+    /// decibels. Requires --output.
+    eq_low: Option<f64>,
+    #[clap(long, global = true, allow_hyphen_values = true)]
+    /// Boost (or, if negative, cut) the midrange around 1 kHz by this many
+    /// decibels. Requires --output.
+    eq_mid: Option<f64>,
+    #[clap(long, global = true, allow_hyphen_values = true)]
+    /// Boost (or, if negative, cut) the treble above 4 kHz by this many
+    /// decibels. Requires --output.
+    eq_high: Option<f64>,
+    #[clap(long, global = true)]
+    /// Bleed a little of each channel into the other, which makes
+    /// hard-panned sounds more natural on headphones. Requires --output.
+    crossfeed: bool,
+    #[clap(long, global = true, value_parser = clap::value_parser!(u16).range(1..=16))]
+    /// Render (and apply effects) at this many times the output sample
+    /// rate, then filter out what's too high to keep and bring it back
+    /// down (default 1, off). This cuts the aliasing of naive waves and
+    /// effects, but makes rendering that much slower. Requires --output.
+    oversample: Option<u16>,
+    #[clap(long, global = true)]
+    /// While playing, show a meter of the output level in the terminal.
+    meter: bool,
+    #[clap(long, global = true)]
+    /// While writing --output, save the audio rendered so far to disk
+    /// every this many seconds of audio, so a partial file is left behind
+    /// if rendering is interrupted (default 10).
+    flush_interval: Option<f64>,
+    #[clap(long, global = true)]
+    /// Multiply the tempo of the song by this factor, without changing its
+    /// pitch (e.g. 0.5 for half speed). The result is rounded to the
+    /// nearest whole BPM.
+    tempo_scale: Option<f64>,
+    #[clap(long, global = true)]
+    /// Only play (or write) the first this many seconds, for quickly
+    /// auditioning changes to a long song.
+    preview: Option<f64>,
+    #[clap(long, global = true)]
+    /// Write a mono file instead of a stereo one. The channels are
+    /// averaged, so sounds that are the same in both keep their level.
+    downmix_mono: bool,
+    #[clap(
+        long,
+        global = true,
+        value_parser = clap::value_parser!(u16).range(1..=2),
+        conflicts_with = "downmix-mono"
+    )]
+    /// Number of channels to write to --output, 1 or 2 (default 2). One
+    /// channel is the same as --downmix-mono. With two, mono sounds are
+    /// written identically to both channels.
+    channels: Option<u16>,
+    #[clap(long, global = true)]
+    /// Audio host (backend) to play through, e.g. ALSA or JACK (default
+    /// the system's default). See --list-devices for the ones available.
+    host: Option<String>,
+    #[clap(long)]
+    /// List the available audio hosts and their output devices, then exit.
+    list_devices: bool,
 }
 
 #[derive(Subcommand, Debug)]
@@ -45,9 +191,76 @@ enum Commands {
         #[clap(long)]
         /// Beats per minute (default 60).
         bpm: Option<u64>,
+        #[clap(long, conflicts_with = "bpm")]
+        /// Length of each note in seconds, instead of setting a tempo with
+        /// --bpm. This isn't affected by --tempo-scale, and there's no beat
+        /// for options like --click-track to follow.
+        note_duration: Option<f64>,
+        #[clap(long, arg_enum)]
+        /// Which way to play the scale (default updown).
+        direction: Option<Direction>,
         #[clap(long)]
         /// Play two scales, the second an octave above the first.
         octaves: bool,
+        #[clap(long)]
+        /// Detune the left and right channels apart by this many cents (default 0).
+        detune: Option<f64>,
+        #[clap(long)]
+        /// Hold the last note for twice as long, then let it ring out
+        /// instead of stopping abruptly.
+        ring: bool,
+    },
+    /// Plays a randomly generated melody that walks along a scale, or
+    /// that imitates one of the built-in songs.
+    Generate {
+        /// Tonic of the scale, e.g. C4, A#2, Bb5 (default C4).
+        note: Option<String>,
+        #[clap(arg_enum)]
+        scale: Option<Scale>,
+        #[clap(long)]
+        /// Number of notes to generate (default 32).
+        length: Option<usize>,
+        #[clap(long, arg_enum, conflicts_with_all = &["note", "scale"])]
+        /// Imitate a built-in song using a Markov chain instead of walking a scale.
+        markov: Option<Song>,
+        #[clap(long, arg_enum)]
+        /// Waveform (default sine).
+        wave: Option<Wave>,
+        #[clap(long)]
+        /// Beats per minute (default 120).
+        bpm: Option<u64>,
+    },
+    /// Plays a melody given as notes separated by spaces, e.g. "C4 E4 G4 C5".
+    Notes {
+        notes: String,
+        #[clap(long, arg_enum)]
+        /// Length of each note (default quarter).
+        beat: Option<NoteLength>,
+        #[clap(long, arg_enum)]
+        /// Waveform (default sine).
+        wave: Option<Wave>,
+        #[clap(long)]
+        /// Beats per minute (default 120).
+        bpm: Option<u64>,
+        #[clap(long, arg_enum)]
+        /// Snap any notes that aren't in this scale to the nearest one
+        /// that is, so there are no wrong notes.
+        scale_lock: Option<Scale>,
+        #[clap(long, requires = "scale-lock")]
+        /// Tonic of --scale-lock's scale, e.g. D or Bb (default C).
+        key: Option<String>,
+    },
+    /// Sustains a chord, given as a root and a chord name (e.g. "C4 major",
+    /// "A3 min7") or as a list of notes (e.g. "C4 E4 G4").
+    Chord {
+        #[clap(required = true)]
+        notes: Vec<String>,
+        #[clap(long)]
+        /// How long to hold the chord, in seconds (default 2).
+        duration: Option<f64>,
+        #[clap(long, arg_enum)]
+        /// Waveform (default sine).
+        wave: Option<Wave>,
     },
     /// Plays a siren sound.
     Siren {},
@@ -57,12 +270,79 @@ enum Commands {
     Tuna {},
     /// Plays the song "Which is Witch?" from pg. 30 of Schaum's Red Book (Alfred).
     Witch {},
+    /// Prints a shell completion script.
+    Completions {
+        #[clap(arg_enum)]
+        shell: Shell,
+    },
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, ArgEnum)]
+enum EventFormat {
+    Table,
+    Json,
 }
 
 #[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, ArgEnum)]
 enum Scale {
     Major,
+    MinorNatural,
     MinorHarmonic,
+    MinorMelodic,
+}
+
+impl Scale {
+    /// Returns the steps between the notes of the scale, going up.
+    fn intervals(&self) -> [Semitones; 7] {
+        match self {
+            Scale::Major => MAJOR_SCALE,
+            Scale::MinorNatural => MINOR_NATURAL_SCALE,
+            Scale::MinorHarmonic => MINOR_HARMONIC_SCALE,
+            Scale::MinorMelodic => MINOR_MELODIC_SCALE,
+        }
+    }
+
+    /// Returns the steps between the notes of the scale when it's played
+    /// going down, still listed from the bottom up. For most scales, this
+    /// is the same as going up.
+    fn descending_intervals(&self) -> [Semitones; 7] {
+        match self {
+            Scale::MinorMelodic => MINOR_NATURAL_SCALE,
+            _ => self.intervals(),
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, ArgEnum)]
+enum Direction {
+    /// Ascending, from the tonic up to the octave.
+    Up,
+    /// Descending, from the octave down to the tonic.
+    Down,
+    /// Ascending, then descending back to the tonic.
+    #[clap(name = "updown")]
+    UpDown,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, ArgEnum)]
+enum Song {
+    CaptainSilver,
+    Tuna,
+    Witch,
+}
+
+impl Song {
+    fn melody(&self) -> Vec<(MidiNote, Beat)> {
+        let melody = match self {
+            Song::CaptainSilver => songs::captain_silver_melody(),
+            Song::Tuna => songs::tuna_melody(),
+            Song::Witch => songs::witch_melody(),
+        };
+        melody
+            .into_iter()
+            .map(|(note, length)| (note.into_midi_note_or_panic(), length))
+            .collect()
+    }
 }
 
 #[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, ArgEnum)]
@@ -73,9 +353,9 @@ enum Wave {
     Sawtooth,
 }
 
-impl Into<Waveform> for Wave {
-    fn into(self) -> Waveform {
-        match self {
+impl From<Wave> for Waveform {
+    fn from(wave: Wave) -> Self {
+        match wave {
             Wave::Sine => Waveform::Sine,
             Wave::Square => Waveform::Square,
             Wave::Triangle => Waveform::Triangle,
@@ -84,51 +364,525 @@ impl Into<Waveform> for Wave {
     }
 }
 
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, ArgEnum)]
+enum NoteLength {
+    Whole,
+    Half,
+    Quarter,
+    Eighth,
+    Sixteenth,
+}
+
+impl From<NoteLength> for Beat {
+    fn from(length: NoteLength) -> Self {
+        match length {
+            NoteLength::Whole => Beat::Whole,
+            NoteLength::Half => Beat::Half,
+            NoteLength::Quarter => Beat::Quarter,
+            NoteLength::Eighth => Beat::Eighth,
+            NoteLength::Sixteenth => Beat::Sixteenth,
+        }
+    }
+}
+
 impl Args {
-    fn run_program<P: PlayerProgram>(&self, program: P) {
+    fn uses_randomness(&self) -> bool {
+        matches!(self.command, Some(Commands::Generate { .. })) || self.chorus || self.flanger
+    }
+
+    /// Prints a message about what's going on. With --dump-events, stdout
+    /// is kept for the events alone, so these go to stderr instead.
+    fn inform(&self, message: &str) {
+        if self.dump_events.is_some() {
+            eprintln!("{}", message);
+        } else {
+            println!("{}", message);
+        }
+    }
+
+    /// Returns the random number generator that everything random should
+    /// draw from, so that a given seed always produces the same output.
+    fn new_rng(&self) -> Rng {
+        let seed = self.seed.unwrap_or_else(|| {
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_nanos() as u64
+        });
+        if self.uses_randomness() {
+            self.inform(&format!("Using random seed {}.", seed));
+        }
+        Player::set_seed(seed);
+        Rng::new(seed)
+    }
+
+    /// Applies --tempo-scale to the given beat settings.
+    fn beats(&self, beat_settings: BeatSettings) -> BeatSettings {
+        beat_settings.with_tempo_scale(self.tempo_scale.unwrap_or(1.0))
+    }
+
+    /// Returns the effects to apply, for audio at the given sample rate.
+    fn effects(
+        &self,
+        rng: &mut Rng,
+        beat_settings: Option<BeatSettings>,
+        sample_rate: usize,
+    ) -> Vec<Box<dyn StereoEffect>> {
+        let mut effects: Vec<Box<dyn StereoEffect>> = vec![];
+        if self.chorus {
+            effects.push(Box::new(PerChannel::new(
+                ModulatedDelay::chorus(sample_rate, rng),
+                ModulatedDelay::chorus(sample_rate, rng),
+            )));
+        }
+        if self.flanger {
+            effects.push(Box::new(PerChannel::new(
+                ModulatedDelay::flanger(sample_rate, rng),
+                ModulatedDelay::flanger(sample_rate, rng),
+            )));
+        }
+        if self.auto_wah {
+            effects.push(Box::new(PerChannel::new(
+                AutoWah::new(sample_rate, 4.0, 2000.0, 3.0),
+                AutoWah::new(sample_rate, 4.0, 2000.0, 3.0),
+            )));
+        }
+        if let Some(pattern) = &self.gate {
+            let beat_settings = require_beat(beat_settings, "gate to");
+            let pattern = parse_gate_pattern_or_exit(pattern);
+            effects.push(Box::new(PerChannel::new(
+                Gate::new(sample_rate, beat_settings, Beat::Sixteenth, pattern.clone()),
+                Gate::new(sample_rate, beat_settings, Beat::Sixteenth, pattern),
+            )));
+        }
+        if let Some(delay_ms) = self.ping_pong {
+            effects.push(Box::new(PingPongDelay::new(
+                sample_rate,
+                delay_ms,
+                0.5,
+                0.4,
+            )));
+        }
+        if self.is_equalizing() {
+            let equalizer = || {
+                Equalizer::new(
+                    sample_rate,
+                    self.eq_low.unwrap_or(0.0),
+                    self.eq_mid.unwrap_or(0.0),
+                    self.eq_high.unwrap_or(0.0),
+                )
+            };
+            effects.push(Box::new(PerChannel::new(equalizer(), equalizer())));
+        }
+        if self.crossfeed {
+            effects.push(Box::new(Crossfeed::new(sample_rate, 0.3, 0.3)));
+        }
+        effects
+    }
+
+    fn is_equalizing(&self) -> bool {
+        self.eq_low.is_some() || self.eq_mid.is_some() || self.eq_high.is_some()
+    }
+
+    fn is_post_processing(&self) -> bool {
+        self.normalize_peak.is_some()
+            || self.normalize_loudness.is_some()
+            || self.chorus
+            || self.flanger
+            || self.ping_pong.is_some()
+            || self.auto_wah
+            || self.gate.is_some()
+            || self.is_equalizing()
+            || self.crossfeed
+            || self.oversample_factor() > 1
+    }
+
+    fn oversample_factor(&self) -> usize {
+        self.oversample.unwrap_or(1) as usize
+    }
+
+    fn wav_options(&self) -> WavOptions {
+        WavOptions {
+            flush_interval: self.flush_interval.unwrap_or(DEFAULT_WAV_FLUSH_INTERVAL),
+            downmix_mono: self.downmix_mono || self.channels == Some(1),
+        }
+    }
+
+    fn write_wav<P: PlayerProgram>(
+        &self,
+        rng: &mut Rng,
+        beat_settings: Option<BeatSettings>,
+        filename: &str,
+        program: P,
+    ) -> RenderReport {
+        let options = self.wav_options();
+        if !self.is_post_processing() {
+            return Player::write_wav_with_options(filename, program, options);
+        }
+        let start = Instant::now();
+        let factor = self.oversample_factor();
+        let sample_rate = WAV_SAMPLE_RATE as usize * factor;
+        let mut samples = Player::render_to_stereo_vec_at(sample_rate, program);
+        apply_stereo_effects(
+            &mut samples,
+            &mut self.effects(rng, beat_settings, sample_rate),
+        );
+        if factor > 1 {
+            samples = downsample(&samples, 2, factor, WAV_SAMPLE_RATE as usize);
+        }
+        if options.downmix_mono {
+            samples = downmix_to_mono(&samples);
+        }
+        if let Some(target_db) = self.normalize_peak {
+            normalize_peak(&mut samples, target_db);
+        }
+        if let Some(target_db) = self.normalize_loudness {
+            normalize_loudness(&mut samples, target_db);
+            let peak_db = amplitude_to_db(peak(&samples));
+            if peak_db > 0.0 {
+                self.inform(&format!(
+                    "Warning: loudness normalization caused clipping (peak {:.1} dBFS).",
+                    peak_db
+                ));
+            }
+        }
+        let channels = options.channels();
+        let report = RenderReport::new(samples.len() / channels as usize, start.elapsed())
+            .with_levels(PeakMeter::measure(&samples, channels as usize));
+        Player::write_wav_samples(filename, &samples, channels);
+        report
+    }
+
+    fn write_click_track<P: PlayerProgram>(
+        &self,
+        filename: &str,
+        beat_settings: Option<BeatSettings>,
+        program: P,
+    ) {
+        let beat_settings = require_beat(beat_settings, "make a click track from");
+        let beat_ms = beat_settings.duration_in_millis(beat_settings.time_signature.beat_unit());
+        let beats = (Player::duration_in_millis(program) / beat_ms).round() as u64;
+        Player::write_wav_with_options(
+            filename,
+            metronome(beat_settings, beats),
+            self.wav_options(),
+        );
+        self.inform(&format!("Wrote click track {}.", filename));
+    }
+
+    /// Runs the program returned by `make_program`, which may be called more
+    /// than once (e.g. to figure out how long the program is). The beat
+    /// settings are used for features that need to follow the program's
+    /// beat, and should be `None` if it doesn't have one.
+    fn run_program<P: PlayerProgram, F: Fn() -> P>(
+        &self,
+        rng: &mut Rng,
+        beat_settings: Option<BeatSettings>,
+        make_program: F,
+    ) {
+        if self.output.as_ref().is_some_and(|f| f.ends_with(".mp3"))
+            && find_in_path("ffmpeg", std::env::var_os("PATH")).is_none()
+        {
+            println!("Writing MP3 files requires ffmpeg, which wasn't found on your PATH.");
+            println!("Install it from https://ffmpeg.org/, or write a .wav file instead.");
+            std::process::exit(1);
+        }
+        if let Some(seconds) = self.preview {
+            if seconds <= 0.0 {
+                println!("--preview must be a positive number of seconds.");
+                std::process::exit(1);
+            }
+            self.inform(&format!(
+                "This is a preview of only the first {} seconds.",
+                seconds
+            ));
+            Player::set_time_limit(Some(seconds * 1000.0));
+        }
+        if let Some(filename) = &self.click_track {
+            self.write_click_track(filename, beat_settings, make_program());
+        }
+        if let Some(filename) = &self.piano_roll {
+            std::fs::write(filename, piano_roll_svg(&record_notes(make_program()))).unwrap();
+            self.inform(&format!("Wrote piano roll {}.", filename));
+        }
+        if let Some(format) = self.dump_events {
+            print!("{}", format_events(&record_events(make_program()), format));
+        }
+        if self.output.is_none()
+            && (self.click_track.is_some()
+                || self.piano_roll.is_some()
+                || self.dump_events.is_some())
+        {
+            return;
+        }
+        let program = make_program();
         if let Some(filename) = &self.output {
             let is_mp3 = filename.ends_with(".mp3");
-            let wav_filename = if is_mp3 { "temp.wav" } else { &filename };
-            Player::write_wav(wav_filename, program);
+            let wav_filename = if is_mp3 { "temp.wav" } else { filename };
+            let report = self.write_wav(rng, beat_settings, wav_filename, program);
             if is_mp3 {
-                let success = convert_wav_to_mp3(wav_filename, filename);
-                std::fs::remove_file(wav_filename).unwrap();
-                if !success {
-                    std::process::exit(1);
+                match convert_wav_to_mp3(wav_filename, filename, self.wav_options().channels()) {
+                    Mp3Conversion::Converted => std::fs::remove_file(wav_filename).unwrap(),
+                    Mp3Conversion::FfmpegMissing => {
+                        let kept_filename = keep_wav_instead_of_mp3(wav_filename, filename);
+                        self.inform(&format!(
+                            "ffmpeg wasn't found, so wrote {} instead (install ffmpeg to write MP3 files).",
+                            kept_filename
+                        ));
+                        self.inform(&report.to_string());
+                        return;
+                    }
+                    Mp3Conversion::FfmpegFailed => {
+                        std::fs::remove_file(wav_filename).unwrap();
+                        std::process::exit(1);
+                    }
                 }
             }
-            println!("Wrote {}.", filename);
+            self.inform(&format!("Wrote {}.", filename));
+            self.inform(&report.to_string());
         } else {
-            let player = build_stream(program);
-            player.play_until_finished();
+            if self.is_post_processing() {
+                println!("Normalization and effects require an output file (use --output).");
+                std::process::exit(1);
+            }
+            play_live(program, self.host.as_deref(), self.meter);
         }
     }
 }
 
-fn convert_wav_to_mp3(wav_filename: &str, mp3_filename: &String) -> bool {
-    let mut success = false;
-    let cmd = format!(
-        "ffmpeg -y -i {} -ar {} -ac {} -b:a 128k {}",
-        wav_filename, WAV_SAMPLE_RATE, WAV_CHANNELS, mp3_filename
-    );
-    if let Ok(mut child) = Command::new("bash").args(["-c", &cmd]).spawn() {
-        if let Ok(ffmpeg_exit_code) = child.wait() {
-            if ffmpeg_exit_code.success() {
-                success = true;
-            } else {
-                println!("An error occurred running ffmpeg.");
+/// Formats events from `record_events`, one per line.
+fn format_events(events: &[ScheduledEvent], format: EventFormat) -> String {
+    let mut output = String::new();
+    match format {
+        EventFormat::Table => {
+            output.push_str("   time_ms  voice  action     frequency  volume\n");
+            for event in events {
+                output.push_str(&format!(
+                    "{:>10.1}  {:>5}  {:<9}  {:>9.2}  {:>6}\n",
+                    event.time_ms,
+                    event.voice,
+                    event.action.name(),
+                    event.frequency,
+                    event.volume
+                ));
             }
-        } else {
+        }
+        EventFormat::Json => {
+            output.push_str("[\n");
+            for (i, event) in events.iter().enumerate() {
+                let separator = if i + 1 < events.len() { "," } else { "" };
+                output.push_str(&format!(
+                    "  {{\"time_ms\": {}, \"voice\": {}, \"action\": \"{}\", \"frequency\": {}, \"volume\": {}}}{}\n",
+                    event.time_ms,
+                    event.voice,
+                    event.action.name(),
+                    event.frequency,
+                    event.volume,
+                    separator
+                ));
+            }
+            output.push_str("]\n");
+        }
+    }
+    output
+}
+
+/// Returns where the given program is, if it's in one of the directories
+/// of `path` (the value of the `PATH` environment variable), like `which`.
+fn find_in_path(program: &str, path: Option<OsString>) -> Option<PathBuf> {
+    let filename = format!("{}{}", program, std::env::consts::EXE_SUFFIX);
+    std::env::split_paths(&path?)
+        .map(|dir| dir.join(&filename))
+        .find(|candidate| candidate.is_file())
+}
+
+/// What happened when we tried to convert a WAV file to MP3.
+enum Mp3Conversion {
+    Converted,
+    /// ffmpeg couldn't be found, so the WAV file is all we have.
+    FfmpegMissing,
+    /// ffmpeg ran, or was found but couldn't be started, and failed.
+    FfmpegFailed,
+}
+
+fn convert_wav_to_mp3(wav_filename: &str, mp3_filename: &str, channels: u16) -> Mp3Conversion {
+    let sample_rate = WAV_SAMPLE_RATE.to_string();
+    let channels = channels.to_string();
+    let result = Command::new("ffmpeg")
+        .args([
+            "-y",
+            "-i",
+            wav_filename,
+            "-ar",
+            &sample_rate,
+            "-ac",
+            &channels,
+        ])
+        .args(["-b:a", "128k", mp3_filename])
+        .spawn();
+    let mut child = match result {
+        Ok(child) => child,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+            return Mp3Conversion::FfmpegMissing;
+        }
+        Err(err) => {
+            println!("Starting ffmpeg failed: {}", err);
+            return Mp3Conversion::FfmpegFailed;
+        }
+    };
+    match child.wait() {
+        Ok(ffmpeg_exit_code) if ffmpeg_exit_code.success() => Mp3Conversion::Converted,
+        Ok(_) => {
+            println!("An error occurred running ffmpeg.");
+            Mp3Conversion::FfmpegFailed
+        }
+        Err(_) => {
             println!("An error occurred while waiting for ffmpeg to exit.");
+            Mp3Conversion::FfmpegFailed
         }
+    }
+}
+
+/// Moves the temporary WAV file made for an MP3 conversion that couldn't
+/// happen next to where the MP3 would have gone, and returns its new name.
+/// If there's already a file by that name, a number is added to the new
+/// one's (e.g. "song-1.wav") rather than overwriting it.
+fn keep_wav_instead_of_mp3(wav_filename: &str, mp3_filename: &str) -> String {
+    let mp3_path = Path::new(mp3_filename);
+    let stem = mp3_path.file_stem().unwrap_or_default().to_string_lossy();
+    let kept_filename = (0..)
+        .map(|i| {
+            let name = if i == 0 {
+                format!("{}.wav", stem)
+            } else {
+                format!("{}-{}.wav", stem, i)
+            };
+            mp3_path.with_file_name(name)
+        })
+        .find(|path| !path.exists())
+        .unwrap()
+        .to_string_lossy()
+        .into_owned();
+    // Renaming fails across filesystems, so fall back to copying.
+    if std::fs::rename(wav_filename, &kept_filename).is_err() {
+        std::fs::copy(wav_filename, &kept_filename).unwrap();
+        std::fs::remove_file(wav_filename).unwrap();
+    }
+    kept_filename
+}
+
+#[cfg(feature = "cpal")]
+fn play_live<P: PlayerProgram>(program: P, host: Option<&str>, meter: bool) {
+    let stream = build_stream(program, host);
+    let result = if meter {
+        let result = stream.play_until_finished_with_meter(Duration::from_millis(100), |levels| {
+            print!("\r{}", format_meter(levels));
+            std::io::stdout().flush().unwrap();
+        });
+        println!();
+        result
     } else {
-        println!("Starting ffmpeg failed, you may need to install it.");
+        stream.play_until_finished()
+    };
+    if let Err(err) = result {
+        println!("Playback stopped early. {}", err);
+        std::process::exit(1);
     }
-    success
 }
 
-fn build_stream<P: PlayerProgram>(program: P) -> PlayerProxy {
-    let host = cpal::default_host();
+/// Formats the output level as a line of text, with a bar for the peak
+/// level that spans the bottom 60 dB.
+#[cfg(feature = "cpal")]
+fn format_meter(levels: Levels) -> String {
+    const WIDTH: usize = 40;
+    const FLOOR_DB: f32 = -60.0;
+    let peak_db = amplitude_to_db(levels.peak).max(FLOOR_DB);
+    let rms_db = amplitude_to_db(levels.rms).max(FLOOR_DB);
+    let filled = ((peak_db - FLOOR_DB) / -FLOOR_DB * WIDTH as f32) as usize;
+    format!(
+        "[{}{}] peak {:>5.1} dBFS, RMS {:>5.1} dBFS",
+        "#".repeat(filled),
+        " ".repeat(WIDTH - filled),
+        peak_db,
+        rms_db
+    )
+}
+
+#[cfg(not(feature = "cpal"))]
+fn play_live<P: PlayerProgram>(_program: P, _host: Option<&str>, _meter: bool) {
+    println!(
+        "This build has no audio output support, so an output file is required (use --output)."
+    );
+    std::process::exit(1);
+}
+
+/// Returns the available audio host with the given name, ignoring case,
+/// or an error saying which hosts there are.
+#[cfg(feature = "cpal")]
+fn find_host_id(name: &str, available: &[cpal::HostId]) -> Result<cpal::HostId, String> {
+    available
+        .iter()
+        .find(|id| id.name().eq_ignore_ascii_case(name))
+        .copied()
+        .ok_or_else(|| {
+            let names: Vec<&str> = available.iter().map(|id| id.name()).collect();
+            format!(
+                "Unknown audio host '{}'. Available hosts: {}.",
+                name,
+                names.join(", ")
+            )
+        })
+}
+
+#[cfg(feature = "cpal")]
+fn host_or_exit(name: Option<&str>) -> cpal::Host {
+    let Some(name) = name else {
+        return cpal::default_host();
+    };
+    let id = find_host_id(name, &cpal::available_hosts()).unwrap_or_else(|message| {
+        println!("{}", message);
+        std::process::exit(1);
+    });
+    cpal::host_from_id(id).unwrap_or_else(|err| {
+        println!("The {} audio host is unavailable: {}", id.name(), err);
+        std::process::exit(1);
+    })
+}
+
+#[cfg(feature = "cpal")]
+fn list_devices() {
+    let default_id = cpal::default_host().id();
+    for id in cpal::available_hosts() {
+        let default_marker = if id == default_id { " (default)" } else { "" };
+        println!("{}{}", id.name(), default_marker);
+        let devices = match cpal::host_from_id(id).map(|host| host.output_devices()) {
+            Ok(Ok(devices)) => devices,
+            Ok(Err(err)) => {
+                println!("  (couldn't list devices: {})", err);
+                continue;
+            }
+            Err(err) => {
+                println!("  (unavailable: {})", err);
+                continue;
+            }
+        };
+        for device in devices {
+            let name = device
+                .name()
+                .unwrap_or_else(|_| "(unknown device)".to_string());
+            println!("  {}", name);
+        }
+    }
+}
+
+#[cfg(not(feature = "cpal"))]
+fn list_devices() {
+    println!("This build has no audio output support, so there are no devices to list.");
+}
+
+#[cfg(feature = "cpal")]
+fn build_stream<P: PlayerProgram>(program: P, host: Option<&str>) -> PlayerProxy {
+    let host = host_or_exit(host);
     let device = host
         .default_output_device()
         .expect("no output device available");
@@ -152,8 +906,13 @@ fn build_stream<P: PlayerProgram>(program: P) -> PlayerProxy {
     }
 }
 
-async fn witch_program() {
-    let beats = BeatSettings::new(80, THREE_FOUR);
+const WITCH_BEATS: BeatSettings = BeatSettings::new(80, THREE_FOUR);
+
+const TUNA_BEATS: BeatSettings = BeatSettings::new(80, FOUR_FOUR);
+
+const CAPTAIN_SILVER_BEATS: BeatSettings = BeatSettings::new(120, FOUR_FOUR);
+
+async fn witch_program(beats: BeatSettings) {
     let mut left_hand = Instrument::new(beats, 16, Waveform::Square);
     let mut right_hand = Instrument::new(beats, 63, Waveform::Triangle);
 
@@ -176,23 +935,10 @@ async fn witch_program() {
 
     // Weird end beat thing
     right_hand.skip(Beat::Whole);
-    right_hand.play_note("C5", Beat::Quarter).await;
-
-    // Measure 2
-    right_hand.play_note("A4", Beat::Quarter).await;
-    right_hand.play_note("G4", Beat::Quarter).await;
-    right_hand.play_note("F4", Beat::Quarter).await;
-
-    // Measure 3
-    right_hand.play_note("A4", Beat::Half).await;
-    right_hand.play_note("F4", Beat::Quarter).await;
-
-    // Measure 4
-    right_hand.play_note("C5", Beat::DottedHalf).await;
+    right_hand.play_melody(WITCH_MELODY).await;
 }
 
-async fn tuna_program() {
-    let beats = BeatSettings::new(80, FOUR_FOUR);
+async fn tuna_program(beats: BeatSettings) {
     let left_hand = Instrument::new(beats, 16, Waveform::Square);
     let right_hand = Instrument::new(beats, 63, Waveform::Triangle);
 
@@ -205,13 +951,7 @@ async fn tuna_program() {
             left_hand.play_chord(&["Bb3", "C4"], Beat::Half).await;
         });
 
-        right_hand.play_note("C5", Beat::Quarter).await;
-        right_hand.play_note("A4", Beat::Quarter).await;
-        right_hand.play_note("A4", Beat::Half).await;
-
-        right_hand.play_note("Bb4", Beat::Quarter).await;
-        right_hand.play_note("G4", Beat::Quarter).await;
-        right_hand.play_note("G4", Beat::Half).await;
+        right_hand.play_melody(TUNA_FISH_TUNA_FISH).await;
     }
 
     async fn sing_a_tune_of_tuna_fish(mut right_hand: Instrument, mut left_hand: Instrument) {
@@ -223,14 +963,7 @@ async fn tuna_program() {
             left_hand.play_chord(&["A3", "C4"], Beat::Half).await;
         });
 
-        right_hand.play_note("F4", Beat::Quarter).await;
-        right_hand.play_note("G4", Beat::Quarter).await;
-        right_hand.play_note("A4", Beat::Quarter).await;
-        right_hand.play_note("Bb4", Beat::Quarter).await;
-
-        right_hand.play_note("C5", Beat::Quarter).await;
-        right_hand.play_note("C5", Beat::Quarter).await;
-        right_hand.play_note("C5", Beat::Half).await;
+        right_hand.play_melody(SING_A_TUNE_OF_TUNA_FISH).await;
     }
 
     async fn its_a_favrite_dish(mut right_hand: Instrument, mut left_hand: Instrument) {
@@ -241,12 +974,7 @@ async fn tuna_program() {
             left_hand.play_chord(&["F3", "A3"], Beat::Whole).await;
         });
 
-        right_hand.play_note("F4", Beat::Quarter).await;
-        right_hand.play_note("A4", Beat::Quarter).await;
-        right_hand.play_note("C5", Beat::Quarter).await;
-        right_hand.play_note("C5", Beat::Quarter).await;
-
-        right_hand.play_note("F4", Beat::Whole).await;
+        right_hand.play_melody(ITS_A_FAVRITE_DISH).await;
     }
 
     async fn evrybody_likes_it_so(mut right_hand: Instrument, mut left_hand: Instrument) {
@@ -257,12 +985,7 @@ async fn tuna_program() {
             }
         });
 
-        for _ in 0..5 {
-            right_hand.play_note("G4", Beat::Quarter).await;
-        }
-
-        right_hand.play_note("A4", Beat::Quarter).await;
-        right_hand.play_note("Bb4", Beat::Half).await;
+        right_hand.play_melody(EVRYBODY_LIKES_IT_SO).await;
     }
 
     async fn from_new_york_to_kokomo(mut right_hand: Instrument, mut left_hand: Instrument) {
@@ -296,43 +1019,24 @@ async fn tuna_program() {
     assert_eq!(left_hand.total_measures(), 16.0);
 }
 
-async fn captain_silver_program() {
-    let beats = BeatSettings::new(120, FOUR_FOUR);
-
+async fn captain_silver_program(beats: BeatSettings) {
     let right_hand = async move {
         let mut hand = Instrument::new(beats, 16, Waveform::Square);
 
         // Measures 1-4
-        for _ in 0..5 {
-            hand.play_note("E4", Beat::Half).await;
-        }
-        hand.play_note("E4", Beat::Quarter).await;
-        hand.play_note("F4", Beat::Quarter).await;
-        hand.play_note("G4", Beat::Whole).await;
+        hand.play_melody(CAPTAIN_SILVER_1).await;
 
         // Measures 5-8
-        hand.play_note("F4", Beat::Half).await;
-        hand.play_note("F4", Beat::Half).await;
-        hand.play_note("D4", Beat::Half).await;
-        hand.play_note("D4", Beat::Half).await;
-        hand.play_note("G4", Beat::Whole).await;
-        hand.play_note("F4", Beat::Whole).await;
+        hand.play_melody(CAPTAIN_SILVER_2).await;
 
         // Measures 9-12 (same as 1-4)
-        for _ in 0..5 {
-            hand.play_note("E4", Beat::Half).await;
-        }
-        hand.play_note("E4", Beat::Quarter).await;
-        hand.play_note("F4", Beat::Quarter).await;
-        hand.play_note("G4", Beat::Whole).await;
+        hand.play_melody(CAPTAIN_SILVER_1).await;
 
         // Measures 13-16
-        hand.play_note("F4", Beat::Half).await;
-        hand.play_note("F4", Beat::Half).await;
-        hand.play_note("D4", Beat::Half).await;
-        hand.play_note("D4", Beat::Half).await;
-        hand.play_note_without_release("C4", Beat::Whole).await;
-        hand.play_note("C4", Beat::Whole).await;
+        let (phrase, tie) = CAPTAIN_SILVER_3.split_at(CAPTAIN_SILVER_3.len() - 2);
+        hand.play_melody(phrase).await;
+        hand.play_note_without_release(tie[0].0, tie[0].1).await;
+        hand.play_note(tie[1].0, tie[1].1).await;
 
         assert_eq!(hand.total_measures(), 16.0);
     };
@@ -404,6 +1108,41 @@ async fn captain_silver_program() {
     Player::start_program(left_hand);
 }
 
+async fn melody_program(melody: Vec<(MidiNote, Beat)>, bpm: u64, waveform: Waveform) {
+    let mut instrument = Instrument::new(BeatSettings::new(bpm, FOUR_FOUR), 127, waveform);
+    instrument.play_melody(&melody).await;
+}
+
+async fn chord_program(notes: Vec<MidiNote>, ms: f64, waveform: Waveform) {
+    // Keep the notes quiet enough that they can't clip when added up.
+    let volume = (255 / notes.len()).min(127) as u8;
+    let _shapes: Vec<_> = notes
+        .iter()
+        .map(|note| {
+            Player::new_shape(AudioShape {
+                frequency: note.frequency(),
+                volume,
+                waveform,
+                ..Default::default()
+            })
+        })
+        .collect();
+    Player::wait(ms).await;
+}
+
+/// Parses the arguments of the chord command, which are either a root and
+/// a chord name or a list of notes.
+fn parse_chord_or_exit(args: &[String]) -> Vec<MidiNote> {
+    if let [root, name] = args {
+        if let Some(quality) = ChordQuality::from_name(name) {
+            return chord(parse_note_or_exit(Some(root)), quality);
+        }
+    }
+    args.iter()
+        .map(|note| parse_note_or_exit(Some(note)))
+        .collect()
+}
+
 async fn siren_program() {
     for _ in 0..5 {
         Player::wait(500.0).await;
@@ -419,82 +1158,811 @@ async fn siren_program() {
     }
 }
 
-async fn scale_program(tonic: MidiNote, scale: Scale, bpm: u64, octaves: bool, wave: Waveform) {
+/// How long each note of a scale lasts.
+#[derive(Copy, Clone)]
+enum ScaleTiming {
+    /// A quarter note at the given tempo.
+    Beats(BeatSettings),
+    /// A fixed number of milliseconds.
+    Millis(f64),
+}
+
+impl ScaleTiming {
+    fn note_millis(&self) -> f64 {
+        match self {
+            ScaleTiming::Beats(beat_settings) => beat_settings.duration_in_millis(Beat::Quarter),
+            ScaleTiming::Millis(ms) => *ms,
+        }
+    }
+
+    fn beat_settings(&self) -> Option<BeatSettings> {
+        match self {
+            ScaleTiming::Beats(beat_settings) => Some(*beat_settings),
+            ScaleTiming::Millis(_) => None,
+        }
+    }
+}
+
+/// How a scale sounds.
+#[derive(Copy, Clone)]
+struct ScaleVoice {
+    waveform: Waveform,
+    /// How far apart to detune the left and right channels, in cents.
+    detune: f64,
+    /// Whether to hold the last note longer and let it ring out.
+    ring: bool,
+}
+
+// How quickly the last note of a scale fades when it rings out.
+const SCALE_RING_TIME_CONSTANT_MS: f64 = 250.0;
+
+async fn scale_program(
+    tonic: MidiNote,
+    scale: Scale,
+    direction: Direction,
+    timing: ScaleTiming,
+    octaves: bool,
+    voice: ScaleVoice,
+) {
     if octaves {
-        Player::start_program(play_scale(tonic + OCTAVE, scale, bpm, wave));
+        // If the scale is too high to play an octave up, just play it once.
+        if let Some(upper_tonic) = tonic.checked_add(OCTAVE) {
+            let notes = scale_notes(upper_tonic, scale, direction);
+            Player::start_program(play_scale(notes, timing, voice));
+        }
+    }
+    play_scale(scale_notes(tonic, scale, direction), timing, voice).await;
+}
+
+/// Returns the notes of the scale built on `tonic`, in the order they're
+/// played in the given direction.
+fn scale_notes(tonic: MidiNote, scale: Scale, direction: Direction) -> Vec<MidiNote> {
+    let walk = |intervals: [Semitones; 7]| {
+        let mut notes = vec![tonic];
+        for semitones in intervals {
+            notes.push(*notes.last().unwrap() + semitones);
+        }
+        notes
+    };
+    let ascending = walk(scale.intervals());
+    let descending: Vec<MidiNote> = walk(scale.descending_intervals())
+        .into_iter()
+        .rev()
+        .collect();
+    match direction {
+        Direction::Up => ascending,
+        Direction::Down => descending,
+        Direction::UpDown => ascending
+            .into_iter()
+            .chain(descending.into_iter().skip(1))
+            .collect(),
     }
-    play_scale(tonic, scale, bpm, wave).await;
 }
 
-async fn play_scale(tonic: MidiNote, scale: Scale, bpm: u64, waveform: Waveform) {
-    let beat_settings = BeatSettings::new(bpm, FOUR_FOUR);
-    let mut note: MidiNote = tonic;
-    let mut shape = Player::new_shape(AudioShape {
-        frequency: note.frequency(),
+/// Returns the given frequency shifted by the given number of cents.
+fn detuned(frequency: f64, cents: f64) -> f64 {
+    frequency * 2.0f64.powf(cents / 1200.0)
+}
+
+async fn play_scale(notes: Vec<MidiNote>, timing: ScaleTiming, voice: ScaleVoice) {
+    let detune = voice.detune;
+    let shape = AudioShape {
+        frequency: notes[0].frequency(),
         volume: 127,
-        waveform,
+        waveform: voice.waveform,
         ..Default::default()
+    };
+    let mut shape = Player::new_stereo_shape(StereoAudioShape {
+        left: AudioShape {
+            frequency: detuned(shape.frequency, -detune / 2.0),
+            ..shape
+        },
+        right: AudioShape {
+            frequency: detuned(shape.frequency, detune / 2.0),
+            ..shape
+        },
     });
 
-    let base_scale = match scale {
-        Scale::Major => MAJOR_SCALE,
-        Scale::MinorHarmonic => MINOR_HARMONIC_SCALE,
-    };
+    let ms_per_note = timing.note_millis();
 
-    let ms_per_quarter_note = beat_settings.duration_in_millis(Beat::Quarter);
+    for note in &notes[1..] {
+        Player::wait(ms_per_note).await;
+        shape.set_frequencies(
+            detuned(note.frequency(), -detune / 2.0),
+            detuned(note.frequency(), detune / 2.0),
+        );
+    }
 
-    for semitones in base_scale
-        .iter()
-        .copied()
-        .chain(base_scale.iter().rev().map(|s| -s))
-    {
-        Player::wait(ms_per_quarter_note).await;
-        note += semitones;
-        shape.set_frequency(note.frequency());
+    Player::wait(ms_per_note).await;
+
+    if voice.ring {
+        Player::wait(ms_per_note).await;
+        // The shape fades out this way once it's dropped.
+        shape.set_release(Release::Exponential {
+            time_constant_ms: SCALE_RING_TIME_CONSTANT_MS,
+        });
     }
+}
 
-    Player::wait(ms_per_quarter_note).await;
+fn require_beat(beat_settings: Option<BeatSettings>, purpose: &str) -> BeatSettings {
+    beat_settings.unwrap_or_else(|| {
+        println!("This command has no beat to {}!", purpose);
+        std::process::exit(1);
+    })
+}
+
+fn parse_gate_pattern_or_exit(pattern: &str) -> Vec<bool> {
+    let steps: Option<Vec<bool>> = pattern
+        .chars()
+        .map(|step| match step {
+            'x' => Some(true),
+            '.' => Some(false),
+            _ => None,
+        })
+        .collect();
+    match steps {
+        Some(steps) if !steps.is_empty() => steps,
+        _ => {
+            println!("Unable to parse gate pattern '{}'!", pattern);
+            std::process::exit(1);
+        }
+    }
+}
+
+fn parse_note_or_exit(note: Option<&str>) -> MidiNote {
+    if let Some(note_str) = note {
+        if let Ok(note) = MidiNote::parse(&note_str) {
+            note
+        } else {
+            println!("Unable to parse note '{}'!", note_str);
+            std::process::exit(1);
+        }
+    } else {
+        "C4".try_into().unwrap()
+    }
+}
+
+/// Returns whether the given key is just a note name, e.g. D or Bb, without
+/// an octave.
+fn is_pitch_class(key: &str) -> bool {
+    match key.as_bytes() {
+        [letter] | [letter, b'#' | b'b'] => (b'A'..=b'G').contains(letter),
+        _ => false,
+    }
+}
+
+fn parse_key_or_exit(key: Option<&str>) -> MidiNote {
+    match key {
+        Some(key) if !is_pitch_class(key) => {
+            println!(
+                "Unable to parse key '{}'! --key takes a note name without an octave, e.g. D or Bb.",
+                key
+            );
+            std::process::exit(1);
+        }
+        // Only the tonic's note name matters, so any octave will do.
+        Some(key) => parse_note_or_exit(Some(&format!("{}4", key))),
+        None => parse_note_or_exit(None),
+    }
 }
 
 fn main() {
     let cli = Args::parse();
-    match &cli.command {
+    if cli.list_devices {
+        list_devices();
+        return;
+    }
+    let Some(command) = &cli.command else {
+        Args::command()
+            .error(
+                clap::ErrorKind::MissingSubcommand,
+                "A subcommand is required, unless --list-devices is given.",
+            )
+            .exit();
+    };
+    let mut rng = cli.new_rng();
+    match command {
         Commands::CaptainSilver {} => {
-            cli.run_program(captain_silver_program());
+            let beats = cli.beats(CAPTAIN_SILVER_BEATS);
+            cli.run_program(&mut rng, Some(beats), || captain_silver_program(beats));
         }
         Commands::Tuna {} => {
-            cli.run_program(tuna_program());
+            let beats = cli.beats(TUNA_BEATS);
+            cli.run_program(&mut rng, Some(beats), || tuna_program(beats));
         }
         Commands::Witch {} => {
-            cli.run_program(witch_program());
+            let beats = cli.beats(WITCH_BEATS);
+            cli.run_program(&mut rng, Some(beats), || witch_program(beats));
+        }
+        Commands::Generate {
+            note,
+            scale,
+            length,
+            markov,
+            wave,
+            bpm,
+        } => {
+            let length = length.unwrap_or(32);
+            let melody = if let Some(song) = markov {
+                MarkovModel::train(&song.melody()).generate(&mut rng, length)
+            } else {
+                random_walk_melody(
+                    &mut rng,
+                    parse_note_or_exit(note.as_deref()),
+                    &scale.unwrap_or(Scale::Major).intervals(),
+                    length,
+                    &DEFAULT_STEP_WEIGHTS,
+                )
+                .into_iter()
+                .map(|note| (note, Beat::Quarter))
+                .collect()
+            };
+            let beats = cli.beats(BeatSettings::new(bpm.unwrap_or(120), FOUR_FOUR));
+            let wave = wave.unwrap_or(Wave::Sine).into();
+            cli.run_program(&mut rng, Some(beats), || {
+                melody_program(melody.clone(), beats.bpm, wave)
+            })
+        }
+        Commands::Notes {
+            notes,
+            beat,
+            wave,
+            bpm,
+            scale_lock,
+            key,
+        } => {
+            let length = beat.unwrap_or(NoteLength::Quarter).into();
+            let tonic = parse_key_or_exit(key.as_deref());
+            let melody = notes
+                .split_whitespace()
+                .map(|note| {
+                    let mut note = parse_note_or_exit(Some(note));
+                    if let Some(scale) = scale_lock {
+                        note = snap_to_scale(note, tonic, &scale.intervals());
+                    }
+                    (note, length)
+                })
+                .collect::<Vec<_>>();
+            let beats = cli.beats(BeatSettings::new(bpm.unwrap_or(120), FOUR_FOUR));
+            let wave = wave.unwrap_or(Wave::Sine).into();
+            cli.run_program(&mut rng, Some(beats), || {
+                melody_program(melody.clone(), beats.bpm, wave)
+            })
+        }
+        Commands::Chord {
+            notes,
+            duration,
+            wave,
+        } => {
+            let notes = parse_chord_or_exit(notes);
+            let ms = duration.unwrap_or(2.0) * 1000.0;
+            let wave = wave.unwrap_or(Wave::Sine).into();
+            cli.run_program(&mut rng, None, || chord_program(notes.clone(), ms, wave));
         }
         Commands::Siren {} => {
-            cli.run_program(siren_program());
+            cli.run_program(&mut rng, None, siren_program);
+        }
+        Commands::Completions { shell } => {
+            let mut command = Args::command();
+            let name = command.get_name().to_string();
+            clap_complete::generate(*shell, &mut command, name, &mut std::io::stdout());
         }
         Commands::Scale {
             note,
             scale,
             wave,
             bpm,
+            note_duration,
+            direction,
             octaves,
+            detune,
+            ring,
         } => {
-            let tonic: MidiNote = if let Some(note_str) = note {
-                if let Ok(note) = MidiNote::parse(note_str) {
-                    note
-                } else {
-                    println!("Unable to parse note '{}'!", note_str);
+            let tonic = parse_note_or_exit(note.as_deref());
+            let octaves_up = if *octaves { 2 } else { 1 };
+            if (0..octaves_up)
+                .try_fold(tonic, |note, _| note.checked_add(OCTAVE))
+                .is_none()
+            {
+                println!("That scale would go higher than G9, the highest MIDI note.");
+                std::process::exit(1);
+            }
+            if let Some(seconds) = note_duration {
+                if !(seconds.is_finite() && *seconds > 0.0) {
+                    println!(
+                        "The note duration must be more than 0 seconds, not {}.",
+                        seconds
+                    );
                     std::process::exit(1);
                 }
-            } else {
-                "C4".try_into().unwrap()
+            }
+            let timing = match note_duration {
+                Some(seconds) => ScaleTiming::Millis(seconds * 1000.0),
+                None => {
+                    ScaleTiming::Beats(cli.beats(BeatSettings::new(bpm.unwrap_or(60), FOUR_FOUR)))
+                }
             };
-            cli.run_program(scale_program(
-                tonic,
-                scale.unwrap_or(Scale::Major),
-                bpm.unwrap_or(60),
-                *octaves,
-                wave.unwrap_or(Wave::Sine).into(),
-            ))
+            cli.run_program(&mut rng, timing.beat_settings(), || {
+                scale_program(
+                    tonic,
+                    scale.unwrap_or(Scale::Major),
+                    direction.unwrap_or(Direction::UpDown),
+                    timing,
+                    *octaves,
+                    ScaleVoice {
+                        waveform: wave.unwrap_or(Wave::Sine).into(),
+                        detune: detune.unwrap_or(0.0),
+                        ring: *ring,
+                    },
+                )
+            })
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        find_in_path, format_events, is_pitch_class, keep_wav_instead_of_mp3, scale_notes,
+        scale_program, witch_program, Args, Direction, EventFormat, Scale, ScaleTiming, ScaleVoice,
+        WITCH_BEATS,
+    };
+    use clap::Parser;
+    use rust_synth::{
+        note::MidiNote,
+        player::{Player, PlayerProgram, WAV_SAMPLE_RATE},
+        rng::Rng,
+        synth::{AudioShape, Waveform},
+    };
+
+    #[test]
+    fn test_tempo_scale_changes_duration() {
+        let duration = Player::duration_in_millis(witch_program(WITCH_BEATS));
+        let half_speed_duration =
+            Player::duration_in_millis(witch_program(WITCH_BEATS.with_tempo_scale(0.5)));
+        assert!(
+            (half_speed_duration - duration * 2.0).abs() < 1.0,
+            "{} is not twice {}",
+            half_speed_duration,
+            duration
+        );
+    }
+
+    fn scale_duration(ring: bool) -> f64 {
+        let c4 = MidiNote::parse(&"C4").unwrap();
+        let timing = ScaleTiming::Millis(250.0);
+        let voice = ScaleVoice {
+            waveform: Waveform::Sine,
+            detune: 0.0,
+            ring,
+        };
+        Player::duration_in_millis(scale_program(
+            c4,
+            Scale::Major,
+            Direction::UpDown,
+            timing,
+            false,
+            voice,
+        ))
+    }
+
+    #[test]
+    fn test_scale_rings_out_only_when_asked() {
+        let duration = scale_duration(false);
+        let ringing_duration = scale_duration(true);
+        // The last note is held for another 250 ms, then fades for a while.
+        assert!(ringing_duration > duration + 1000.0, "{}", ringing_duration);
+    }
+
+    #[test]
+    fn test_scale_note_duration_sets_length_of_each_note() {
+        // Eight notes up to the octave, then seven back down.
+        let expected = 15.0 * 250.0;
+        let duration = scale_duration(false);
+        assert!(
+            duration >= expected && duration < expected + 50.0,
+            "{}",
+            duration
+        );
+    }
+
+    fn scale_note_numbers(scale: Scale, direction: Direction) -> String {
+        let c4 = MidiNote::parse(&"C4").unwrap();
+        scale_notes(c4, scale, direction)
+            .iter()
+            .map(|note| note.number().to_string())
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+
+    #[test]
+    fn test_scale_directions_work() {
+        assert_eq!(
+            scale_note_numbers(Scale::Major, Direction::Up),
+            "60 62 64 65 67 69 71 72"
+        );
+        assert_eq!(
+            scale_note_numbers(Scale::Major, Direction::Down),
+            "72 71 69 67 65 64 62 60"
+        );
+        assert_eq!(
+            scale_note_numbers(Scale::Major, Direction::UpDown),
+            "60 62 64 65 67 69 71 72 71 69 67 65 64 62 60"
+        );
+    }
+
+    #[test]
+    fn test_melodic_minor_differs_going_down() {
+        assert_eq!(
+            scale_note_numbers(Scale::MinorMelodic, Direction::Up),
+            "60 62 63 65 67 69 71 72"
+        );
+        // The 7th and 6th are lowered (Bb and Ab) on the way down.
+        assert_eq!(
+            scale_note_numbers(Scale::MinorMelodic, Direction::Down),
+            "72 70 68 67 65 63 62 60"
+        );
+        assert_eq!(
+            scale_note_numbers(Scale::MinorMelodic, Direction::UpDown),
+            "60 62 63 65 67 69 71 72 70 68 67 65 63 62 60"
+        );
+    }
+
+    #[test]
+    fn test_format_events_works() {
+        use rust_synth::recording::{ScheduledEvent, VoiceAction};
+
+        let events = [
+            ScheduledEvent {
+                time_ms: 0.0,
+                voice: 1,
+                action: VoiceAction::Start,
+                frequency: 440.0,
+                volume: 63,
+            },
+            ScheduledEvent {
+                time_ms: 950.5,
+                voice: 1,
+                action: VoiceAction::Stop,
+                frequency: 440.0,
+                volume: 0,
+            },
+        ];
+        assert_eq!(
+            format_events(&events, EventFormat::Table),
+            concat!(
+                "   time_ms  voice  action     frequency  volume\n",
+                "       0.0      1  start         440.00      63\n",
+                "     950.5      1  stop          440.00       0\n",
+            )
+        );
+        assert_eq!(
+            format_events(&events, EventFormat::Json),
+            concat!(
+                "[\n",
+                "  {\"time_ms\": 0, \"voice\": 1, \"action\": \"start\", \"frequency\": 440, \"volume\": 63},\n",
+                "  {\"time_ms\": 950.5, \"voice\": 1, \"action\": \"stop\", \"frequency\": 440, \"volume\": 0}\n",
+                "]\n",
+            )
+        );
+    }
+
+    #[test]
+    fn test_oversample_is_validated() {
+        let args = Args::parse_from(["rust-synth", "--oversample", "4", "siren"]);
+        assert_eq!(args.oversample_factor(), 4);
+        let args = Args::parse_from(["rust-synth", "siren"]);
+        assert_eq!(args.oversample_factor(), 1);
+        assert!(Args::try_parse_from(["rust-synth", "--oversample", "0", "siren"]).is_err());
+    }
+
+    #[test]
+    fn test_key_requires_scale_lock() {
+        assert!(Args::try_parse_from(["rust-synth", "notes", "C4", "--key", "D"]).is_err());
+        assert!(Args::try_parse_from([
+            "rust-synth",
+            "notes",
+            "C4",
+            "--key",
+            "D",
+            "--scale-lock",
+            "major"
+        ])
+        .is_ok());
+    }
+
+    #[test]
+    fn test_key_is_a_note_name_without_an_octave() {
+        assert!(is_pitch_class("D"));
+        assert!(is_pitch_class("Bb"));
+        assert!(is_pitch_class("F#"));
+        assert!(!is_pitch_class("D4"));
+        assert!(!is_pitch_class("Bb4"));
+        assert!(!is_pitch_class("H"));
+        assert!(!is_pitch_class(""));
+    }
+
+    #[test]
+    fn test_markov_conflicts_with_scale() {
+        assert!(Args::try_parse_from(["rust-synth", "generate", "--markov", "tuna"]).is_ok());
+        assert!(
+            Args::try_parse_from(["rust-synth", "generate", "D4", "--markov", "tuna"]).is_err()
+        );
+        assert!(Args::try_parse_from([
+            "rust-synth",
+            "generate",
+            "D4",
+            "minor-harmonic",
+            "--markov",
+            "tuna"
+        ])
+        .is_err());
+    }
+
+    #[test]
+    fn test_dump_events_format_is_optional() {
+        let args = Args::parse_from(["rust-synth", "--dump-events", "siren"]);
+        assert_eq!(args.dump_events, Some(EventFormat::Table));
+        let args = Args::parse_from(["rust-synth", "siren", "--dump-events=json"]);
+        assert_eq!(args.dump_events, Some(EventFormat::Json));
+    }
+
+    #[test]
+    fn test_list_devices_does_not_need_a_subcommand() {
+        let args = Args::parse_from(["rust-synth", "--list-devices"]);
+        assert!(args.list_devices);
+        assert!(args.command.is_none());
+        let args = Args::parse_from(["rust-synth", "tuna", "--host", "jack"]);
+        assert_eq!(args.host.as_deref(), Some("jack"));
+    }
+
+    #[cfg(feature = "cpal")]
+    #[test]
+    fn test_find_host_id_ignores_case() {
+        use super::find_host_id;
+
+        let available = cpal::available_hosts();
+        let first = available[0];
+        let name = first.name().to_lowercase();
+        assert_eq!(find_host_id(&name, &available), Ok(first));
+        let err = find_host_id("no-such-host", &available).unwrap_err();
+        assert!(err.contains(first.name()), "{}", err);
+    }
+
+    #[cfg(feature = "cpal")]
+    #[test]
+    fn test_format_meter_works() {
+        use super::format_meter;
+        use rust_synth::player::Levels;
+
+        let silence = format_meter(Levels::default());
+        assert!(
+            silence.starts_with(&format!("[{}]", " ".repeat(40))),
+            "{}",
+            silence
+        );
+        assert!(
+            silence.ends_with("peak -60.0 dBFS, RMS -60.0 dBFS"),
+            "{}",
+            silence
+        );
+        let full_scale = format_meter(Levels {
+            peak: 1.0,
+            rms: 0.5,
+        });
+        assert!(
+            full_scale.starts_with(&format!("[{}]", "#".repeat(40))),
+            "{}",
+            full_scale
+        );
+        assert!(
+            full_scale.ends_with("peak   0.0 dBFS, RMS  -6.0 dBFS"),
+            "{}",
+            full_scale
+        );
+    }
+
+    /// Writes the program to a WAV file with the given command-line options
+    /// and returns the file's sample rate and its left channel.
+    fn write_wav_with_options<P: PlayerProgram>(options: &[&str], program: P) -> (u32, Vec<f32>) {
+        let args = Args::parse_from(["rust-synth"].iter().chain(options).chain(&["siren"]));
+        let filename = std::env::temp_dir().join(format!(
+            "rust-synth-options-test-{}-{}.wav",
+            std::process::id(),
+            options.join("")
+        ));
+        args.write_wav(&mut Rng::new(1), None, filename.to_str().unwrap(), program);
+        let mut reader = hound::WavReader::open(&filename).unwrap();
+        let sample_rate = reader.spec().sample_rate;
+        let samples: Vec<f32> = reader.samples().map(Result::unwrap).step_by(2).collect();
+        std::fs::remove_file(filename).unwrap();
+        (sample_rate, samples)
+    }
+
+    /// Returns the energy of the given samples that isn't at one of the
+    /// harmonics of `frequency`, in a tenth of a second starting a
+    /// twentieth of a second in.
+    fn inharmonic_energy(samples: &[f32], frequency: f64) -> f64 {
+        // A tenth of a second, so every DFT bin is 10 Hz wide.
+        const LEN: usize = WAV_SAMPLE_RATE as usize / 10;
+        let samples = &samples[LEN / 2..LEN / 2 + LEN];
+        (1..LEN / 2)
+            .filter(|bin| (*bin as f64 * 10.0) % frequency != 0.0)
+            .map(|bin| {
+                let (mut re, mut im) = (0.0, 0.0);
+                for (i, sample) in samples.iter().enumerate() {
+                    let angle = 2.0 * std::f64::consts::PI * (bin * i) as f64 / LEN as f64;
+                    re += *sample as f64 * angle.cos();
+                    im -= *sample as f64 * angle.sin();
+                }
+                re * re + im * im
+            })
+            .sum()
+    }
+
+    #[test]
+    fn test_oversample_reduces_aliasing_of_naive_waves() {
+        const FREQUENCY: f64 = 3030.0;
+        let program = || async {
+            let _shape = Player::new_shape(AudioShape {
+                waveform: Waveform::Sawtooth,
+                frequency: FREQUENCY,
+                volume: 127,
+                band_limited: false,
+                ..Default::default()
+            });
+            Player::wait(300.0).await;
+        };
+        let (plain_rate, plain) = write_wav_with_options(&["--oversample", "1"], program());
+        let (oversampled_rate, oversampled) =
+            write_wav_with_options(&["--oversample", "4"], program());
+        assert_eq!(plain_rate, WAV_SAMPLE_RATE);
+        assert_eq!(oversampled_rate, WAV_SAMPLE_RATE);
+        // Both last as long, except that the default release moves a step
+        // per sample, which takes less time at a higher sample rate.
+        assert!(
+            plain.len().abs_diff(oversampled.len()) <= u8::MAX as usize,
+            "{} vs {}",
+            plain.len(),
+            oversampled.len()
+        );
+        let plain_aliasing = inharmonic_energy(&plain, FREQUENCY);
+        let oversampled_aliasing = inharmonic_energy(&oversampled, FREQUENCY);
+        assert!(
+            oversampled_aliasing < plain_aliasing / 4.0,
+            "{} vs {}",
+            oversampled_aliasing,
+            plain_aliasing
+        );
+    }
+
+    #[test]
+    fn test_oversample_keeps_effect_timing() {
+        let program = || async {
+            let _shape = Player::new_shape(AudioShape {
+                waveform: Waveform::Sine,
+                frequency: 440.0,
+                volume: 255,
+                ..Default::default()
+            });
+            Player::wait(10.0).await;
+            drop(_shape);
+            Player::wait(300.0).await;
+        };
+        // Returns when the first echo of the beep starts, in milliseconds.
+        let first_echo_ms = |oversample: &str| {
+            let (_, samples) = write_wav_with_options(
+                &["--ping-pong", "150", "--oversample", oversample],
+                program(),
+            );
+            let after_beep = WAV_SAMPLE_RATE as usize / 20;
+            let start = samples[after_beep..]
+                .iter()
+                .position(|sample| sample.abs() > 0.05)
+                .unwrap();
+            (after_beep + start) as f64 * 1000.0 / WAV_SAMPLE_RATE as f64
+        };
+        let plain = first_echo_ms("1");
+        let oversampled = first_echo_ms("4");
+        assert!(
+            (plain - oversampled).abs() < 2.0,
+            "{} vs {}",
+            plain,
+            oversampled
+        );
+    }
+
+    #[test]
+    fn test_channels_2_duplicates_mono_source() {
+        let args = Args::parse_from(["rust-synth", "--channels", "2", "siren"]);
+        let filename = std::env::temp_dir().join(format!(
+            "rust-synth-channels-test-{}.wav",
+            std::process::id()
+        ));
+        args.write_wav(&mut Rng::new(1), None, filename.to_str().unwrap(), async {
+            let _shape = Player::new_shape(AudioShape {
+                waveform: Waveform::Sine,
+                frequency: 440.0,
+                volume: 127,
+                ..Default::default()
+            });
+            Player::wait(100.0).await;
+        });
+        let mut reader = hound::WavReader::open(&filename).unwrap();
+        assert_eq!(reader.spec().channels, 2);
+        let samples: Vec<f32> = reader.samples().map(Result::unwrap).collect();
+        std::fs::remove_file(filename).unwrap();
+        assert!(samples.iter().any(|&sample| sample != 0.0));
+        for frame in samples.chunks(2) {
+            assert_eq!(frame[0], frame[1]);
+        }
+    }
+
+    #[test]
+    fn test_channels_rejects_unsupported_counts() {
+        assert!(Args::try_parse_from(["rust-synth", "--channels", "0", "siren"]).is_err());
+        assert!(Args::try_parse_from(["rust-synth", "--channels", "6", "siren"]).is_err());
+        assert!(
+            Args::try_parse_from(["rust-synth", "--channels", "1", "--downmix-mono", "siren"])
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_keep_wav_instead_of_mp3_moves_temp_file_next_to_mp3() {
+        let dir = std::env::temp_dir().join(format!("rust-synth-keep-wav-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let temp_wav = dir.join("temp.wav");
+        std::fs::write(&temp_wav, b"audio").unwrap();
+        let mp3 = dir.join("song.mp3");
+
+        let kept = keep_wav_instead_of_mp3(temp_wav.to_str().unwrap(), mp3.to_str().unwrap());
+
+        assert_eq!(kept, dir.join("song.wav").to_str().unwrap());
+        assert!(!temp_wav.exists());
+        assert_eq!(std::fs::read(&kept).unwrap(), b"audio");
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_keep_wav_instead_of_mp3_does_not_overwrite() {
+        let dir = std::env::temp_dir().join(format!(
+            "rust-synth-keep-wav-existing-{}",
+            std::process::id()
+        ));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("song.wav"), b"existing").unwrap();
+        std::fs::write(dir.join("song-1.wav"), b"existing too").unwrap();
+        let temp_wav = dir.join("temp.wav");
+        std::fs::write(&temp_wav, b"audio").unwrap();
+        let mp3 = dir.join("song.mp3");
+
+        let kept = keep_wav_instead_of_mp3(temp_wav.to_str().unwrap(), mp3.to_str().unwrap());
+
+        assert_eq!(kept, dir.join("song-2.wav").to_str().unwrap());
+        assert_eq!(std::fs::read(&kept).unwrap(), b"audio");
+        assert_eq!(std::fs::read(dir.join("song.wav")).unwrap(), b"existing");
+        assert_eq!(
+            std::fs::read(dir.join("song-1.wav")).unwrap(),
+            b"existing too"
+        );
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_find_in_path() {
+        let dir = std::env::temp_dir().join(format!("rust-synth-path-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let program = dir.join(format!("fake-ffmpeg{}", std::env::consts::EXE_SUFFIX));
+        std::fs::write(&program, b"").unwrap();
+        let path = std::env::join_paths([std::env::temp_dir(), dir.clone()]).unwrap();
+
+        assert_eq!(
+            find_in_path("fake-ffmpeg", Some(path.clone())),
+            Some(program)
+        );
+        assert_eq!(find_in_path("missing-ffmpeg", Some(path)), None);
+        assert_eq!(find_in_path("fake-ffmpeg", None), None);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,71 @@
+use crate::{
+    beat::BeatSettings,
+    player::Player,
+    synth::{AudioShape, Waveform},
+};
+
+// How long each click lasts.
+const CLICK_MS: f64 = 20.0;
+
+const CLICK_VOLUME: u8 = 127;
+
+// The first beat of every measure is accented with a higher pitch.
+const ACCENT_FREQUENCY: f64 = 1760.0;
+const CLICK_FREQUENCY: f64 = 880.0;
+
+/// Plays a click on each of the given number of beats.
+pub async fn metronome(beat_settings: BeatSettings, beats: u64) {
+    let time_signature = beat_settings.time_signature;
+    let beat_ms = beat_settings.duration_in_millis(time_signature.beat_unit());
+    let start_time = Player::current_time();
+    let mut shape = Player::new_shape(AudioShape {
+        waveform: Waveform::Square,
+        frequency: CLICK_FREQUENCY,
+        volume: 0,
+        ..Default::default()
+    });
+
+    for beat in 0..beats {
+        let frequency = if beat % time_signature.beats_per_measure() == 0 {
+            ACCENT_FREQUENCY
+        } else {
+            CLICK_FREQUENCY
+        };
+        shape.set_frequency(frequency);
+        shape.set_volume(CLICK_VOLUME);
+        Player::wait(CLICK_MS).await;
+        shape.set_volume(0);
+
+        // Figure out when the next beat is relative to when we started, so
+        // that rounding errors don't accumulate and make us drift.
+        let next_beat_time = start_time + (beat + 1) as f64 * beat_ms;
+        Player::wait(next_beat_time - Player::current_time()).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::metronome;
+    use crate::{
+        analysis::peak,
+        beat::{BeatSettings, FOUR_FOUR},
+        player::{Player, WAV_SAMPLE_RATE},
+    };
+
+    #[test]
+    fn test_metronome_clicks_on_every_beat() {
+        let samples = Player::render_to_vec(metronome(BeatSettings::new(120, FOUR_FOUR), 4));
+        let samples_per_beat = WAV_SAMPLE_RATE as usize / 2;
+        let click_samples = WAV_SAMPLE_RATE as usize / 100;
+
+        assert!(samples.len() >= samples_per_beat * 4);
+        assert!(samples.len() < samples_per_beat * 4 + click_samples);
+        for beat in 0..4 {
+            let start = beat * samples_per_beat;
+            let click = &samples[start..start + click_samples];
+            let gap = &samples[start + click_samples * 3..start + samples_per_beat];
+            assert!(peak(click) > 0.4);
+            assert_eq!(peak(gap), 0.0);
+        }
+    }
+}
@@ -7,12 +7,20 @@ const A4_MIDI_NOTE: i8 = 69;
 const A4_FREQUENCY: f64 = 440.0;
 const SEMITONES_PER_OCTAVE: i8 = 12;
 
+// MIDI note numbers go from C-1 (0) up to G9 (127).
+const MIN_MIDI_NOTE: i32 = 0;
+const MAX_MIDI_NOTE: i32 = 127;
+
 pub const SEMITONE: Semitones = Semitones(1);
 pub const TONE: Semitones = Semitones(2);
 pub const OCTAVE: Semitones = Semitones(SEMITONES_PER_OCTAVE);
 pub const MAJOR_SCALE: [Semitones; 7] = [TONE, TONE, SEMITONE, TONE, TONE, TONE, SEMITONE];
 pub const MINOR_HARMONIC_SCALE: [Semitones; 7] =
     [TONE, SEMITONE, TONE, TONE, SEMITONE, Semitones(3), SEMITONE];
+pub const MINOR_NATURAL_SCALE: [Semitones; 7] = [TONE, SEMITONE, TONE, TONE, SEMITONE, TONE, TONE];
+/// The ascending form of the melodic minor scale, with a raised 6th and
+/// 7th. Traditionally, it's played as the natural minor scale descending.
+pub const MINOR_MELODIC_SCALE: [Semitones; 7] = [TONE, SEMITONE, TONE, TONE, TONE, TONE, SEMITONE];
 
 pub trait MidiNoteLike: TryInto<MidiNote> + Copy {
     fn into_midi_note_or_panic(self) -> MidiNote;
@@ -24,14 +32,54 @@ impl<T: TryInto<MidiNote, Error = E> + Copy, E: std::fmt::Debug> MidiNoteLike fo
     }
 }
 
-#[derive(Copy, Clone, PartialEq, Debug)]
+/// Notes are ordered by pitch, from lowest to highest. They can also be
+/// used as map keys, e.g. to keep track of which notes are sounding.
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Debug)]
 pub struct MidiNote(i8);
 
 impl MidiNote {
+    /// Returns the note with the given MIDI note number, which must be
+    /// from 0 (C-1) to 127 (G9).
+    pub fn new(value: i8) -> Result<MidiNote, MidiNoteError> {
+        MidiNote::from_number(value as i32).ok_or(MidiNoteError::OutOfRange)
+    }
+
+    fn from_number(value: i32) -> Option<MidiNote> {
+        if (MIN_MIDI_NOTE..=MAX_MIDI_NOTE).contains(&value) {
+            Some(MidiNote(value as i8))
+        } else {
+            None
+        }
+    }
+
+    /// Returns the note the given number of semitones up, or `None` if
+    /// that's past the highest MIDI note.
+    pub fn checked_add(self, semitones: Semitones) -> Option<MidiNote> {
+        MidiNote::from_number(self.0 as i32 + semitones.0 as i32)
+    }
+
+    /// Returns the note the given number of semitones down, or `None` if
+    /// that's past the lowest MIDI note.
+    pub fn checked_sub(self, semitones: Semitones) -> Option<MidiNote> {
+        MidiNote::from_number(self.0 as i32 - semitones.0 as i32)
+    }
+
+    /// Returns the note the given number of semitones away, stopping at
+    /// the highest or lowest MIDI note.
+    fn saturating_offset(self, semitones: i32) -> MidiNote {
+        let value = self.0 as i32 + semitones;
+        MidiNote(value.clamp(MIN_MIDI_NOTE, MAX_MIDI_NOTE) as i8)
+    }
+
+    /// Returns the MIDI note number, e.g. 60 for C4.
+    pub fn number(&self) -> i8 {
+        self.0
+    }
+
     pub fn frequency(&self) -> f64 {
         // Keep in mind that every MIDI note represents a semitone.
         let semitones_from_a4: f64 = self.0 as f64 - A4_MIDI_NOTE as f64;
-        return A4_FREQUENCY * 2.0f64.powf(semitones_from_a4 as f64 / SEMITONES_PER_OCTAVE as f64);
+        A4_FREQUENCY * 2.0f64.powf(semitones_from_a4 / SEMITONES_PER_OCTAVE as f64)
     }
 
     pub fn parse<T: AsRef<str>>(value: &T) -> Result<MidiNote, MidiNoteParseError> {
@@ -59,7 +107,7 @@ impl MidiNote {
             None => Semitones(0),
             _ => return Err(MidiNoteParseError::InvalidAccidentalCharacter),
         };
-        let octaves_from_4 = match octave {
+        let octaves_from_4: i32 = match octave {
             '0' => -4,
             '1' => -3,
             '2' => -2,
@@ -73,28 +121,125 @@ impl MidiNote {
             _ => return Err(MidiNoteParseError::InvalidOctaveCharacter),
         };
 
-        Ok(MidiNote(A4_MIDI_NOTE)
-            + note_semitones_from_a
-            + accidental_semitone_offset
-            + Semitones(octaves_from_4 * SEMITONES_PER_OCTAVE))
+        let semitones_from_a4 = note_semitones_from_a.0 as i32
+            + accidental_semitone_offset.0 as i32
+            + octaves_from_4 * SEMITONES_PER_OCTAVE as i32;
+        MidiNote::from_number(A4_MIDI_NOTE as i32 + semitones_from_a4)
+            .ok_or(MidiNoteParseError::OutOfRange)
+    }
+}
+
+/// Returns the note at the given degree of the scale built on `tonic`,
+/// where degree 0 is the tonic itself. Degrees past the end of the scale
+/// (or below zero) continue into the neighboring octaves, stopping at the
+/// highest or lowest MIDI note, like adding `Semitones` does.
+pub fn scale_degree(tonic: MidiNote, scale: &[Semitones], degree: i32) -> MidiNote {
+    let degrees_per_octave = scale.len() as i32;
+    let octave_semitones: i32 = scale.iter().map(|s| s.0 as i32).sum();
+    let octaves = degree.div_euclid(degrees_per_octave);
+    let index = degree.rem_euclid(degrees_per_octave) as usize;
+    let semitones: i32 = scale[..index].iter().map(|s| s.0 as i32).sum();
+    tonic.saturating_offset(octaves * octave_semitones + semitones)
+}
+
+/// Returns the note of the scale built on `tonic` that's nearest to the
+/// given note, in any octave. Notes exactly between two scale notes are
+/// snapped down.
+pub fn snap_to_scale(note: MidiNote, tonic: MidiNote, scale: &[Semitones]) -> MidiNote {
+    let offset = (note.0 as i32 - tonic.0 as i32).rem_euclid(SEMITONES_PER_OCTAVE as i32);
+    let mut scale_offset = 0;
+    let mut nearest = 0;
+    // The last step of the scale lands on the next octave's tonic, which
+    // is the nearest scale note to anything just below it.
+    for step in scale {
+        scale_offset += step.0 as i32;
+        if (scale_offset - offset).abs() < (nearest - offset).abs() {
+            nearest = scale_offset;
+        }
     }
+    note + Semitones((nearest - offset) as i8)
+}
+
+/// The kinds of chord that `chord` can build.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum ChordQuality {
+    Major,
+    Minor,
+    Diminished,
+    Augmented,
+    Suspended2,
+    Suspended4,
+    Dominant7,
+    Major7,
+    Minor7,
+}
+
+impl ChordQuality {
+    /// Looks up a chord quality by name, e.g. "major", "min7" or "sus4".
+    pub fn from_name(name: &str) -> Option<ChordQuality> {
+        match name.to_lowercase().as_str() {
+            "major" | "maj" => Some(ChordQuality::Major),
+            "minor" | "min" => Some(ChordQuality::Minor),
+            "diminished" | "dim" => Some(ChordQuality::Diminished),
+            "augmented" | "aug" => Some(ChordQuality::Augmented),
+            "sus2" => Some(ChordQuality::Suspended2),
+            "sus4" => Some(ChordQuality::Suspended4),
+            "7" | "dom7" => Some(ChordQuality::Dominant7),
+            "maj7" => Some(ChordQuality::Major7),
+            "min7" => Some(ChordQuality::Minor7),
+            _ => None,
+        }
+    }
+
+    /// Returns how far each note of the chord is above its root.
+    pub fn intervals(&self) -> &'static [i8] {
+        match self {
+            ChordQuality::Major => &[0, 4, 7],
+            ChordQuality::Minor => &[0, 3, 7],
+            ChordQuality::Diminished => &[0, 3, 6],
+            ChordQuality::Augmented => &[0, 4, 8],
+            ChordQuality::Suspended2 => &[0, 2, 7],
+            ChordQuality::Suspended4 => &[0, 5, 7],
+            ChordQuality::Dominant7 => &[0, 4, 7, 10],
+            ChordQuality::Major7 => &[0, 4, 7, 11],
+            ChordQuality::Minor7 => &[0, 3, 7, 10],
+        }
+    }
+}
+
+/// Returns the notes of the chord with the given root, in root position.
+pub fn chord(root: MidiNote, quality: ChordQuality) -> Vec<MidiNote> {
+    quality
+        .intervals()
+        .iter()
+        .map(|semitones| root + Semitones(*semitones))
+        .collect()
 }
 
 #[derive(Debug, PartialEq)]
+#[allow(clippy::enum_variant_names)]
 pub enum MidiNoteParseError {
     InvalidLength,
     InvalidNoteCharacter,
     InvalidAccidentalCharacter,
     InvalidOctaveCharacter,
+    /// The note is higher than G9, the highest MIDI note.
+    OutOfRange,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum MidiNoteError {
+    /// The number is outside of the range of MIDI notes, 0 to 127.
+    OutOfRange,
 }
 
 impl TryFrom<&str> for MidiNote {
     type Error = MidiNoteParseError;
 
     fn try_from(value: &str) -> Result<MidiNote, MidiNoteParseError> {
-        match &value.chars().collect::<Vec<char>>()[..] {
-            &[note, accidental, octave] => MidiNote::try_from_chars(note, Some(accidental), octave),
-            &[note, octave] => MidiNote::try_from_chars(note, None, octave),
+        match value.chars().collect::<Vec<char>>()[..] {
+            [note, accidental, octave] => MidiNote::try_from_chars(note, Some(accidental), octave),
+            [note, octave] => MidiNote::try_from_chars(note, None, octave),
             _ => Err(MidiNoteParseError::InvalidLength),
         }
     }
@@ -102,7 +247,12 @@ impl TryFrom<&str> for MidiNote {
 
 #[cfg(test)]
 mod tests {
-    use super::{MidiNote, MidiNoteParseError};
+    use std::collections::HashMap;
+
+    use super::{
+        chord, scale_degree, snap_to_scale, ChordQuality, MidiNote, MidiNoteError,
+        MidiNoteParseError, Semitones, MAJOR_SCALE, OCTAVE,
+    };
 
     #[test]
     fn test_a4_works() {
@@ -143,6 +293,45 @@ mod tests {
         value.try_into()
     }
 
+    #[test]
+    fn test_notes_past_g9_are_out_of_range() {
+        assert_eq!(try_parse("C0"), Ok(MidiNote(12)));
+        assert_eq!(try_parse("Cb0"), Ok(MidiNote(11)));
+        assert_eq!(try_parse("G9"), Ok(MidiNote(127)));
+        assert_eq!(try_parse("G#9"), Err(MidiNoteParseError::OutOfRange));
+        assert_eq!(try_parse("A9"), Err(MidiNoteParseError::OutOfRange));
+        assert_eq!(try_parse("B9"), Err(MidiNoteParseError::OutOfRange));
+    }
+
+    #[test]
+    fn test_new_checks_range() {
+        assert_eq!(MidiNote::new(0), Ok(MidiNote(0)));
+        assert_eq!(MidiNote::new(127), Ok(MidiNote(127)));
+        assert_eq!(MidiNote::new(-1), Err(MidiNoteError::OutOfRange));
+    }
+
+    #[test]
+    fn test_checked_arithmetic_stays_in_range() {
+        let g9 = try_parse("G9").unwrap();
+        let c4 = try_parse("C4").unwrap();
+        assert_eq!(c4.checked_add(OCTAVE), Some(try_parse("C5").unwrap()));
+        assert_eq!(g9.checked_add(Semitones(1)), None);
+        assert_eq!(c4.checked_sub(Semitones(60)), Some(MidiNote(0)));
+        assert_eq!(c4.checked_sub(Semitones(61)), None);
+    }
+
+    #[test]
+    fn test_operators_saturate() {
+        let g9 = try_parse("G9").unwrap();
+        assert_eq!(g9 + OCTAVE, g9);
+        assert_eq!(MidiNote(5) - OCTAVE, MidiNote(0));
+        let mut note = MidiNote(120);
+        note += OCTAVE;
+        assert_eq!(note, g9);
+        note -= Semitones(-128);
+        assert_eq!(note, g9);
+    }
+
     #[test]
     fn test_invalid_length_error() {
         assert_eq!(try_parse("A"), Err(MidiNoteParseError::InvalidLength));
@@ -172,11 +361,127 @@ mod tests {
             Err(MidiNoteParseError::InvalidOctaveCharacter)
         );
     }
+
+    #[test]
+    fn test_scale_degree_works() {
+        let c4: MidiNote = "C4".try_into().unwrap();
+        assert_eq!(scale_degree(c4, &MAJOR_SCALE, 0), c4);
+        assert_eq!(scale_degree(c4, &MAJOR_SCALE, 2), try_parse("E4").unwrap());
+        assert_eq!(scale_degree(c4, &MAJOR_SCALE, 6), try_parse("B4").unwrap());
+        assert_eq!(scale_degree(c4, &MAJOR_SCALE, 7), try_parse("C5").unwrap());
+        assert_eq!(scale_degree(c4, &MAJOR_SCALE, 9), try_parse("E5").unwrap());
+        assert_eq!(scale_degree(c4, &MAJOR_SCALE, -1), try_parse("B3").unwrap());
+        assert_eq!(scale_degree(c4, &MAJOR_SCALE, -7), try_parse("C3").unwrap());
+    }
+
+    #[test]
+    fn test_scale_degree_stops_at_range_limits() {
+        let c4: MidiNote = "C4".try_into().unwrap();
+        let g9 = try_parse("G9").unwrap();
+        assert_eq!(scale_degree(c4, &MAJOR_SCALE, 35), try_parse("C9").unwrap());
+        assert_eq!(scale_degree(c4, &MAJOR_SCALE, 39), g9);
+        assert_eq!(scale_degree(c4, &MAJOR_SCALE, 40), g9);
+        assert_eq!(scale_degree(c4, &MAJOR_SCALE, 1000), g9);
+        assert_eq!(scale_degree(c4, &MAJOR_SCALE, -35), MidiNote(0));
+        assert_eq!(scale_degree(c4, &MAJOR_SCALE, -1000), MidiNote(0));
+    }
+
+    #[test]
+    fn test_notes_are_ordered_by_pitch() {
+        assert!(try_parse("C4").unwrap() < try_parse("G4").unwrap());
+        assert!(try_parse("B3").unwrap() < try_parse("C4").unwrap());
+        let mut chord: Vec<MidiNote> = ["G4", "C4", "E4", "C3"]
+            .iter()
+            .map(|note| try_parse(note).unwrap())
+            .collect();
+        chord.sort();
+        let names = ["C3", "C4", "E4", "G4"];
+        let expected: Vec<MidiNote> = names.iter().map(|note| try_parse(note).unwrap()).collect();
+        assert_eq!(chord, expected);
+    }
+
+    #[test]
+    fn test_notes_work_as_map_keys() {
+        let mut counts: HashMap<MidiNote, usize> = HashMap::new();
+        for note in ["C4", "E4", "C4", "B#3"] {
+            *counts.entry(try_parse(note).unwrap()).or_default() += 1;
+        }
+        // B#3 is just another way of writing C4.
+        assert_eq!(counts.len(), 2);
+        assert_eq!(counts[&try_parse("C4").unwrap()], 3);
+    }
+
+    #[test]
+    fn test_chord_works() {
+        let notes = |names: &[&'static str]| -> Vec<MidiNote> {
+            names.iter().map(|name| try_parse(name).unwrap()).collect()
+        };
+        let c4 = try_parse("C4").unwrap();
+        assert_eq!(chord(c4, ChordQuality::Major), notes(&["C4", "E4", "G4"]));
+        assert_eq!(chord(c4, ChordQuality::Minor), notes(&["C4", "Eb4", "G4"]));
+        assert_eq!(
+            chord(c4, ChordQuality::Dominant7),
+            notes(&["C4", "E4", "G4", "Bb4"])
+        );
+        assert_eq!(ChordQuality::from_name("Maj7"), Some(ChordQuality::Major7));
+        assert_eq!(ChordQuality::from_name("C4"), None);
+    }
+
+    #[test]
+    fn test_snap_to_scale_works() {
+        let d3: MidiNote = "D3".try_into().unwrap();
+        let snap = |note| snap_to_scale(try_parse(note).unwrap(), d3, &MAJOR_SCALE);
+        // In D major, notes that are already in the scale stay put...
+        assert_eq!(snap("F#4"), try_parse("F#4").unwrap());
+        assert_eq!(snap("D5"), try_parse("D5").unwrap());
+        // ...and ones that aren't move to the nearest one, preferring down.
+        assert_eq!(snap("F4"), try_parse("E4").unwrap());
+        assert_eq!(snap("C4"), try_parse("B3").unwrap());
+        assert_eq!(snap("D#2"), try_parse("D2").unwrap());
+        // C#4 is in D major, a semitone below the tonic.
+        assert_eq!(snap("C#4"), try_parse("C#4").unwrap());
+    }
+
+    #[test]
+    fn test_semitones_display_works() {
+        assert_eq!(Semitones(7).value(), 7);
+        assert_eq!(Semitones(7).to_string(), "7 (P5)");
+        assert_eq!(Semitones(-3).to_string(), "-3 (m3)");
+        assert_eq!(OCTAVE.to_string(), "12 (P8)");
+        assert_eq!(Semitones(14).to_string(), "14");
+    }
 }
 
-#[derive(Copy, Clone)]
+#[derive(Copy, Clone, Debug, PartialEq)]
 pub struct Semitones(i8);
 
+// https://en.wikipedia.org/wiki/Interval_(music)#Main_intervals
+const INTERVAL_NAMES: [&str; 13] = [
+    "P1", "m2", "M2", "m3", "M3", "P4", "TT", "P5", "m6", "M6", "m7", "M7", "P8",
+];
+
+impl Semitones {
+    pub fn value(&self) -> i8 {
+        self.0
+    }
+
+    /// Returns the short name of the interval (e.g. "P5" for a perfect
+    /// fifth), regardless of its direction. Intervals larger than an
+    /// octave don't have a name.
+    pub fn interval_name(&self) -> Option<&'static str> {
+        INTERVAL_NAMES.get(self.0.unsigned_abs() as usize).copied()
+    }
+}
+
+impl std::fmt::Display for Semitones {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.interval_name() {
+            Some(name) => write!(f, "{} ({})", self.0, name),
+            None => write!(f, "{}", self.0),
+        }
+    }
+}
+
 impl ops::Neg for Semitones {
     type Output = Semitones;
 
@@ -196,29 +501,31 @@ impl ops::Neg for &Semitones {
 impl ops::Add<Semitones> for MidiNote {
     type Output = MidiNote;
 
+    /// Stops at the highest or lowest MIDI note; use `checked_add` to find
+    /// out if that happened.
     fn add(self, rhs: Semitones) -> MidiNote {
-        // TODO: We shouldn't let this go outside the range of MIDI notes.
-        MidiNote(self.0 + rhs.0)
+        self.saturating_offset(rhs.0 as i32)
     }
 }
 
 impl ops::AddAssign<Semitones> for MidiNote {
     fn add_assign(&mut self, rhs: Semitones) {
-        self.0 = self.0 + rhs.0;
+        *self = self.saturating_offset(rhs.0 as i32);
     }
 }
 
 impl ops::Sub<Semitones> for MidiNote {
     type Output = MidiNote;
 
+    /// Stops at the highest or lowest MIDI note; use `checked_sub` to find
+    /// out if that happened.
     fn sub(self, rhs: Semitones) -> MidiNote {
-        // TODO: We shouldn't let this go outside the range of MIDI notes.
-        MidiNote(self.0 - rhs.0)
+        self.saturating_offset(-(rhs.0 as i32))
     }
 }
 
 impl ops::SubAssign<Semitones> for MidiNote {
     fn sub_assign(&mut self, rhs: Semitones) {
-        self.0 = self.0 - rhs.0;
+        *self = self.saturating_offset(-(rhs.0 as i32));
     }
 }
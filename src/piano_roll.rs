@@ -0,0 +1,87 @@
+// Draws recorded notes as a piano roll: time runs from left to right,
+// pitch from bottom to top, and each note is a rectangle.
+
+use std::fmt::Write;
+
+use crate::recording::NoteEvent;
+
+const PIXELS_PER_SECOND: f64 = 100.0;
+
+const PIXELS_PER_SEMITONE: f64 = 8.0;
+
+// Blank space around the notes, in pixels.
+const MARGIN: f64 = 8.0;
+
+/// Returns an SVG image of the given notes. Each C is marked with a
+/// horizontal line to make it easier to tell which octave a note is in.
+pub fn piano_roll_svg(events: &[NoteEvent]) -> String {
+    let numbers = events.iter().map(|event| event.note.number() as i32);
+    let lowest = numbers.clone().min().unwrap_or(60);
+    let highest = numbers.max().unwrap_or(60);
+    let end_ms = events
+        .iter()
+        .fold(0.0, |end, event| f64::max(end, event.end_ms));
+
+    let x = |ms: f64| MARGIN + ms / 1000.0 * PIXELS_PER_SECOND;
+    let y = |number: i32| MARGIN + (highest - number) as f64 * PIXELS_PER_SEMITONE;
+    let width = x(end_ms) + MARGIN;
+    let height = y(lowest - 1) + MARGIN;
+
+    let mut svg = String::new();
+    writeln!(
+        svg,
+        r#"<svg xmlns="http://www.w3.org/2000/svg" width="{:.0}" height="{:.0}">"#,
+        width, height
+    )
+    .unwrap();
+    writeln!(svg, r#"<rect width="100%" height="100%" fill="white"/>"#).unwrap();
+    for number in (lowest..=highest).filter(|number| number % 12 == 0) {
+        writeln!(
+            svg,
+            r#"<line x1="0" y1="{:.1}" x2="{:.1}" y2="{:.1}" stroke="lightgray"/>"#,
+            y(number - 1),
+            width,
+            y(number - 1)
+        )
+        .unwrap();
+    }
+    for event in events {
+        writeln!(
+            svg,
+            r#"<rect x="{:.1}" y="{:.1}" width="{:.1}" height="{:.1}" fill="steelblue"/>"#,
+            x(event.start_ms),
+            y(event.note.number() as i32),
+            x(event.end_ms) - x(event.start_ms),
+            PIXELS_PER_SEMITONE
+        )
+        .unwrap();
+    }
+    writeln!(svg, "</svg>").unwrap();
+    svg
+}
+
+#[cfg(test)]
+mod tests {
+    use super::piano_roll_svg;
+    use crate::{note::MidiNote, recording::NoteEvent};
+
+    fn event(note: &str, start_ms: f64, end_ms: f64) -> NoteEvent {
+        NoteEvent {
+            note: MidiNote::parse(&note).unwrap(),
+            start_ms,
+            end_ms,
+        }
+    }
+
+    #[test]
+    fn test_piano_roll_svg_works() {
+        let svg = piano_roll_svg(&[event("C4", 0.0, 500.0), event("D4", 500.0, 1000.0)]);
+        assert!(svg.starts_with("<svg"));
+        assert!(svg.contains(r#"width="116" height="40""#));
+        // The higher note is drawn above the lower one.
+        assert!(svg.contains(r#"<rect x="8.0" y="24.0" width="50.0" height="8.0""#));
+        assert!(svg.contains(r#"<rect x="58.0" y="8.0" width="50.0" height="8.0""#));
+        // There's a line under C4.
+        assert!(svg.contains(r#"<line x1="0" y1="32.0""#));
+    }
+}
@@ -1,31 +1,185 @@
+#[cfg(feature = "cpal")]
 use cpal::traits::{DeviceTrait, StreamTrait};
+#[cfg(feature = "cpal")]
 use cpal::{Device, Sample, Stream, StreamConfig};
-use hound;
 use std::cell::{RefCell, RefMut};
+use std::collections::HashMap;
+use std::fmt::Display;
 use std::future::Future;
 use std::path::Path;
 use std::pin::Pin;
-use std::sync::mpsc::{sync_channel, Receiver, SyncSender};
+#[cfg(feature = "cpal")]
+use std::sync::atomic::AtomicU32;
+use std::sync::atomic::{AtomicUsize, Ordering};
+#[cfg(feature = "cpal")]
+use std::sync::mpsc::{sync_channel, RecvTimeoutError};
+use std::sync::mpsc::{Receiver, Sender, SyncSender, TryRecvError};
+use std::sync::Arc;
 use std::task::Context;
+#[cfg(feature = "cpal")]
 use std::thread::sleep;
-use std::time::Duration;
+use std::thread::LocalKey;
+use std::time::{Duration, Instant};
 
+use crate::analysis::{amplitude_to_db, PeakMeter};
 use crate::dummy_waker::dummy_waker;
-use crate::synth::{AudioShape, AudioShapeSynthesizer};
+use crate::recording::{record_event, ScheduledEvent, VoiceAction};
+use crate::rng::Rng;
+use crate::synth::{
+    AudioShape, AudioShapeSynthesizer, Release, StereoAudioShape, StereoAudioShapeSynthesizer,
+    VolumeMode,
+};
 use crate::synth_registry::SynthRegistry;
 use crate::waiter::Waiter;
 
 type PinnedPlayerProgram = Pin<Box<dyn Future<Output = ()> + Send>>;
 
-pub const WAV_CHANNELS: u16 = 1;
-
 pub const WAV_SAMPLE_RATE: u32 = 44100;
 
+/// The seed players use if `Player::set_seed` hasn't been called.
+pub const DEFAULT_SEED: u64 = 0;
+
+/// How often `write_wav` flushes the audio it's rendered so far to disk,
+/// in seconds of audio.
+pub const DEFAULT_WAV_FLUSH_INTERVAL: f64 = 10.0;
+
+/// Options for writing WAV files, which are stereo by default.
+#[derive(Copy, Clone)]
+pub struct WavOptions {
+    /// How often to flush the audio rendered so far to disk, in seconds of
+    /// audio. The file is valid after each flush, so if a long render dies
+    /// partway through, everything up to the last flush can be recovered.
+    pub flush_interval: f64,
+    /// Write a mono file, mixed down with `downmix`.
+    pub downmix_mono: bool,
+}
+
+impl Default for WavOptions {
+    fn default() -> Self {
+        WavOptions {
+            flush_interval: DEFAULT_WAV_FLUSH_INTERVAL,
+            downmix_mono: false,
+        }
+    }
+}
+
+impl WavOptions {
+    pub fn channels(&self) -> u16 {
+        if self.downmix_mono {
+            1
+        } else {
+            2
+        }
+    }
+}
+
+/// Mixes a stereo frame down to mono by averaging the channels. Mono
+/// synths are written at full level to both channels, so they keep their
+/// level.
+pub fn downmix(left: f64, right: f64) -> f64 {
+    (left + right) / 2.0
+}
+
+/// Mixes interleaved stereo samples (e.g. from `render_to_stereo_vec`) down
+/// to mono with `downmix`.
+pub fn downmix_to_mono(samples: &[f32]) -> Vec<f32> {
+    samples
+        .chunks(2)
+        .map(|frame| downmix(frame[0] as f64, frame[1] as f64) as f32)
+        .collect()
+}
+
 thread_local! {
-    static CURRENT_SAMPLE_RATE: RefCell<Option<usize>> = RefCell::new(None);
-    static CURRENT_TIME: RefCell<f64> = RefCell::new(0.0);
+    static CURRENT_SAMPLE_RATE: RefCell<Option<usize>> = const { RefCell::new(None) };
+    static CURRENT_TIME: RefCell<f64> = const { RefCell::new(0.0) };
+    static NEXT_WAKE_TIME: RefCell<Option<f64>> = const { RefCell::new(None) };
     static CURRENT_SYNTHS: RefCell<SynthRegistry> = RefCell::new(SynthRegistry::new());
     static NEW_PROGRAMS: RefCell<Vec<PinnedPlayerProgram>> = RefCell::new(vec![]);
+    static CURRENT_RNG: RefCell<Rng> = RefCell::new(Rng::new(DEFAULT_SEED));
+    static NEXT_PLAYER_SEED: RefCell<u64> = const { RefCell::new(DEFAULT_SEED) };
+    static NEXT_PLAYER_TIME_LIMIT: RefCell<Option<f64>> = const { RefCell::new(None) };
+}
+
+/// The state that a player's programs reach through `Player`'s associated
+/// functions (e.g. `Player::wait` and `Player::new_shape`). It's only in
+/// the thread-locals above while the player is running its programs; the
+/// rest of the time, the player keeps it. That way every player has its
+/// own, even when several take turns on one thread, or one player runs
+/// another (e.g. to find out how long a program is).
+struct PlayerContext {
+    sample_rate: Option<usize>,
+    time: f64,
+    next_wake_time: Option<f64>,
+    synths: SynthRegistry,
+    new_programs: Vec<PinnedPlayerProgram>,
+    rng: Rng,
+}
+
+impl PlayerContext {
+    fn new(sample_rate: usize, seed: u64) -> Self {
+        PlayerContext {
+            sample_rate: Some(sample_rate),
+            time: 0.0,
+            next_wake_time: None,
+            synths: SynthRegistry::new(),
+            new_programs: vec![],
+            rng: Rng::new(seed),
+        }
+    }
+
+    /// A context with nothing in it, to hold a player's place while its own
+    /// context is in the thread-locals.
+    fn empty() -> Self {
+        PlayerContext {
+            sample_rate: None,
+            time: 0.0,
+            next_wake_time: None,
+            synths: SynthRegistry::new(),
+            new_programs: vec![],
+            rng: Rng::new(DEFAULT_SEED),
+        }
+    }
+
+    /// Swaps this context with the one in the thread-locals.
+    fn swap_with_current(&mut self) {
+        fn swap<T: 'static>(key: &'static LocalKey<RefCell<T>>, value: &mut T) {
+            key.with(|current| std::mem::swap(&mut *current.borrow_mut(), value));
+        }
+        swap(&CURRENT_SAMPLE_RATE, &mut self.sample_rate);
+        swap(&CURRENT_TIME, &mut self.time);
+        swap(&NEXT_WAKE_TIME, &mut self.next_wake_time);
+        swap(&CURRENT_SYNTHS, &mut self.synths);
+        swap(&NEW_PROGRAMS, &mut self.new_programs);
+        swap(&CURRENT_RNG, &mut self.rng);
+    }
+}
+
+/// Keeps a player's context in the thread-locals until it's dropped, when
+/// whatever was there before is put back. Since that happens even if the
+/// player's programs panic, a panicking program can't leave its context
+/// behind for whatever runs on the thread next.
+struct ContextGuard(Option<PlayerContext>);
+
+impl ContextGuard {
+    fn enter(mut context: PlayerContext) -> Self {
+        context.swap_with_current();
+        ContextGuard(Some(context))
+    }
+
+    /// Takes the context back out of the thread-locals.
+    fn leave(mut self) -> PlayerContext {
+        let mut context = self.0.take().unwrap();
+        context.swap_with_current();
+        context
+    }
+}
+
+impl Drop for ContextGuard {
+    fn drop(&mut self) {
+        if let Some(context) = &mut self.0 {
+            context.swap_with_current();
+        }
+    }
 }
 
 pub trait PlayerProgram: Future<Output = ()> + Send + 'static {}
@@ -36,25 +190,173 @@ fn get_current_time() -> f64 {
     CURRENT_TIME.with(|value| *value.borrow())
 }
 
+/// Returns a seed for a new synth's noise, so that every synth plays
+/// different noise, but the same noise every time for a given seed.
+fn next_noise_seed() -> u64 {
+    CURRENT_RNG.with(|rng| rng.borrow_mut().next_u64())
+}
+
+fn schedule_wake(time: f64) {
+    NEXT_WAKE_TIME.with(|value| {
+        let mut next_wake_time = value.borrow_mut();
+        if next_wake_time.is_none_or(|next| time < next) {
+            *next_wake_time = Some(time);
+        }
+    });
+}
+
+/// Adds a change to the shape with the given id to the current recording
+/// of events, if we're making one.
+fn record_shape_event(id: usize, action: VoiceAction, shape: &AudioShape) {
+    record_event(ScheduledEvent {
+        time_ms: get_current_time(),
+        voice: id,
+        action,
+        frequency: shape.frequency,
+        volume: shape.volume,
+    });
+}
+
+/// Returns whichever channel of a stereo shape is louder, which stands for
+/// the whole shape in recordings of events.
+fn louder_channel(shape: &StereoAudioShape) -> &AudioShape {
+    if shape.right.volume > shape.left.volume {
+        &shape.right
+    } else {
+        &shape.left
+    }
+}
+
+/// The level of the audio most recently sent to the output device.
+#[cfg(feature = "cpal")]
+#[derive(Copy, Clone, Debug, Default)]
+pub struct Levels {
+    pub peak: f32,
+    pub rms: f32,
+}
+
+/// Lets the audio thread publish the level of each buffer it fills,
+/// without blocking, for other threads to display.
+#[cfg(feature = "cpal")]
+#[derive(Default)]
+struct LevelMeter {
+    peak: AtomicU32,
+    rms: AtomicU32,
+}
+
+#[cfg(feature = "cpal")]
+impl LevelMeter {
+    fn update<T: Sample>(&self, data: &[T]) {
+        let mut peak: f32 = 0.0;
+        let mut sum_of_squares = 0.0;
+        for sample in data {
+            let value = sample.to_f32();
+            peak = peak.max(value.abs());
+            sum_of_squares += value * value;
+        }
+        let rms = (sum_of_squares / data.len().max(1) as f32).sqrt();
+        self.peak.store(peak.to_bits(), Ordering::Relaxed);
+        self.rms.store(rms.to_bits(), Ordering::Relaxed);
+    }
+
+    fn levels(&self) -> Levels {
+        Levels {
+            peak: f32::from_bits(self.peak.load(Ordering::Relaxed)),
+            rms: f32::from_bits(self.rms.load(Ordering::Relaxed)),
+        }
+    }
+}
+
+/// Why live playback stopped before its program finished.
+#[derive(Debug, Clone, PartialEq)]
+pub enum PlaybackError {
+    /// The output device went away, e.g. because it was unplugged.
+    DeviceLost,
+    /// The output stream failed for some other reason, described by the
+    /// audio backend.
+    StreamFailed(String),
+}
+
+impl Display for PlaybackError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PlaybackError::DeviceLost => {
+                write!(
+                    f,
+                    "The audio device is no longer available (was it unplugged?)."
+                )
+            }
+            PlaybackError::StreamFailed(message) => {
+                write!(f, "The audio stream failed: {}", message)
+            }
+        }
+    }
+}
+
+impl std::error::Error for PlaybackError {}
+
+#[cfg(feature = "cpal")]
+impl From<cpal::StreamError> for PlaybackError {
+    fn from(err: cpal::StreamError) -> Self {
+        match err {
+            cpal::StreamError::DeviceNotAvailable => PlaybackError::DeviceLost,
+            err => PlaybackError::StreamFailed(err.to_string()),
+        }
+    }
+}
+
+/// What the audio thread tells the thread waiting on it once it's done:
+/// either the program finished, or the stream failed.
+type PlaybackResult = Result<(), PlaybackError>;
+
+#[cfg(feature = "cpal")]
 pub struct PlayerProxy {
     stream: Stream,
-    receiver: Receiver<()>,
+    receiver: Receiver<PlaybackResult>,
+    meter: Arc<LevelMeter>,
 }
 
+#[cfg(feature = "cpal")]
 impl PlayerProxy {
-    fn wait_until_finished(&mut self) {
-        self.receiver.recv().unwrap();
-        // The audio thread has finished generating audio, but it may still
-        // need to be played, so give a bit of time for that.
-        sleep(Duration::from_millis(250));
+    /// Plays the program until it finishes, or until the stream fails,
+    /// e.g. because the device was unplugged.
+    pub fn play_until_finished(self) -> Result<(), PlaybackError> {
+        self.play_until_finished_with_meter(Duration::MAX, |_| {})
     }
 
-    pub fn play_until_finished(mut self) {
+    /// Like `play_until_finished`, but calls `on_update` with the current
+    /// output level every `interval` while playing, e.g. to show a meter.
+    pub fn play_until_finished_with_meter<F: FnMut(Levels)>(
+        self,
+        interval: Duration,
+        mut on_update: F,
+    ) -> Result<(), PlaybackError> {
         self.stream.play().unwrap();
-        self.wait_until_finished();
+        wait_for_playback(&self.receiver, interval, || on_update(self.meter.levels()))
     }
 }
 
+/// Waits for the audio thread to say it's done, calling `on_update` every
+/// `interval` in the meantime.
+#[cfg(feature = "cpal")]
+fn wait_for_playback<F: FnMut()>(
+    receiver: &Receiver<PlaybackResult>,
+    interval: Duration,
+    mut on_update: F,
+) -> Result<(), PlaybackError> {
+    loop {
+        match receiver.recv_timeout(interval) {
+            Err(RecvTimeoutError::Timeout) => on_update(),
+            Ok(Err(err)) => return Err(err),
+            Ok(Ok(())) | Err(RecvTimeoutError::Disconnected) => break,
+        }
+    }
+    // The audio thread has finished generating audio, but it may still
+    // need to be played, so give a bit of time for that.
+    sleep(Duration::from_millis(250));
+    Ok(())
+}
+
 pub struct AudioShapeProxy {
     id: usize,
 }
@@ -64,19 +366,41 @@ impl AudioShapeProxy {
         let sample_rate = CURRENT_SAMPLE_RATE.with(|value| value.borrow().unwrap());
         let id = CURRENT_SYNTHS.with(|registry| {
             let mut mut_registry = registry.borrow_mut();
-            let synth = AudioShapeSynthesizer::new(shape, sample_rate);
-            return mut_registry.insert(synth);
+            let mut synth = AudioShapeSynthesizer::new(shape, sample_rate);
+            synth.set_noise_seed(next_noise_seed());
+            mut_registry.insert(synth)
         });
+        record_shape_event(id, VoiceAction::Start, &shape);
         AudioShapeProxy { id }
     }
 
+    /// Changes the frequency legato: the shape keeps sounding at its
+    /// current volume, so the change is heard as one continuous note.
     pub fn set_frequency(&mut self, frequency: f64) {
         CURRENT_SYNTHS.with(|registry| {
             registry.borrow_mut().modify(self.id, |synth| {
                 synth.update_target(AudioShape {
                     frequency,
                     ..synth.get_target()
-                })
+                });
+                record_shape_event(self.id, VoiceAction::Change, &synth.get_target());
+            });
+        })
+    }
+
+    /// Starts a new note at the given frequency, retriggering the attack:
+    /// the shape quickly fades to silence and ramps back up to its volume,
+    /// so the change is heard as a separate note. Use `set_frequency` to glide
+    /// legato instead.
+    pub fn note_on(&mut self, frequency: f64) {
+        CURRENT_SYNTHS.with(|registry| {
+            registry.borrow_mut().modify(self.id, |synth| {
+                synth.update_target(AudioShape {
+                    frequency,
+                    ..synth.get_target()
+                });
+                synth.retrigger();
+                record_shape_event(self.id, VoiceAction::Retrigger, &synth.get_target());
             });
         })
     }
@@ -87,10 +411,99 @@ impl AudioShapeProxy {
                 synth.update_target(AudioShape {
                     volume,
                     ..synth.get_target()
+                });
+                record_shape_event(self.id, VoiceAction::Change, &synth.get_target());
+            });
+        })
+    }
+
+    pub fn set_release(&mut self, release: Release) {
+        CURRENT_SYNTHS.with(|registry| {
+            registry
+                .borrow_mut()
+                .modify(self.id, |synth| synth.set_release(release));
+        })
+    }
+
+    pub fn set_volume_mode(&mut self, volume_mode: VolumeMode) {
+        CURRENT_SYNTHS.with(|registry| {
+            registry.borrow_mut().modify(self.id, |synth| {
+                synth.update_target(AudioShape {
+                    volume_mode,
+                    ..synth.get_target()
                 })
             });
         })
     }
+
+    pub fn set_volume_steps(&mut self, volume_steps: u16) {
+        CURRENT_SYNTHS.with(|registry| {
+            registry.borrow_mut().modify(self.id, |synth| {
+                synth.update_target(AudioShape {
+                    volume_steps,
+                    ..synth.get_target()
+                })
+            });
+        })
+    }
+
+    pub fn set_crossfade(&mut self, crossfade_ms: f64) {
+        CURRENT_SYNTHS.with(|registry| {
+            registry.borrow_mut().modify(self.id, |synth| {
+                synth.update_target(AudioShape {
+                    crossfade_ms,
+                    ..synth.get_target()
+                })
+            });
+        })
+    }
+
+    pub fn set_frequency_smoothing(&mut self, frequency_smoothing_ms: f64) {
+        CURRENT_SYNTHS.with(|registry| {
+            registry.borrow_mut().modify(self.id, |synth| {
+                synth.update_target(AudioShape {
+                    frequency_smoothing_ms,
+                    ..synth.get_target()
+                })
+            });
+        })
+    }
+
+    /// Holds the shape at its current sound, even after this proxy is
+    /// dropped, until `release_sustain` is called. This is useful for
+    /// drones and pads. So that a forgotten sustain can't keep the player
+    /// running forever, it's released automatically after `max_ms`.
+    pub fn sustain(&mut self, max_ms: f64) {
+        let sample_rate = CURRENT_SAMPLE_RATE.with(|value| value.borrow().unwrap());
+        let max_samples = (max_ms * sample_rate as f64 / 1000.0) as usize;
+        CURRENT_SYNTHS.with(|registry| {
+            registry
+                .borrow_mut()
+                .modify(self.id, |synth| synth.sustain(max_samples));
+        })
+    }
+
+    /// Stops holding the shape, before the `max_ms` given to `sustain` is
+    /// up. The shape keeps sounding while this proxy is around, and fades
+    /// out as usual once it's dropped. If `max_ms` has already passed, the
+    /// sustain was released then, and this does nothing.
+    pub fn release_sustain(&mut self) {
+        CURRENT_SYNTHS.with(|registry| {
+            registry
+                .borrow_mut()
+                .modify(self.id, |synth| synth.release_sustain());
+        })
+    }
+
+    /// Sets the fraction of full volume below which the shape is considered
+    /// silent once it's dropped (see `DEFAULT_SILENCE_THRESHOLD`).
+    pub fn set_silence_threshold(&mut self, threshold: f64) {
+        CURRENT_SYNTHS.with(|registry| {
+            registry
+                .borrow_mut()
+                .modify(self.id, |synth| synth.set_silence_threshold(threshold));
+        })
+    }
 }
 
 impl Clone for AudioShapeProxy {
@@ -107,64 +520,446 @@ impl Drop for AudioShapeProxy {
         CURRENT_SYNTHS.with(|registry| {
             registry.borrow_mut().modify(self.id, |synth| {
                 synth.make_inactive();
+                record_shape_event(self.id, VoiceAction::Stop, &synth.get_target());
+            });
+        })
+    }
+}
+
+pub struct StereoAudioShapeProxy {
+    id: usize,
+}
+
+impl StereoAudioShapeProxy {
+    fn new(shape: StereoAudioShape) -> Self {
+        let sample_rate = CURRENT_SAMPLE_RATE.with(|value| value.borrow().unwrap());
+        let id = CURRENT_SYNTHS.with(|registry| {
+            let mut mut_registry = registry.borrow_mut();
+            let mut synth = StereoAudioShapeSynthesizer::new(shape, sample_rate);
+            synth.set_noise_seed(next_noise_seed());
+            mut_registry.insert_stereo(synth)
+        });
+        record_shape_event(id, VoiceAction::Start, louder_channel(&shape));
+        StereoAudioShapeProxy { id }
+    }
+
+    fn update<F: FnOnce(StereoAudioShape) -> StereoAudioShape>(&mut self, f: F) {
+        CURRENT_SYNTHS.with(|registry| {
+            registry.borrow_mut().modify_stereo(self.id, |synth| {
+                let shape = f(synth.get_target());
+                synth.update_target(shape);
+                record_shape_event(self.id, VoiceAction::Change, louder_channel(&shape));
             });
         })
     }
+
+    pub fn set_release(&mut self, release: Release) {
+        CURRENT_SYNTHS.with(|registry| {
+            registry
+                .borrow_mut()
+                .modify_stereo(self.id, |synth| synth.set_release(release));
+        })
+    }
+
+    pub fn set_shape(&mut self, shape: StereoAudioShape) {
+        self.update(|_| shape);
+    }
+
+    pub fn set_frequencies(&mut self, left: f64, right: f64) {
+        self.update(|shape| StereoAudioShape {
+            left: AudioShape {
+                frequency: left,
+                ..shape.left
+            },
+            right: AudioShape {
+                frequency: right,
+                ..shape.right
+            },
+        });
+    }
+}
+
+impl Clone for StereoAudioShapeProxy {
+    fn clone(&self) -> Self {
+        let shape = CURRENT_SYNTHS
+            .with(|registry| registry.borrow_mut().get_stereo_shape(&self.id))
+            .unwrap();
+        Self::new(shape)
+    }
+}
+
+impl Drop for StereoAudioShapeProxy {
+    fn drop(&mut self) {
+        CURRENT_SYNTHS.with(|registry| {
+            registry.borrow_mut().modify_stereo(self.id, |synth| {
+                synth.make_inactive();
+                record_shape_event(
+                    self.id,
+                    VoiceAction::Stop,
+                    louder_channel(&synth.get_target()),
+                );
+            });
+        })
+    }
+}
+
+/// Identifies a shape created with `PlayerHandle::new_shape`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct ShapeId(usize);
+
+enum PlayerCommand {
+    NewShape(ShapeId, AudioShape),
+    SetFrequency(ShapeId, f64),
+    SetVolume(ShapeId, u8),
+    RemoveShape(ShapeId),
+}
+
+/// Controls a running player from any thread, e.g. a GUI or a network
+/// server. Commands are queued up and carried out by the audio thread
+/// before it generates its next batch of samples.
+///
+/// The player keeps running for as long as any clone of its handle is
+/// alive, even if it has no programs left. Once every clone is dropped,
+/// the shapes created through the handle are released.
+#[derive(Clone)]
+pub struct PlayerHandle {
+    sender: Sender<PlayerCommand>,
+    latest_id: Arc<AtomicUsize>,
+}
+
+impl PlayerHandle {
+    #[cfg(any(feature = "cpal", test))]
+    fn new() -> (Self, Receiver<PlayerCommand>) {
+        let (sender, receiver) = std::sync::mpsc::channel();
+        let handle = PlayerHandle {
+            sender,
+            latest_id: Arc::new(AtomicUsize::new(0)),
+        };
+        (handle, receiver)
+    }
+
+    fn send(&self, command: PlayerCommand) {
+        // If the player has gone away, there's nothing left to control.
+        let _ = self.sender.send(command);
+    }
+
+    pub fn new_shape(&self, shape: AudioShape) -> ShapeId {
+        let id = ShapeId(self.latest_id.fetch_add(1, Ordering::Relaxed));
+        self.send(PlayerCommand::NewShape(id, shape));
+        id
+    }
+
+    pub fn set_frequency(&self, id: ShapeId, frequency: f64) {
+        self.send(PlayerCommand::SetFrequency(id, frequency));
+    }
+
+    pub fn set_volume(&self, id: ShapeId, volume: u8) {
+        self.send(PlayerCommand::SetVolume(id, volume));
+    }
+
+    /// Releases the shape, which fades out like a dropped `AudioShapeProxy`.
+    pub fn remove_shape(&self, id: ShapeId) {
+        self.send(PlayerCommand::RemoveShape(id));
+    }
+}
+
+/// Describes how long it took to render some audio, and optionally how
+/// loud it was.
+pub struct RenderReport {
+    audio_seconds: f64,
+    elapsed: Duration,
+    levels: Option<PeakMeter>,
+}
+
+impl RenderReport {
+    pub fn new(num_frames: usize, elapsed: Duration) -> Self {
+        RenderReport {
+            audio_seconds: num_frames as f64 / WAV_SAMPLE_RATE as f64,
+            elapsed,
+            levels: None,
+        }
+    }
+
+    /// Includes the levels of the rendered audio in the report.
+    pub fn with_levels(self, levels: PeakMeter) -> Self {
+        RenderReport {
+            levels: Some(levels),
+            ..self
+        }
+    }
+
+    pub fn levels(&self) -> Option<&PeakMeter> {
+        self.levels.as_ref()
+    }
+
+    pub fn realtime_factor(&self) -> f64 {
+        self.audio_seconds / self.elapsed.as_secs_f64()
+    }
+}
+
+impl Display for RenderReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Rendered {:.1}s of audio in {:.2}s ({:.1}x realtime).",
+            self.audio_seconds,
+            self.elapsed.as_secs_f64(),
+            self.realtime_factor()
+        )?;
+        if let Some(levels) = &self.levels {
+            let peaks: Vec<String> = levels
+                .peaks()
+                .iter()
+                .map(|peak| format!("{:.1} dBFS", amplitude_to_db(*peak)))
+                .collect();
+            let label = if peaks.len() == 2 {
+                "Peak level (left, right)"
+            } else {
+                "Peak level"
+            };
+            write!(f, "\n{}: {}.", label, peaks.join(", "))?;
+            if levels.has_clipped() {
+                write!(
+                    f,
+                    "\nWarning: the audio clipped. Try turning it down, e.g. with --normalize-peak."
+                )?;
+            }
+        }
+        Ok(())
+    }
 }
 
 pub struct Player {
     num_channels: u16,
     sample_rate: usize,
     programs: Vec<PinnedPlayerProgram>,
+    context: PlayerContext,
+    max_samples: Option<usize>,
     total_samples: usize,
-    sender: Option<SyncSender<()>>,
+    sender: Option<SyncSender<PlaybackResult>>,
+    commands: Option<Receiver<PlayerCommand>>,
+    handle_shapes: HashMap<ShapeId, AudioShapeProxy>,
     is_finished: bool,
 }
 
 impl Player {
-    pub fn write_wav<F: AsRef<Path>, P: PlayerProgram>(filename: F, program: P) {
-        let spec = hound::WavSpec {
-            channels: WAV_CHANNELS,
-            sample_rate: WAV_SAMPLE_RATE,
-            bits_per_sample: 32,
-            sample_format: hound::SampleFormat::Float,
-        };
-        let mut writer = hound::WavWriter::create(filename, spec).unwrap();
-        let mut player = Player {
-            num_channels: spec.channels,
+    fn new<P: PlayerProgram>(
+        num_channels: u16,
+        sample_rate: usize,
+        program: P,
+        sender: Option<SyncSender<PlaybackResult>>,
+    ) -> Self {
+        Player {
+            num_channels,
             programs: vec![Box::pin(program)],
+            context: PlayerContext::new(
+                sample_rate,
+                NEXT_PLAYER_SEED.with(|value| *value.borrow()),
+            ),
+            max_samples: NEXT_PLAYER_TIME_LIMIT.with(|value| {
+                value
+                    .borrow()
+                    .map(|ms| (ms * sample_rate as f64 / 1000.0) as usize)
+            }),
             total_samples: 0,
-            sample_rate: spec.sample_rate as usize,
-            sender: None,
+            sample_rate,
+            sender,
+            commands: None,
+            handle_shapes: HashMap::new(),
             is_finished: false,
-        };
-        player.write_wav_audio(&mut writer);
+        }
+    }
+
+    fn new_offline<P: PlayerProgram>(num_channels: u16, program: P) -> Self {
+        Player::new(num_channels, WAV_SAMPLE_RATE as usize, program, None)
+    }
+
+    fn wav_spec(channels: u16) -> hound::WavSpec {
+        hound::WavSpec {
+            channels,
+            sample_rate: WAV_SAMPLE_RATE,
+            bits_per_sample: 32,
+            sample_format: hound::SampleFormat::Float,
+        }
+    }
+
+    pub fn write_wav<F: AsRef<Path>, P: PlayerProgram>(filename: F, program: P) -> RenderReport {
+        Player::write_wav_with_options(filename, program, WavOptions::default())
+    }
+
+    pub fn write_wav_with_options<F: AsRef<Path>, P: PlayerProgram>(
+        filename: F,
+        program: P,
+        options: WavOptions,
+    ) -> RenderReport {
+        Player::write_wav_with_progress(filename, program, options, |_frames| {})
+    }
+
+    /// Like `write_wav_with_options`, but calls `on_progress` with the
+    /// number of frames (samples per channel) written so far, about every
+    /// tenth of a second of audio and once more when the program finishes.
+    /// Together with `duration_in_millis`, this can be used to show how far
+    /// along a long render is.
+    pub fn write_wav_with_progress<F: AsRef<Path>, P: PlayerProgram, C: FnMut(usize)>(
+        filename: F,
+        program: P,
+        options: WavOptions,
+        mut on_progress: C,
+    ) -> RenderReport {
+        let channels = options.channels();
+        let mut writer = hound::WavWriter::create(filename, Player::wav_spec(channels)).unwrap();
+        let mut player = Player::new_offline(channels, program);
+        let start = Instant::now();
+        let levels = player.write_wav_audio(&mut writer, options, &mut on_progress);
+        on_progress(writer.duration() as usize);
+        let report =
+            RenderReport::new(writer.duration() as usize, start.elapsed()).with_levels(levels);
+        write_wav_silence(&mut writer);
         writer.finalize().unwrap();
+        report
+    }
+
+    /// Renders the program to mono samples at the WAV sample rate, without
+    /// the trailing silence that `write_wav` adds. The channels are
+    /// averaged together, so that mono synths keep their level.
+    pub fn render_to_vec<P: PlayerProgram>(program: P) -> Vec<f32> {
+        let mut samples = vec![];
+        let mut player = Player::new_offline(1, program);
+        player.render_audio(|value| samples.push(value as f32));
+        samples
     }
 
+    /// Renders the program to stereo samples at the WAV sample rate. The
+    /// samples are interleaved, starting with the left channel, so even
+    /// indices are left samples and odd indices are right samples.
+    pub fn render_to_stereo_vec<P: PlayerProgram>(program: P) -> Vec<f32> {
+        Player::render_to_stereo_vec_at(WAV_SAMPLE_RATE as usize, program)
+    }
+
+    /// Like `render_to_stereo_vec`, but at the given sample rate instead,
+    /// e.g. a multiple of the WAV sample rate to oversample at.
+    pub fn render_to_stereo_vec_at<P: PlayerProgram>(sample_rate: usize, program: P) -> Vec<f32> {
+        let mut samples = vec![];
+        let mut player = Player::new(2, sample_rate, program, None);
+        player.render_frames(|left, right| {
+            samples.push(left as f32);
+            samples.push(right as f32);
+        });
+        samples
+    }
+
+    /// Returns how long the program takes to finish, by running it without
+    /// keeping any of the audio it generates.
+    pub fn duration_in_millis<P: PlayerProgram>(program: P) -> f64 {
+        let mut num_samples = 0;
+        let mut player = Player::new_offline(1, program);
+        player.render_audio(|_value| num_samples += 1);
+        num_samples as f64 / WAV_SAMPLE_RATE as f64 * 1000.0
+    }
+
+    /// Writes interleaved samples with the given number of channels (e.g.
+    /// 2 for samples from `render_to_stereo_vec`) to a WAV file.
+    pub fn write_wav_samples<F: AsRef<Path>>(filename: F, samples: &[f32], channels: u16) {
+        let mut writer = hound::WavWriter::create(filename, Player::wav_spec(channels)).unwrap();
+        for sample in samples {
+            writer.write_sample(*sample).unwrap();
+        }
+        write_wav_silence(&mut writer);
+        writer.finalize().unwrap();
+    }
+
+    #[cfg(test)]
+    fn with_commands(mut self, commands: Receiver<PlayerCommand>) -> Self {
+        self.commands = Some(commands);
+        self
+    }
+
+    #[cfg(feature = "cpal")]
     pub fn get_stream<T: Sample, P: PlayerProgram>(
         device: Device,
         config: &StreamConfig,
         program: P,
+    ) -> PlayerProxy {
+        Player::get_stream_impl::<T, P>(device, config, program, None)
+    }
+
+    /// Like `get_stream`, but also returns a handle for controlling the
+    /// player from other threads.
+    #[cfg(feature = "cpal")]
+    pub fn get_stream_with_handle<T: Sample, P: PlayerProgram>(
+        device: Device,
+        config: &StreamConfig,
+        program: P,
+    ) -> (PlayerProxy, PlayerHandle) {
+        let (handle, commands) = PlayerHandle::new();
+        let proxy = Player::get_stream_impl::<T, P>(device, config, program, Some(commands));
+        (proxy, handle)
+    }
+
+    #[cfg(feature = "cpal")]
+    fn get_stream_impl<T: Sample, P: PlayerProgram>(
+        device: Device,
+        config: &StreamConfig,
+        program: P,
+        commands: Option<Receiver<PlayerCommand>>,
     ) -> PlayerProxy {
         let (sender, receiver) = sync_channel(1);
-        let mut player = Player {
-            num_channels: config.channels,
-            programs: vec![Box::pin(program)],
-            total_samples: 0,
-            sample_rate: config.sample_rate.0 as usize,
-            sender: Some(sender),
-            is_finished: false,
+        let error_sender = sender.clone();
+        let mut player = Player::new(
+            config.channels,
+            config.sample_rate.0 as usize,
+            program,
+            Some(sender),
+        );
+        player.commands = commands;
+        let meter = Arc::new(LevelMeter::default());
+        let audio_thread_meter = meter.clone();
+        // Errors can't be handled on the audio thread, so pass them on to
+        // whoever's waiting for playback to finish. If they've already been
+        // told why it stopped, there's nothing more to say.
+        let err_fn = move |err: cpal::StreamError| {
+            let _ = error_sender.try_send(Err(err.into()));
         };
-        let err_fn = |err| eprintln!("an error occurred on the output audio stream: {}", err);
         let stream = device
             .build_output_stream(
                 config,
-                move |data, cpal| player.write_audio::<T>(data, cpal),
+                move |data, cpal| {
+                    player.write_audio::<T>(data, cpal);
+                    audio_thread_meter.update(data);
+                },
                 err_fn,
             )
             .unwrap();
-        return PlayerProxy { stream, receiver };
+        PlayerProxy {
+            stream,
+            receiver,
+            meter,
+        }
+    }
+
+    /// Sets the seed for players created on this thread from now on. All
+    /// of a player's randomness comes from this seed, so a given program
+    /// always produces the same audio with the same seed.
+    pub fn set_seed(seed: u64) {
+        NEXT_PLAYER_SEED.with(|value| {
+            *value.borrow_mut() = seed;
+        });
+    }
+
+    /// Makes players created on this thread from now on stop after the
+    /// given number of milliseconds, even if their programs haven't
+    /// finished, or removes the limit if it's `None`.
+    pub fn set_time_limit(ms: Option<f64>) {
+        NEXT_PLAYER_TIME_LIMIT.with(|value| {
+            *value.borrow_mut() = ms;
+        });
+    }
+
+    /// Calls the given function with the running player's random number
+    /// generator, which programs should use for anything random, so that
+    /// it's controlled by the player's seed.
+    pub fn with_rng<T, F: FnOnce(&mut Rng) -> T>(f: F) -> T {
+        CURRENT_RNG.with(|rng| f(&mut rng.borrow_mut()))
     }
 
     pub fn current_time() -> f64 {
@@ -172,13 +967,17 @@ impl Player {
     }
 
     pub fn wait(ms: f64) -> impl Future<Output = ()> {
-        Waiter::new(ms, get_current_time)
+        Waiter::new(ms, get_current_time, schedule_wake)
     }
 
     pub fn new_shape(shape: AudioShape) -> AudioShapeProxy {
         AudioShapeProxy::new(shape)
     }
 
+    pub fn new_stereo_shape(shape: StereoAudioShape) -> StereoAudioShapeProxy {
+        StereoAudioShapeProxy::new(shape)
+    }
+
     pub fn start_program<P: Future<Output = ()> + Send + 'static>(program: P) {
         NEW_PROGRAMS.with(|programs| {
             programs.borrow_mut().push(Box::pin(program));
@@ -195,6 +994,9 @@ impl Player {
     }
 
     fn execute_programs(&mut self) {
+        NEXT_WAKE_TIME.with(|value| {
+            *value.borrow_mut() = None;
+        });
         let waker = dummy_waker();
         let mut context = Context::from_waker(&waker);
         let mut i = 0;
@@ -202,7 +1004,7 @@ impl Player {
             let program = self.programs.get_mut(i).unwrap();
             match program.as_mut().poll(&mut context) {
                 std::task::Poll::Ready(_) => {
-                    self.programs.remove(i);
+                    drop(self.programs.remove(i));
                 }
                 std::task::Poll::Pending => {
                     i += 1;
@@ -212,13 +1014,14 @@ impl Player {
         }
     }
 
-    fn init_thread_locals(&mut self) {
-        CURRENT_SAMPLE_RATE.with(|value| {
-            *value.borrow_mut() = Some(self.sample_rate);
-        });
-        CURRENT_TIME.with(|value| {
-            *value.borrow_mut() = 0.0;
-        });
+    /// Calls the given function with the player's context in the
+    /// thread-locals, where its programs can reach it.
+    fn with_context<T, F: FnOnce(&mut Player) -> T>(&mut self, f: F) -> T {
+        let context = std::mem::replace(&mut self.context, PlayerContext::empty());
+        let guard = ContextGuard::enter(context);
+        let result = f(self);
+        self.context = guard.leave();
+        result
     }
 
     fn increment_total_samples(&mut self, amount: usize) {
@@ -228,12 +1031,54 @@ impl Player {
         });
     }
 
-    fn check_finished(&mut self, mut_registry: &mut RefMut<SynthRegistry>) {
+    /// Carries out any commands sent through the player's handle.
+    fn process_commands(&mut self) {
+        let Some(commands) = &self.commands else {
+            return;
+        };
+        let mut received = vec![];
+        let is_disconnected = loop {
+            match commands.try_recv() {
+                Ok(command) => received.push(command),
+                Err(TryRecvError::Empty) => break false,
+                Err(TryRecvError::Disconnected) => break true,
+            }
+        };
+        for command in received {
+            match command {
+                PlayerCommand::NewShape(id, shape) => {
+                    self.handle_shapes.insert(id, AudioShapeProxy::new(shape));
+                }
+                PlayerCommand::SetFrequency(id, frequency) => {
+                    if let Some(shape) = self.handle_shapes.get_mut(&id) {
+                        shape.set_frequency(frequency);
+                    }
+                }
+                PlayerCommand::SetVolume(id, volume) => {
+                    if let Some(shape) = self.handle_shapes.get_mut(&id) {
+                        shape.set_volume(volume);
+                    }
+                }
+                PlayerCommand::RemoveShape(id) => {
+                    self.handle_shapes.remove(&id);
+                }
+            }
+        }
+        if is_disconnected {
+            self.commands = None;
+            self.handle_shapes.clear();
+        }
+    }
+
+    fn check_finished(&mut self, mut_registry: &mut RefMut<SynthRegistry>, num_samples: usize) {
         mut_registry.remove_finished_synths();
 
-        if mut_registry.is_empty() && self.programs.len() == 0 && !self.is_finished {
+        let is_out_of_time = self.is_out_of_time(num_samples);
+        let is_done =
+            mut_registry.is_empty() && self.programs.is_empty() && self.commands.is_none();
+        if (is_done || is_out_of_time) && !self.is_finished {
             if let Some(sender) = &self.sender {
-                if let Ok(_) = sender.send(()) {
+                if sender.send(Ok(())).is_ok() {
                     self.is_finished = true;
                 }
             } else {
@@ -242,70 +1087,747 @@ impl Player {
         }
     }
 
-    fn generate_samples<F: FnOnce(&mut RefMut<SynthRegistry>)>(&mut self, f: F) {
+    /// Returns whether the time limit, if there is one, will have been
+    /// reached once the given number of samples are added to the total.
+    fn is_out_of_time(&self, num_samples: usize) -> bool {
+        self.max_samples
+            .is_some_and(|max| self.total_samples + num_samples >= max)
+    }
+
+    /// Runs all programs, then calls the given function with the registry
+    /// and the number of samples it should generate before we run the
+    /// programs again.
+    fn generate_samples<F: FnOnce(&mut RefMut<SynthRegistry>, usize)>(&mut self, f: F) {
+        self.process_commands();
         self.execute_programs();
+        let batch_size = self.batch_size();
         let mut num_samples = 0;
 
         CURRENT_SYNTHS.with(|registry| {
             let mut mut_registry = registry.borrow_mut();
             let start_samples = mut_registry.get_total_samples();
 
-            f(&mut mut_registry);
+            f(&mut mut_registry, batch_size);
 
             num_samples = mut_registry.get_total_samples() - start_samples;
-            self.check_finished(&mut mut_registry);
+            self.check_finished(&mut mut_registry, num_samples);
         });
 
         self.increment_total_samples(num_samples);
     }
 
-    fn samples_per_program_loop(&self) -> usize {
-        let samples_per_ms = self.sample_rate / 1000;
-        samples_per_ms / 2
+    /// Returns how many samples to generate before running the programs
+    /// again. Nothing can change until a program wakes up, so if we know
+    /// when that will be, we can generate everything up to it in one go.
+    /// Otherwise (e.g. if a program is waiting on something other than
+    /// `Player::wait`, or is just letting its synths finish), we fall back
+    /// to running the programs very frequently.
+    fn batch_size(&self) -> usize {
+        let min_batch_size = self.sample_rate / 2000;
+        let max_batch_size = self.sample_rate / 20;
+        let next_wake_time = NEXT_WAKE_TIME.with(|value| *value.borrow());
+        let batch_size = match next_wake_time {
+            Some(time) if !self.programs.is_empty() => {
+                // Work out the wake-up sample from the start of playback,
+                // rather than from now, so that rounding doesn't depend on
+                // where the previous batch happened to end.
+                let wake_sample = (time * self.sample_rate as f64 / 1000.0).ceil() as usize;
+                wake_sample
+                    .saturating_sub(self.total_samples)
+                    .clamp(1, max_batch_size)
+            }
+            _ => min_batch_size,
+        };
+        // Don't go past the time limit, if there is one.
+        match self.max_samples {
+            Some(max) => batch_size.min(max.saturating_sub(self.total_samples)),
+            None => batch_size,
+        }
+    }
+
+    fn render_audio<F: FnMut(f64)>(&mut self, mut f: F) {
+        assert_eq!(self.num_channels, 1);
+        self.render_frames(|left, right| f((left + right) / 2.0));
+    }
+
+    fn render_frames<F: FnMut(f64, f64)>(&mut self, mut f: F) {
+        self.with_context(|player| {
+            while !player.is_finished {
+                player.generate_samples(|registry, num_samples| {
+                    for _ in 0..num_samples {
+                        let (left, right) = registry.next_frame();
+                        f(left, right);
+                    }
+                });
+            }
+        });
     }
 
     fn write_wav_audio<W: std::io::Write + std::io::Seek>(
         &mut self,
         writer: &mut hound::WavWriter<W>,
-    ) {
-        assert_eq!(self.num_channels, 1);
-        let num_samples = self.samples_per_program_loop();
-        self.init_thread_locals();
-
-        while !self.is_finished {
-            self.generate_samples(|registry| {
-                for _ in 0..num_samples {
-                    let value = registry.next_sample();
-                    writer.write_sample(value as f32).unwrap();
+        options: WavOptions,
+        on_progress: &mut dyn FnMut(usize),
+    ) -> PeakMeter {
+        let mut levels = PeakMeter::new(options.channels() as usize);
+        let flush_interval_frames =
+            ((options.flush_interval * WAV_SAMPLE_RATE as f64) as u32).max(1);
+        let progress_interval_frames = WAV_SAMPLE_RATE / 10;
+        self.render_frames(|left, right| {
+            if options.downmix_mono {
+                let mono = downmix(left, right) as f32;
+                writer.write_sample(mono).unwrap();
+                levels.add_frame(&[mono]);
+            } else {
+                writer.write_sample(left as f32).unwrap();
+                writer.write_sample(right as f32).unwrap();
+                levels.add_frame(&[left as f32, right as f32]);
+            }
+            let frames = writer.duration();
+            if frames.is_multiple_of(flush_interval_frames) {
+                writer.flush().unwrap();
+            }
+            if frames.is_multiple_of(progress_interval_frames) {
+                on_progress(frames as usize);
+            }
+        });
+        levels
+    }
+
+    #[cfg(feature = "cpal")]
+    fn write_audio<T: Sample>(&mut self, data: &mut [T], _info: &cpal::OutputCallbackInfo) {
+        self.with_context(|player| player.write_audio_in_context(data));
+    }
+
+    #[cfg(feature = "cpal")]
+    fn write_audio_in_context<T: Sample>(&mut self, data: &mut [T]) {
+        let num_channels = self.num_channels as usize;
+        // We use chunks_mut() to access individual channels:
+        // https://github.com/RustAudio/cpal/blob/master/examples/beep.rs#L127
+        let mut frames = data.chunks_mut(num_channels);
+        while frames.len() > 0 && !self.is_out_of_time(0) {
+            self.generate_samples(|registry, num_samples| {
+                for sample in frames.by_ref().take(num_samples) {
+                    let (left, right) = registry.next_frame();
+                    let mono = (left + right) / 2.0;
+
+                    for (channel, channel_sample) in sample.iter_mut().enumerate() {
+                        // The first two channels are left and right; any
+                        // others (or a single mono channel) get a mixdown.
+                        let value = match (num_channels, channel) {
+                            (1, _) => mono,
+                            (_, 0) => left,
+                            (_, 1) => right,
+                            _ => mono,
+                        };
+                        *channel_sample = Sample::from(&(value as f32));
+                    }
                 }
             });
         }
-
-        // Write about a quarter-second of silence.
-        for _ in 0..(self.sample_rate / 4) {
-            writer.write_sample(0.0).unwrap();
+        // Once the time limit's been reached, anything still sounding stays
+        // cut off.
+        for sample in frames.flatten() {
+            *sample = Sample::from(&0.0f32);
         }
     }
+}
 
-    fn write_audio<T: Sample>(&mut self, data: &mut [T], _info: &cpal::OutputCallbackInfo) {
-        if self.total_samples == 0 {
-            self.init_thread_locals();
+impl Drop for Player {
+    fn drop(&mut self) {
+        // Whatever the programs (and the handle's shapes) are holding onto
+        // belongs to this player's context, so let go of it there.
+        self.with_context(|player| {
+            player.programs.clear();
+            player.handle_shapes.clear();
+            let new_programs = NEW_PROGRAMS.with(|programs| programs.take());
+            drop(new_programs);
+        });
+    }
+}
+
+/// Renders a program a block at a time, for hosts that pull audio from us
+/// rather than the other way around (e.g. a Web Audio worklet).
+pub struct FrameRenderer {
+    player: Player,
+}
+
+impl FrameRenderer {
+    pub fn new<P: PlayerProgram>(sample_rate: usize, program: P) -> Self {
+        FrameRenderer {
+            player: Player::new(2, sample_rate, program, None),
         }
+    }
 
-        let num_channels = self.num_channels as usize;
-        for chunk in data.chunks_mut(self.samples_per_program_loop() * num_channels) {
-            self.generate_samples(|registry| {
-                // We use chunks_mut() to access individual channels:
-                // https://github.com/RustAudio/cpal/blob/master/examples/beep.rs#L127
-                for sample in chunk.chunks_mut(num_channels) {
-                    let value = registry.next_sample();
-                    let sample_value = Sample::from(&(value as f32));
-
-                    for channel_sample in sample.iter_mut() {
-                        *channel_sample = sample_value;
+    /// Fills `out` with interleaved stereo samples, left channel first, and
+    /// returns how many frames were written. Once the program finishes,
+    /// this will be fewer than `out` can hold, and the rest is left alone.
+    pub fn render(&mut self, out: &mut [f32]) -> usize {
+        self.player.with_context(|player| {
+            let mut frames = out.chunks_exact_mut(2);
+            let mut frames_written = 0;
+            while frames.len() > 0 && !player.is_finished {
+                player.generate_samples(|registry, num_samples| {
+                    for frame in frames.by_ref().take(num_samples) {
+                        let (left, right) = registry.next_frame();
+                        frame[0] = left as f32;
+                        frame[1] = right as f32;
+                        frames_written += 1;
                     }
-                }
+                });
+            }
+            frames_written
+        })
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.player.is_finished
+    }
+}
+
+/// Runs a program without generating any audio, moving the clock straight
+/// from one wake-up time to the next, e.g. so that tests can check exactly
+/// when things happen, or to quickly record a program's timeline.
+pub(crate) struct SilentClock {
+    player: Player,
+}
+
+impl SilentClock {
+    /// Starts the program, running it up to its first wait.
+    pub fn new<P: PlayerProgram>(program: P) -> Self {
+        let mut player = Player::new_offline(2, program);
+        player.with_context(Player::execute_programs);
+        SilentClock { player }
+    }
+
+    #[cfg(test)]
+    pub fn now(&self) -> f64 {
+        self.player.context.time
+    }
+
+    /// Returns when the programs are next waiting to wake up, if they're
+    /// waiting on `Player::wait`.
+    pub fn next_wake_time(&self) -> Option<f64> {
+        if self.player.programs.is_empty() {
+            return None;
+        }
+        self.player.context.next_wake_time
+    }
+
+    /// Moves the clock to the next wake-up time and runs the programs.
+    /// Returns false, without doing anything, if nothing is waiting.
+    pub fn step(&mut self) -> bool {
+        let Some(time) = self.next_wake_time() else {
+            return false;
+        };
+        self.set_time(time);
+        self.player.with_context(Player::execute_programs);
+        true
+    }
+
+    /// Steps through every wake-up time up to the given one, then leaves
+    /// the clock there.
+    #[cfg(test)]
+    pub fn advance_to(&mut self, time: f64) {
+        while self.next_wake_time().is_some_and(|wake| wake <= time) {
+            self.step();
+        }
+        self.set_time(time.max(self.now()));
+    }
+
+    /// Steps until every program has finished.
+    pub fn run_to_end(&mut self) {
+        while self.step() {}
+    }
+
+    #[cfg(test)]
+    pub fn is_finished(&self) -> bool {
+        self.player.programs.is_empty()
+    }
+
+    fn set_time(&mut self, time: f64) {
+        self.player.context.time = time;
+    }
+}
+
+fn write_wav_silence<W: std::io::Write + std::io::Seek>(writer: &mut hound::WavWriter<W>) {
+    // Write about a quarter-second of silence.
+    for _ in 0..(WAV_SAMPLE_RATE / 4 * writer.spec().channels as u32) {
+        writer.write_sample(0.0).unwrap();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        downmix_to_mono, FrameRenderer, Player, PlayerHandle, SilentClock, WavOptions,
+        WAV_SAMPLE_RATE,
+    };
+    use crate::{
+        analysis::peak,
+        synth::{AudioShape, StereoAudioShape, Waveform},
+    };
+    use std::sync::{Arc, Barrier, Mutex};
+
+    /// Plays noise for a moment, with its volume picked at random.
+    async fn random_noise() {
+        let volume = Player::with_rng(|rng| 64 + rng.next_index(128) as u8);
+        let _shape = Player::new_shape(AudioShape {
+            waveform: Waveform::Noise,
+            frequency: 0.0,
+            volume,
+            ..Default::default()
+        });
+        Player::wait(50.0).await;
+    }
+
+    fn render_with_seed(seed: u64) -> Vec<f32> {
+        Player::set_seed(seed);
+        Player::render_to_vec(random_noise())
+    }
+
+    #[test]
+    fn test_seed_determines_output() {
+        assert_eq!(render_with_seed(1), render_with_seed(1));
+        assert_ne!(render_with_seed(1), render_with_seed(2));
+    }
+
+    #[test]
+    fn test_render_to_stereo_vec_keeps_hard_left_in_left_channel() {
+        let samples = Player::render_to_stereo_vec(async {
+            let shape = AudioShape {
+                waveform: Waveform::Sine,
+                frequency: 440.0,
+                volume: 255,
+                ..Default::default()
+            };
+            let _shape = Player::new_stereo_shape(StereoAudioShape::panned(shape, -1.0));
+            Player::wait(100.0).await;
+        });
+        let left: Vec<f32> = samples.iter().step_by(2).copied().collect();
+        let right: Vec<f32> = samples.iter().skip(1).step_by(2).copied().collect();
+        assert_eq!(left.len(), right.len());
+        assert!(peak(&left) > 0.5);
+        assert_eq!(peak(&right), 0.0);
+    }
+
+    fn render_frequency_change(retrigger: bool) -> Vec<f32> {
+        Player::render_to_vec(async move {
+            let mut shape = Player::new_shape(AudioShape {
+                waveform: Waveform::Square,
+                frequency: 440.0,
+                volume: 255,
+                ..Default::default()
             });
+            Player::wait(100.0).await;
+            if retrigger {
+                shape.note_on(880.0);
+            } else {
+                shape.set_frequency(880.0);
+            }
+            Player::wait(100.0).await;
+        })
+    }
+
+    #[test]
+    fn test_note_on_retriggers_but_set_frequency_is_legato() {
+        let change = WAV_SAMPLE_RATE as usize / 10;
+        // The square wave is at full volume right after a legato change...
+        let legato = render_frequency_change(false);
+        assert!(peak(&legato[change..change + 10]) > 0.99);
+        // ...but fades out and starts over from silence when retriggered.
+        let retriggered = render_frequency_change(true);
+        let fade = WAV_SAMPLE_RATE as usize * 3 / 1000;
+        assert!(peak(&retriggered[change + fade..change + fade + 10]) < 0.1);
+        assert!(peak(&retriggered[change + fade + 255..change + fade + 265]) > 0.99);
+    }
+
+    fn sustained_duration(release_after_ms: Option<f64>) -> f64 {
+        Player::duration_in_millis(async move {
+            let mut shape = Player::new_shape(AudioShape {
+                waveform: Waveform::Sine,
+                frequency: 440.0,
+                volume: 255,
+                ..Default::default()
+            });
+            shape.sustain(500.0);
+            if let Some(ms) = release_after_ms {
+                Player::wait(ms).await;
+                shape.release_sustain();
+            }
+        })
+    }
+
+    #[test]
+    fn test_sustain_holds_shape_until_released_or_capped() {
+        let held = sustained_duration(None);
+        assert!((500.0..600.0).contains(&held), "{}", held);
+        let released = sustained_duration(Some(200.0));
+        assert!((200.0..300.0).contains(&released), "{}", released);
+    }
+
+    #[test]
+    fn test_handle_commands_are_carried_out() {
+        let (handle, commands) = PlayerHandle::new();
+        let id = handle.new_shape(AudioShape {
+            waveform: Waveform::Square,
+            frequency: 440.0,
+            volume: 0,
+            ..Default::default()
+        });
+        handle.set_volume(id, 255);
+        let other_id = handle.new_shape(AudioShape {
+            waveform: Waveform::Square,
+            frequency: 880.0,
+            volume: 255,
+            ..Default::default()
+        });
+        handle.remove_shape(other_id);
+
+        let mut samples = vec![];
+        let program = async move {
+            Player::wait(100.0).await;
+            drop(handle);
+        };
+        let mut player = Player::new_offline(1, program).with_commands(commands);
+        player.render_audio(|value| samples.push(value as f32));
+        let hundred_ms = WAV_SAMPLE_RATE as usize / 10;
+        // Only the first shape is still around, at the volume it was set to.
+        assert_eq!(peak(&samples[hundred_ms / 2..hundred_ms]), 1.0);
+        // Once the handle is gone, so are its shapes.
+        assert!(samples.len() < hundred_ms + 300, "{}", samples.len());
+    }
+
+    #[test]
+    fn test_centered_stereo_shape_matches_mono_shape() {
+        let shape = AudioShape {
+            waveform: Waveform::Sine,
+            frequency: 440.0,
+            volume: 200,
+            ..Default::default()
+        };
+        let mono = Player::render_to_stereo_vec(async move {
+            let _shape = Player::new_shape(shape);
+            Player::wait(100.0).await;
+        });
+        let centered = Player::render_to_stereo_vec(async move {
+            let _shape = Player::new_stereo_shape(StereoAudioShape::panned(shape, 0.0));
+            Player::wait(100.0).await;
+        });
+        assert_eq!(mono, centered);
+    }
+
+    #[test]
+    fn test_downmix_keeps_level_of_mono_shape() {
+        let program = || async {
+            let _shape = Player::new_shape(AudioShape {
+                waveform: Waveform::Sine,
+                frequency: 440.0,
+                volume: 255,
+                ..Default::default()
+            });
+            Player::wait(100.0).await;
+        };
+        let stereo = Player::render_to_stereo_vec(program());
+        let downmixed = downmix_to_mono(&stereo);
+        assert_eq!(peak(&downmixed), peak(&stereo));
+        // Writing a mono file takes the same path.
+        let filename = std::env::temp_dir().join(format!(
+            "rust-synth-downmix-test-{}.wav",
+            std::process::id()
+        ));
+        Player::write_wav_with_options(
+            &filename,
+            program(),
+            WavOptions {
+                downmix_mono: true,
+                ..Default::default()
+            },
+        );
+        let written: Vec<f32> = hound::WavReader::open(&filename)
+            .unwrap()
+            .into_samples::<f32>()
+            .map(|sample| sample.unwrap())
+            .collect();
+        std::fs::remove_file(filename).unwrap();
+        assert_eq!(peak(&written), peak(&stereo));
+    }
+
+    #[test]
+    fn test_write_wav_reports_progress() {
+        let filename = std::env::temp_dir().join(format!(
+            "rust-synth-progress-test-{}.wav",
+            std::process::id()
+        ));
+        let mut progress = vec![];
+        Player::write_wav_with_progress(
+            &filename,
+            Player::wait(1050.0),
+            WavOptions::default(),
+            |frames| progress.push(frames),
+        );
+        std::fs::remove_file(filename).unwrap();
+        let tenth = WAV_SAMPLE_RATE as usize / 10;
+        let expected: Vec<usize> = (1..=10).map(|i| i * tenth).collect();
+        assert_eq!(&progress[..10], &expected[..]);
+        // The last call reports everything that was written.
+        assert_eq!(progress.len(), 11);
+        assert!(progress[10] >= WAV_SAMPLE_RATE as usize * 105 / 100);
+        assert!(progress[10] < WAV_SAMPLE_RATE as usize * 106 / 100);
+    }
+
+    #[test]
+    fn test_write_wav_flushes_partial_audio() {
+        let filename =
+            std::env::temp_dir().join(format!("rust-synth-flush-test-{}.wav", std::process::id()));
+        let partial_filename = filename.clone();
+        Player::write_wav_with_options(
+            &filename,
+            async move {
+                let _shape = Player::new_shape(AudioShape {
+                    waveform: Waveform::Sine,
+                    frequency: 440.0,
+                    volume: 127,
+                    ..Default::default()
+                });
+                Player::wait(600.0).await;
+                // We're still rendering, but the audio up to the last flush
+                // should already be readable.
+                let reader = hound::WavReader::open(&partial_filename).unwrap();
+                assert_eq!(reader.duration(), WAV_SAMPLE_RATE / 2);
+                Player::wait(100.0).await;
+            },
+            WavOptions {
+                flush_interval: 0.25,
+                ..Default::default()
+            },
+        );
+        std::fs::remove_file(filename).unwrap();
+    }
+
+    async fn panned_beep() {
+        let shape = AudioShape {
+            waveform: Waveform::Sine,
+            frequency: 440.0,
+            volume: 255,
+            ..Default::default()
+        };
+        let _shape = Player::new_stereo_shape(StereoAudioShape::panned(shape, -0.5));
+        Player::wait(100.0).await;
+    }
+
+    #[test]
+    fn test_frame_renderer_matches_render_to_stereo_vec() {
+        let expected = Player::render_to_stereo_vec(panned_beep());
+        let mut renderer = FrameRenderer::new(WAV_SAMPLE_RATE as usize, panned_beep());
+        let mut samples = vec![];
+        // An odd block size, so blocks don't line up with the player's batches.
+        let mut block = [0.0; 2 * 127];
+        while !renderer.is_finished() {
+            let frames = renderer.render(&mut block);
+            samples.extend_from_slice(&block[..frames * 2]);
         }
+        // Blocks can split the player's batches, which shifts when it notices
+        // everything has finished, but only by a batch or so.
+        let max_batch_samples = 2 * WAV_SAMPLE_RATE as usize / 2000;
+        let len = samples.len().min(expected.len());
+        assert!(samples.len().abs_diff(expected.len()) <= max_batch_samples);
+        assert_eq!(samples[..len], expected[..len]);
+    }
+
+    #[test]
+    fn test_players_on_different_threads_do_not_interfere() {
+        let expected_beep = Player::render_to_stereo_vec(panned_beep());
+        let expected_noise = Player::render_to_stereo_vec(random_noise());
+        // Enough blocks for either program to finish.
+        let blocks = expected_beep.len().max(expected_noise.len()) / (2 * 127) + 2;
+        // The threads render a block at a time in lockstep, so both players
+        // are running at once.
+        let barrier = Arc::new(Barrier::new(2));
+        let render_in_lockstep = |renderer: FrameRenderer| {
+            let barrier = barrier.clone();
+            let mut renderer = renderer;
+            std::thread::spawn(move || {
+                let mut samples = vec![];
+                let mut block = [0.0; 2 * 127];
+                for _ in 0..blocks {
+                    barrier.wait();
+                    let frames = renderer.render(&mut block);
+                    samples.extend_from_slice(&block[..frames * 2]);
+                }
+                assert!(renderer.is_finished());
+                samples
+            })
+        };
+        let beep = render_in_lockstep(FrameRenderer::new(WAV_SAMPLE_RATE as usize, panned_beep()));
+        let noise =
+            render_in_lockstep(FrameRenderer::new(WAV_SAMPLE_RATE as usize, random_noise()));
+        for (output, expected) in [
+            (beep.join().unwrap(), expected_beep),
+            (noise.join().unwrap(), expected_noise),
+        ] {
+            let len = output.len().min(expected.len());
+            assert!(output.len().abs_diff(expected.len()) <= 2 * WAV_SAMPLE_RATE as usize / 2000);
+            assert_eq!(output[..len], expected[..len]);
+        }
+    }
+
+    #[test]
+    fn test_panicking_program_does_not_leave_its_context_behind() {
+        let expected = Player::render_to_stereo_vec(panned_beep());
+        let mut renderer = FrameRenderer::new(WAV_SAMPLE_RATE as usize, panned_beep());
+        let mut output = vec![];
+        let mut block = [0.0; 2 * 127];
+        let frames = renderer.render(&mut block);
+        output.extend_from_slice(&block[..frames * 2]);
+
+        let result = std::panic::catch_unwind(|| {
+            Player::render_to_vec(async {
+                let _shape = Player::new_shape(AudioShape::default());
+                Player::wait(20.0).await;
+                panic!("this program panics on purpose");
+            })
+        });
+        assert!(result.is_err());
+        assert_eq!(Player::current_time(), 0.0);
+
+        while !renderer.is_finished() {
+            let frames = renderer.render(&mut block);
+            output.extend_from_slice(&block[..frames * 2]);
+        }
+        let len = output.len().min(expected.len());
+        assert_eq!(output[..len], expected[..len]);
+    }
+
+    #[test]
+    fn test_players_taking_turns_on_one_thread_do_not_interfere() {
+        let expected_beep = Player::render_to_stereo_vec(panned_beep());
+        let expected_noise = Player::render_to_stereo_vec(random_noise());
+        let mut renderers = [
+            FrameRenderer::new(WAV_SAMPLE_RATE as usize, panned_beep()),
+            FrameRenderer::new(WAV_SAMPLE_RATE as usize, random_noise()),
+        ];
+        let mut outputs = [vec![], vec![]];
+        let mut block = [0.0; 2 * 127];
+        while renderers.iter().any(|renderer| !renderer.is_finished()) {
+            for (renderer, output) in renderers.iter_mut().zip(outputs.iter_mut()) {
+                let frames = renderer.render(&mut block);
+                output.extend_from_slice(&block[..frames * 2]);
+            }
+        }
+        for (output, expected) in outputs.iter().zip([expected_beep, expected_noise]) {
+            let len = output.len().min(expected.len());
+            assert!(output.len().abs_diff(expected.len()) <= 2 * WAV_SAMPLE_RATE as usize / 2000);
+            assert_eq!(output[..len], expected[..len]);
+        }
+    }
+
+    #[test]
+    fn test_test_clock_jumps_between_wake_times() {
+        let times = Arc::new(Mutex::new(vec![]));
+        let program_times = times.clone();
+        let mut clock = SilentClock::new(async move {
+            for ms in [100.0, 250.0, 50.0] {
+                Player::wait(ms).await;
+                program_times.lock().unwrap().push(Player::current_time());
+            }
+        });
+        assert_eq!(clock.next_wake_time(), Some(100.0));
+
+        clock.advance_to(120.0);
+        assert_eq!(clock.now(), 120.0);
+        assert_eq!(*times.lock().unwrap(), vec![100.0]);
+        assert_eq!(clock.next_wake_time(), Some(350.0));
+
+        clock.run_to_end();
+        assert!(clock.is_finished());
+        assert_eq!(*times.lock().unwrap(), vec![100.0, 350.0, 400.0]);
+        assert!(!clock.step());
+    }
+
+    fn render_with_extra_shape(extra_waveform: Option<Waveform>) -> Vec<f32> {
+        Player::render_to_vec(async move {
+            let _shape = Player::new_shape(AudioShape {
+                waveform: Waveform::Sine,
+                frequency: 440.0,
+                volume: 127,
+                ..Default::default()
+            });
+            let _extra = extra_waveform.map(|waveform| {
+                Player::new_shape(AudioShape {
+                    waveform,
+                    frequency: 220.0,
+                    volume: 255,
+                    ..Default::default()
+                })
+            });
+            Player::wait(100.0).await;
+        })
+    }
+
+    #[test]
+    fn test_silence_contributes_nothing_to_the_mix() {
+        assert_eq!(
+            render_with_extra_shape(Some(Waveform::Silence)),
+            render_with_extra_shape(None)
+        );
+    }
+
+    #[test]
+    fn test_time_limit_cuts_rendering_short() {
+        Player::set_time_limit(Some(250.0));
+        let samples = Player::render_to_vec(async {
+            let _shape = Player::new_shape(AudioShape {
+                waveform: Waveform::Sine,
+                frequency: 440.0,
+                volume: 127,
+                ..Default::default()
+            });
+            Player::wait(1000.0).await;
+        });
+        Player::set_time_limit(None);
+        assert_eq!(samples.len(), WAV_SAMPLE_RATE as usize / 4);
+        assert!(peak(&samples[samples.len() - 100..]) > 0.1);
+    }
+
+    #[test]
+    fn test_write_wav_reports_clipping() {
+        let filename =
+            std::env::temp_dir().join(format!("rust-synth-clip-test-{}.wav", std::process::id()));
+        let report = Player::write_wav(&filename, async {
+            // Two full-volume shapes in phase add up to twice full scale.
+            let shape = AudioShape {
+                waveform: Waveform::Square,
+                frequency: 440.0,
+                volume: 255,
+                ..Default::default()
+            };
+            let _shapes = [Player::new_shape(shape), Player::new_shape(shape)];
+            Player::wait(100.0).await;
+        });
+        std::fs::remove_file(filename).unwrap();
+        let levels = report.levels().unwrap();
+        assert!(levels.has_clipped());
+        assert_eq!(levels.peaks(), &[2.0, 2.0]);
+        assert!(report.to_string().contains("clipped"));
+    }
+
+    #[cfg(feature = "cpal")]
+    #[test]
+    fn test_stream_errors_stop_waiting_for_playback() {
+        use super::{wait_for_playback, PlaybackError};
+        use std::sync::mpsc::sync_channel;
+        use std::time::Duration;
+
+        let (sender, receiver) = sync_channel(1);
+        let error_sender = sender.clone();
+        std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(20));
+            let _ = error_sender.try_send(Err(cpal::StreamError::DeviceNotAvailable.into()));
+        });
+        let mut updates = 0;
+        let result = wait_for_playback(&receiver, Duration::from_millis(1), || updates += 1);
+        assert_eq!(result, Err(PlaybackError::DeviceLost));
+        assert!(updates > 0);
     }
 }
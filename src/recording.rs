@@ -0,0 +1,155 @@
+// Captures the notes that instruments play, so that a program's music can
+// be exported (e.g. as a piano roll) rather than just heard. It can also
+// capture every change a program makes to its synths, for debugging.
+
+use std::cell::RefCell;
+
+use crate::note::MidiNote;
+use crate::player::{Player, PlayerProgram, SilentClock};
+
+thread_local! {
+    static RECORDED_NOTES: RefCell<Option<Vec<NoteEvent>>> = const { RefCell::new(None) };
+    static RECORDED_EVENTS: RefCell<Option<Vec<ScheduledEvent>>> = const { RefCell::new(None) };
+}
+
+/// A note that was played, with its start and end times in milliseconds
+/// from the beginning of the program.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct NoteEvent {
+    pub note: MidiNote,
+    pub start_ms: f64,
+    pub end_ms: f64,
+}
+
+/// Adds the note to the current recording, if we're making one.
+pub fn record_note(event: NoteEvent) {
+    RECORDED_NOTES.with(|notes| {
+        if let Some(notes) = notes.borrow_mut().as_mut() {
+            notes.push(event);
+        }
+    });
+}
+
+/// Runs the program without keeping any of the audio it generates, and
+/// returns the notes that its instruments played, sorted by start time.
+pub fn record_notes<P: PlayerProgram>(program: P) -> Vec<NoteEvent> {
+    RECORDED_NOTES.with(|notes| *notes.borrow_mut() = Some(vec![]));
+    Player::duration_in_millis(program);
+    let mut events = RECORDED_NOTES.with(|notes| notes.borrow_mut().take().unwrap());
+    events.sort_by(|a, b| a.start_ms.total_cmp(&b.start_ms));
+    events
+}
+
+/// What a program did to one of its synth voices.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum VoiceAction {
+    Start,
+    /// The frequency or volume changed.
+    Change,
+    /// A new note started, with its attack starting over.
+    Retrigger,
+    /// The program let go of the voice, which fades out from here.
+    Stop,
+}
+
+impl VoiceAction {
+    pub fn name(&self) -> &'static str {
+        match self {
+            VoiceAction::Start => "start",
+            VoiceAction::Change => "change",
+            VoiceAction::Retrigger => "retrigger",
+            VoiceAction::Stop => "stop",
+        }
+    }
+}
+
+/// Something a program did to a synth voice, along with the voice's
+/// frequency and volume afterwards. Stereo voices give those of their
+/// louder channel.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct ScheduledEvent {
+    pub time_ms: f64,
+    /// Identifies the voice, which is unique within a program.
+    pub voice: usize,
+    pub action: VoiceAction,
+    pub frequency: f64,
+    pub volume: u8,
+}
+
+/// Adds the event to the current recording, if we're making one.
+pub fn record_event(event: ScheduledEvent) {
+    RECORDED_EVENTS.with(|events| {
+        if let Some(events) = events.borrow_mut().as_mut() {
+            events.push(event);
+        }
+    });
+}
+
+/// Runs the program without generating any audio, jumping straight from
+/// one wait to the next, and returns everything it did to its synth
+/// voices, in order.
+pub fn record_events<P: PlayerProgram>(program: P) -> Vec<ScheduledEvent> {
+    RECORDED_EVENTS.with(|events| *events.borrow_mut() = Some(vec![]));
+    SilentClock::new(program).run_to_end();
+    RECORDED_EVENTS.with(|events| events.borrow_mut().take().unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{record_events, record_notes, ScheduledEvent, VoiceAction};
+    use crate::{
+        beat::{Beat, BeatSettings, FOUR_FOUR},
+        instrument::Instrument,
+        note::MidiNote,
+        synth::Waveform,
+    };
+
+    #[test]
+    fn test_record_notes_works() {
+        let events = record_notes(async {
+            let mut instrument =
+                Instrument::new(BeatSettings::new(60, FOUR_FOUR), 63, Waveform::Sine);
+            instrument.play_note("C4", Beat::Quarter).await;
+            instrument.play_chord(&["E4", "G4"], Beat::Half).await;
+        });
+        let notes: Vec<MidiNote> = events.iter().map(|event| event.note).collect();
+        let c4 = MidiNote::parse(&"C4").unwrap();
+        assert_eq!(notes[0], c4);
+        assert_eq!(notes.len(), 3);
+        assert_eq!(events[0].start_ms, 0.0);
+        // Notes end when they're released, 50 ms before their beat is over.
+        assert!((events[0].end_ms - 950.0).abs() < 1.0);
+        assert!((events[1].start_ms - 1000.0).abs() < 1.0);
+        assert!((events[2].end_ms - 2950.0).abs() < 1.0);
+    }
+
+    #[test]
+    fn test_record_events_works() {
+        let events = record_events(async {
+            let mut instrument =
+                Instrument::new(BeatSettings::new(60, FOUR_FOUR), 63, Waveform::Sine);
+            instrument.play_note("A4", Beat::Quarter).await;
+            instrument.play_note("A5", Beat::Eighth).await;
+        });
+        let event = |time_ms, action, frequency, volume| ScheduledEvent {
+            time_ms,
+            voice: 1,
+            action,
+            frequency,
+            volume,
+        };
+        assert_eq!(
+            events,
+            vec![
+                event(0.0, VoiceAction::Start, 0.0, 0),
+                event(0.0, VoiceAction::Change, 440.0, 0),
+                event(0.0, VoiceAction::Change, 440.0, 63),
+                event(950.0, VoiceAction::Change, 440.0, 0),
+                event(1000.0, VoiceAction::Change, 880.0, 0),
+                event(1000.0, VoiceAction::Change, 880.0, 63),
+                event(1450.0, VoiceAction::Change, 880.0, 0),
+                event(1500.0, VoiceAction::Stop, 880.0, 0),
+            ]
+        );
+    }
+}
@@ -0,0 +1,109 @@
+// A small deterministic PRNG (xorshift64*), so that a given seed always
+// produces the same sequence of numbers on every platform.
+// https://en.wikipedia.org/wiki/Xorshift#xorshift*
+//
+// Everything random should draw from an `Rng` made from the one seed the
+// user chooses (`--seed` on the command line): programs and synths through
+// `Player::with_rng`, which the player seeds from `Player::set_seed` (and
+// which seeds each synth's noise, too), and effects and melody generators
+// through an `Rng` they're handed. The contract is that the seed, together
+// with the program and its options, fully determines the output, so the
+// same seed always renders exactly the same audio.
+
+#[derive(Clone)]
+pub struct Rng {
+    state: u64,
+}
+
+impl Rng {
+    pub fn new(seed: u64) -> Self {
+        // Xorshift gets stuck at zero, so make sure we never start there.
+        let state = seed ^ 0x9E37_79B9_7F4A_7C15;
+        Rng {
+            state: if state == 0 { 1 } else { state },
+        }
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        let mut x = self.state;
+        x ^= x >> 12;
+        x ^= x << 25;
+        x ^= x >> 27;
+        self.state = x;
+        x.wrapping_mul(0x2545_F491_4F6C_DD1D)
+    }
+
+    /// Returns a number in the range [0, 1).
+    pub fn next_f64(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    /// Returns a number in the range [0, len).
+    pub fn next_index(&mut self, len: usize) -> usize {
+        (self.next_u64() % len as u64) as usize
+    }
+
+    /// Returns an index into `weights`, chosen with probability
+    /// proportional to its weight.
+    ///
+    /// Panics if `weights` is empty.
+    pub fn choose_weighted(&mut self, weights: &[f64]) -> usize {
+        assert!(
+            !weights.is_empty(),
+            "Can't choose from an empty list of weights"
+        );
+        let total: f64 = weights.iter().sum();
+        let mut target = self.next_f64() * total;
+        for (i, weight) in weights.iter().enumerate() {
+            if target < *weight {
+                return i;
+            }
+            target -= weight;
+        }
+        weights.len() - 1
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Rng;
+
+    #[test]
+    fn test_same_seed_produces_same_sequence() {
+        let mut a = Rng::new(1234);
+        let mut b = Rng::new(1234);
+        for _ in 0..100 {
+            assert_eq!(a.next_u64(), b.next_u64());
+        }
+    }
+
+    #[test]
+    fn test_different_seeds_produce_different_sequences() {
+        let mut a = Rng::new(1);
+        let mut b = Rng::new(2);
+        assert_ne!(a.next_u64(), b.next_u64());
+    }
+
+    #[test]
+    fn test_next_f64_is_in_range() {
+        let mut rng = Rng::new(0);
+        for _ in 0..1000 {
+            let value = rng.next_f64();
+            assert!((0.0..1.0).contains(&value));
+        }
+    }
+
+    #[test]
+    fn test_choose_weighted_never_picks_zero_weight() {
+        let mut rng = Rng::new(5);
+        for _ in 0..1000 {
+            assert_ne!(rng.choose_weighted(&[1.0, 0.0, 1.0]), 1);
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "empty list of weights")]
+    fn test_choose_weighted_rejects_empty_weights() {
+        Rng::new(5).choose_weighted(&[]);
+    }
+}
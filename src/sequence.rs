@@ -0,0 +1,167 @@
+use crate::{
+    beat::Beat,
+    note::{MidiNote, MidiNoteParseError},
+};
+
+/// One step of a sequence written in the compact notation that
+/// `parse_sequence` understands.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SequenceStep {
+    Note {
+        note: MidiNote,
+        length: Beat,
+        /// Whether the note runs straight into the next one, without the
+        /// usual pause between notes.
+        slur: bool,
+    },
+    Rest(Beat),
+}
+
+#[derive(Debug, PartialEq)]
+pub enum SequenceParseErrorKind {
+    InvalidNote(MidiNoteParseError),
+    /// The duration after the `:` isn't one of `w`, `h`, `q`, `e` or `s`.
+    InvalidDuration(String),
+    /// Rests can't be slurred into the next note, since nothing's playing.
+    SlurredRest,
+}
+
+/// Describes what was wrong with a sequence, and where.
+#[derive(Debug, PartialEq)]
+pub struct SequenceParseError {
+    /// The position of the bad token in the sequence, starting from 0.
+    pub index: usize,
+    pub token: String,
+    pub kind: SequenceParseErrorKind,
+}
+
+impl std::fmt::Display for SequenceParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Token {} ('{}') is invalid: ", self.index, self.token)?;
+        match &self.kind {
+            SequenceParseErrorKind::InvalidNote(err) => write!(f, "bad note name ({:?})", err),
+            SequenceParseErrorKind::InvalidDuration(duration) => write!(
+                f,
+                "unknown duration '{}', expected one of w, h, q, e or s",
+                duration
+            ),
+            SequenceParseErrorKind::SlurredRest => write!(f, "rests can't be slurred"),
+        }
+    }
+}
+
+fn parse_duration(duration: &str) -> Option<Beat> {
+    match duration {
+        "w" => Some(Beat::Whole),
+        "h" => Some(Beat::Half),
+        "q" => Some(Beat::Quarter),
+        "e" => Some(Beat::Eighth),
+        "s" => Some(Beat::Sixteenth),
+        _ => None,
+    }
+}
+
+fn parse_step(token: &str) -> Result<SequenceStep, SequenceParseErrorKind> {
+    let (token, slur) = match token.strip_suffix('~') {
+        Some(token) => (token, true),
+        None => (token, false),
+    };
+    let (name, length) = match token.split_once(':') {
+        Some((name, duration)) => (
+            name,
+            parse_duration(duration)
+                .ok_or_else(|| SequenceParseErrorKind::InvalidDuration(duration.to_string()))?,
+        ),
+        None => (token, Beat::Quarter),
+    };
+    if name == "r" {
+        return if slur {
+            Err(SequenceParseErrorKind::SlurredRest)
+        } else {
+            Ok(SequenceStep::Rest(length))
+        };
+    }
+    let note = MidiNote::parse(&name).map_err(SequenceParseErrorKind::InvalidNote)?;
+    Ok(SequenceStep::Note { note, length, slur })
+}
+
+/// Parses a whitespace-separated sequence of notes and rests, like
+/// `"E4:h E4:q~ F4:q r:h"`.
+///
+/// Each token is a note name (or `r` for a rest), optionally followed by
+/// `:` and a duration: `w`, `h`, `q`, `e` or `s`, for whole through
+/// sixteenth. Without a duration, it's a quarter. A trailing `~` slurs the
+/// note into the next one.
+pub fn parse_sequence(sequence: &str) -> Result<Vec<SequenceStep>, SequenceParseError> {
+    sequence
+        .split_whitespace()
+        .enumerate()
+        .map(|(index, token)| {
+            parse_step(token).map_err(|kind| SequenceParseError {
+                index,
+                token: token.to_string(),
+                kind,
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_sequence, SequenceParseError, SequenceParseErrorKind, SequenceStep};
+    use crate::{
+        beat::Beat,
+        note::{MidiNote, MidiNoteParseError},
+    };
+
+    fn note(value: &str) -> MidiNote {
+        MidiNote::parse(&value).unwrap()
+    }
+
+    #[test]
+    fn test_parse_sequence_works() {
+        assert_eq!(
+            parse_sequence(" E4:h  C#5~\nr:s "),
+            Ok(vec![
+                SequenceStep::Note {
+                    note: note("E4"),
+                    length: Beat::Half,
+                    slur: false
+                },
+                SequenceStep::Note {
+                    note: note("C#5"),
+                    length: Beat::Quarter,
+                    slur: true
+                },
+                SequenceStep::Rest(Beat::Sixteenth),
+            ])
+        );
+        assert_eq!(parse_sequence(""), Ok(vec![]));
+    }
+
+    #[test]
+    fn test_parse_sequence_reports_bad_tokens() {
+        assert_eq!(
+            parse_sequence("E4:h X4:q"),
+            Err(SequenceParseError {
+                index: 1,
+                token: "X4:q".to_string(),
+                kind: SequenceParseErrorKind::InvalidNote(MidiNoteParseError::InvalidNoteCharacter),
+            })
+        );
+        let err = parse_sequence("E4 F4 G4:x").unwrap_err();
+        assert_eq!(err.index, 2);
+        assert_eq!(
+            err.kind,
+            SequenceParseErrorKind::InvalidDuration("x".to_string())
+        );
+        assert_eq!(
+            err.to_string(),
+            "Token 2 ('G4:x') is invalid: unknown duration 'x', expected one of w, h, q, e or s"
+        );
+        assert_eq!(
+            parse_sequence("r~").unwrap_err().kind,
+            SequenceParseErrorKind::SlurredRest
+        );
+    }
+}
@@ -0,0 +1,123 @@
+// Right-hand melodies of the built-in songs, kept as data so they can be
+// analyzed (e.g. to train melody generators) as well as played.
+
+use crate::beat::Beat;
+
+pub type Melody = &'static [(&'static str, Beat)];
+
+// "Captain Silver" from pg. 21 of Schaum's Red Book (Alfred).
+
+/// Measures 1-4 (repeated as measures 9-12).
+pub const CAPTAIN_SILVER_1: Melody = &[
+    ("E4", Beat::Half),
+    ("E4", Beat::Half),
+    ("E4", Beat::Half),
+    ("E4", Beat::Half),
+    ("E4", Beat::Half),
+    ("E4", Beat::Quarter),
+    ("F4", Beat::Quarter),
+    ("G4", Beat::Whole),
+];
+
+/// Measures 5-8.
+pub const CAPTAIN_SILVER_2: Melody = &[
+    ("F4", Beat::Half),
+    ("F4", Beat::Half),
+    ("D4", Beat::Half),
+    ("D4", Beat::Half),
+    ("G4", Beat::Whole),
+    ("F4", Beat::Whole),
+];
+
+/// Measures 13-16. The two final notes are tied together.
+pub const CAPTAIN_SILVER_3: Melody = &[
+    ("F4", Beat::Half),
+    ("F4", Beat::Half),
+    ("D4", Beat::Half),
+    ("D4", Beat::Half),
+    ("C4", Beat::Whole),
+    ("C4", Beat::Whole),
+];
+
+pub fn captain_silver_melody() -> Vec<(&'static str, Beat)> {
+    [
+        CAPTAIN_SILVER_1,
+        CAPTAIN_SILVER_2,
+        CAPTAIN_SILVER_1,
+        CAPTAIN_SILVER_3,
+    ]
+    .concat()
+}
+
+// "Tune of the Tuna Fish" from pg. 29 of Schaum's Red Book (Alfred).
+
+pub const TUNA_FISH_TUNA_FISH: Melody = &[
+    ("C5", Beat::Quarter),
+    ("A4", Beat::Quarter),
+    ("A4", Beat::Half),
+    ("Bb4", Beat::Quarter),
+    ("G4", Beat::Quarter),
+    ("G4", Beat::Half),
+];
+
+pub const SING_A_TUNE_OF_TUNA_FISH: Melody = &[
+    ("F4", Beat::Quarter),
+    ("G4", Beat::Quarter),
+    ("A4", Beat::Quarter),
+    ("Bb4", Beat::Quarter),
+    ("C5", Beat::Quarter),
+    ("C5", Beat::Quarter),
+    ("C5", Beat::Half),
+];
+
+pub const ITS_A_FAVRITE_DISH: Melody = &[
+    ("F4", Beat::Quarter),
+    ("A4", Beat::Quarter),
+    ("C5", Beat::Quarter),
+    ("C5", Beat::Quarter),
+    ("F4", Beat::Whole),
+];
+
+pub const EVRYBODY_LIKES_IT_SO: Melody = &[
+    ("G4", Beat::Quarter),
+    ("G4", Beat::Quarter),
+    ("G4", Beat::Quarter),
+    ("G4", Beat::Quarter),
+    ("G4", Beat::Quarter),
+    ("A4", Beat::Quarter),
+    ("Bb4", Beat::Half),
+];
+
+/// This phrase is played as F4-C5 chords; the melody is their top note.
+pub const FROM_NEW_YORK_TO_KOKOMO: Melody = &[("C5", Beat::Whole), ("C5", Beat::Whole)];
+
+pub fn tuna_melody() -> Vec<(&'static str, Beat)> {
+    [
+        TUNA_FISH_TUNA_FISH,
+        SING_A_TUNE_OF_TUNA_FISH,
+        TUNA_FISH_TUNA_FISH,
+        ITS_A_FAVRITE_DISH,
+        EVRYBODY_LIKES_IT_SO,
+        FROM_NEW_YORK_TO_KOKOMO,
+        TUNA_FISH_TUNA_FISH,
+        ITS_A_FAVRITE_DISH,
+    ]
+    .concat()
+}
+
+// "Which is Witch?" from pg. 30 of Schaum's Red Book (Alfred).
+
+/// Starts with a one-beat pickup.
+pub const WITCH_MELODY: Melody = &[
+    ("C5", Beat::Quarter),
+    ("A4", Beat::Quarter),
+    ("G4", Beat::Quarter),
+    ("F4", Beat::Quarter),
+    ("A4", Beat::Half),
+    ("F4", Beat::Quarter),
+    ("C5", Beat::DottedHalf),
+];
+
+pub fn witch_melody() -> Vec<(&'static str, Beat)> {
+    WITCH_MELODY.to_vec()
+}
@@ -1,44 +1,243 @@
-const TWO_PI: f64 = 2.0 * std::f64::consts::PI;
+use crate::rng::Rng;
 
-#[derive(Copy, Clone)]
+const TWO_PI: f64 = 2.0 * core::f64::consts::PI;
+
+#[cfg(feature = "std")]
+fn sin(x: f64) -> f64 {
+    x.sin()
+}
+
+#[cfg(not(feature = "std"))]
+fn sin(x: f64) -> f64 {
+    libm::sin(x)
+}
+
+#[cfg(feature = "std")]
+fn exp(x: f64) -> f64 {
+    x.exp()
+}
+
+#[cfg(not(feature = "std"))]
+fn exp(x: f64) -> f64 {
+    libm::exp(x)
+}
+
+/// By default, a synth that's been made inactive is considered finished
+/// once its volume drops below this fraction of full volume (-60 dB).
+pub const DEFAULT_SILENCE_THRESHOLD: f64 = 0.001;
+
+/// How long a retriggered synth takes to fade out before its new attack,
+/// in milliseconds. Cutting straight to silence would click.
+const RETRIGGER_FADE_MS: f64 = 3.0;
+
+/// How a synth's volume moves down to a lower target volume.
+#[derive(Copy, Clone, Default)]
+pub enum Release {
+    /// Move one step (1/255 of full volume) per sample.
+    #[default]
+    Linear,
+    /// Close a fixed fraction of the remaining distance every sample, so
+    /// that the volume falls by about 63% every `time_constant_ms`. This
+    /// sounds more natural, but never actually reaches zero, which is why
+    /// synths have a silence threshold.
+    Exponential { time_constant_ms: f64 },
+}
+
+#[derive(Copy, Clone, Default)]
 pub enum Waveform {
+    #[default]
     Sine,
     Square,
     Triangle,
     Sawtooth,
+    /// White noise. Its frequency is ignored.
+    Noise,
+    /// Always outputs nothing, e.g. for a sequencer lane that's switched
+    /// off but should keep its place in the mix.
+    Silence,
 }
 
-impl Default for Waveform {
-    fn default() -> Self {
-        Waveform::Sine
-    }
+// A fixed seed means the noise is the same every time, like the rest of
+// the synth's output, unless another seed is set.
+const NOISE_SEED: u64 = 0x2545_f491;
+
+/// An ADSR envelope, which shapes the volume of each note: it rises to the
+/// full volume over the attack, falls to the sustain level over the decay,
+/// stays there until the note ends, and then fades out over the release.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Envelope {
+    pub attack_ms: f64,
+    pub decay_ms: f64,
+    /// The fraction of the full volume held after the decay, from 0.0 to 1.0.
+    pub sustain_level: f64,
+    pub release_ms: f64,
 }
 
-#[derive(Copy, Clone, Default)]
+/// How a synth's volume gets to its target volume.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub enum VolumeMode {
+    /// Ramp smoothly, as set by the shape's envelope (if any) and release.
+    #[default]
+    Smooth,
+    /// Jump straight to the target, rounded to one of this many evenly
+    /// spaced levels (including silence). This is how the sound chips of
+    /// old consoles work, e.g. 16 levels on the NES and Game Boy.
+    Stepped { levels: u8 },
+}
+
+impl VolumeMode {
+    /// Stepped volume like the NES and Game Boy.
+    pub const CHIPTUNE: VolumeMode = VolumeMode::Stepped { levels: 16 };
+}
+
+#[derive(Copy, Clone, PartialEq)]
+enum EnvelopeStage {
+    Attack,
+    Decay,
+    Sustain,
+    Release,
+}
+
+/// The number of volume steps a synth has unless it's told otherwise, i.e.
+/// every volume from 0 to 255.
+pub const FULL_VOLUME_STEPS: u16 = 256;
+
+#[derive(Copy, Clone)]
 pub struct AudioShape {
     pub waveform: Waveform,
     pub frequency: f64,
     pub volume: u8,
+    /// Shapes the volume of each note. Without one, the volume just moves
+    /// one step (1/255 of full volume) per sample towards its target, which
+    /// sounds nearly instant.
+    pub envelope: Option<Envelope>,
+    pub volume_mode: VolumeMode,
+    /// How many evenly spaced levels (including silence) the synth's
+    /// output volume is rounded to, after any envelope, like the bit depth
+    /// of an old sound chip's volume register. `FULL_VOLUME_STEPS` (or
+    /// more) leaves it alone.
+    pub volume_steps: u16,
+    /// Whether the square and sawtooth waves are smoothed out at their
+    /// jumps (with PolyBLEP), so that their harmonics above the Nyquist
+    /// frequency don't alias back down as an inharmonic whine. This is on
+    /// by default; turning it off gives the naive waves, sample for sample.
+    pub band_limited: bool,
+    /// When the frequency changes while the shape is sounding, fade from
+    /// the old frequency to the new one over this many milliseconds,
+    /// rather than switching on the very next sample. This smooths over
+    /// the click a sudden change can make, e.g. between slurred notes, and
+    /// is separate from how the volume is released. The default of 0
+    /// switches right away.
+    pub crossfade_ms: f64,
+    /// When the frequency changes while the shape is sounding, ease the
+    /// pitch towards the new frequency with this time constant, in
+    /// milliseconds, instead of jumping to it. A millisecond or so keeps
+    /// rapid runs of small changes, like a pitch bend made of many
+    /// `set_frequency` calls, from being heard as a "zipper" of steps,
+    /// without audibly sliding between notes. The default of 0 jumps right
+    /// away.
+    pub frequency_smoothing_ms: f64,
+}
+
+impl Default for AudioShape {
+    fn default() -> Self {
+        AudioShape {
+            waveform: Default::default(),
+            frequency: 0.0,
+            volume: 0,
+            envelope: None,
+            volume_mode: Default::default(),
+            volume_steps: FULL_VOLUME_STEPS,
+            band_limited: true,
+            crossfade_ms: 0.0,
+            frequency_smoothing_ms: 0.0,
+        }
+    }
+}
+
+/// How many old oscillators a synth can be fading out at once, when its
+/// frequency changes again before the last fade has finished. Past this,
+/// the quietest one is cut off to make room.
+const MAX_CROSSFADES: usize = 4;
+
+/// An oscillator a synth is fading out after its frequency changed.
+#[derive(Copy, Clone)]
+struct Crossfade {
+    pos_in_wave: f64,
+    wave_delta_per_sample: f64,
+    samples_left: usize,
+    total_samples: usize,
+    /// How much of the output the oscillator made up when the fade began.
+    start_weight: f64,
+}
+
+impl Crossfade {
+    fn weight(&self) -> f64 {
+        self.start_weight * self.samples_left as f64 / self.total_samples as f64
+    }
 }
 
 pub struct AudioShapeSynthesizer {
     sample_rate: usize,
     pos_in_wave: f64,
-    volume: u8,
+    volume: f64,
     wave_delta_per_sample: f64,
     is_active: bool,
     target: AudioShape,
+    release: Release,
+    silence_threshold: f64,
+    sustain_samples_left: usize,
+    noise: Rng,
+    /// The noise waveform's current value, from -1.0 to 1.0.
+    noise_value: f64,
+    envelope_stage: EnvelopeStage,
+    /// How much the volume moves each sample in the current envelope stage.
+    envelope_step: f64,
+    /// The target volume the envelope last saw, so it can tell when a note
+    /// starts or ends.
+    envelope_target_volume: u8,
+    /// How much the volume drops each sample while fading out before a
+    /// retriggered attack, or 0 if it isn't.
+    retrigger_fade_step: f64,
+    crossfades: [Option<Crossfade>; MAX_CROSSFADES],
+    /// The delta the frequency smoothing is easing `wave_delta_per_sample`
+    /// towards.
+    target_wave_delta_per_sample: f64,
+    /// How much of the remaining distance to `target_wave_delta_per_sample`
+    /// is covered each sample.
+    smoothing_coefficient: f64,
 }
 
 impl Iterator for AudioShapeSynthesizer {
     type Item = f64;
 
     fn next(&mut self) -> Option<Self::Item> {
-        let volume_scale = self.volume as f64 / std::u8::MAX as f64;
+        let volume_scale = self.output_volume() / u8::MAX as f64;
         let value = self.base_value() * volume_scale;
 
         self.pos_in_wave = (self.pos_in_wave + self.wave_delta_per_sample) % 1.0;
+        self.move_to_target_delta();
+        for slot in self.crossfades.iter_mut() {
+            if let Some(crossfade) = slot {
+                crossfade.pos_in_wave =
+                    (crossfade.pos_in_wave + crossfade.wave_delta_per_sample) % 1.0;
+                crossfade.samples_left -= 1;
+                if crossfade.samples_left == 0 {
+                    *slot = None;
+                }
+            }
+        }
+        // Other waveforms don't use the noise, so they needn't pay for it.
+        if let Waveform::Noise = self.target.waveform {
+            self.noise_value = next_noise_value(&mut self.noise);
+        }
         self.move_to_target_volume();
+        if self.sustain_samples_left > 0 {
+            self.sustain_samples_left -= 1;
+            if self.sustain_samples_left == 0 {
+                self.end_sustain();
+            }
+        }
 
         Some(value)
     }
@@ -58,6 +257,44 @@ fn triangle_wave(t: f64) -> f64 {
     }
 }
 
+fn next_noise_value(noise: &mut Rng) -> f64 {
+    noise.next_f64() * 2.0 - 1.0
+}
+
+/// Rounds the volume to the nearest of the given number of evenly spaced
+/// levels, from silence to full volume.
+fn quantize_volume(volume: f64, levels: u16) -> f64 {
+    let max = u8::MAX as f64;
+    let steps = levels.max(2) as f64 - 1.0;
+    // Adding 0.5 rounds, since the result is never negative (`f64::round`
+    // isn't available without std).
+    let level = (volume / max * steps + 0.5) as u32;
+    level as f64 * max / steps
+}
+
+/// The PolyBLEP correction for a jump of +2 at the start of a cycle, where
+/// `t` is the position in the cycle and `dt` is how far it moves each
+/// sample. This smooths the samples on either side of the jump, and is zero
+/// everywhere else.
+fn poly_blep(t: f64, dt: f64) -> f64 {
+    if t < dt {
+        let x = t / dt;
+        x + x - x * x - 1.0
+    } else if t > 1.0 - dt {
+        let x = (t - 1.0) / dt;
+        x * x + x + x + 1.0
+    } else {
+        0.0
+    }
+}
+
+/// Returns how far the wave moves each sample, for PolyBLEP. Past half a
+/// cycle (i.e. above the Nyquist frequency), the corrections on either
+/// side of a jump would overlap, so it's capped there.
+fn band_limit_delta(delta: f64) -> f64 {
+    delta.min(0.5)
+}
+
 fn rectangle_wave(duty_cycle: f64, t: f64) -> f64 {
     if t < duty_cycle {
         1.0
@@ -68,17 +305,45 @@ fn rectangle_wave(duty_cycle: f64, t: f64) -> f64 {
 
 impl AudioShapeSynthesizer {
     fn base_value(&self) -> f64 {
+        let mut value = 0.0;
+        let mut weight_left = 1.0;
+        for crossfade in self.crossfades.iter().flatten() {
+            let weight = crossfade.weight();
+            value +=
+                weight * self.wave_value(crossfade.pos_in_wave, crossfade.wave_delta_per_sample);
+            weight_left -= weight;
+        }
+        value + weight_left * self.wave_value(self.pos_in_wave, self.wave_delta_per_sample)
+    }
+
+    /// Returns the value of the wave at the given position in its cycle,
+    /// when it moves `delta` each sample.
+    fn wave_value(&self, pos: f64, delta: f64) -> f64 {
         match self.target.waveform {
-            Waveform::Sine => (self.pos_in_wave * TWO_PI).sin(),
-            Waveform::Square => rectangle_wave(0.5, self.pos_in_wave),
-            Waveform::Triangle => triangle_wave(self.pos_in_wave),
+            Waveform::Sine => sin(pos * TWO_PI),
+            Waveform::Square if self.target.band_limited => {
+                // The wave jumps up at the start of the cycle, and down
+                // halfway through it.
+                let dt = band_limit_delta(delta);
+                rectangle_wave(0.5, pos) + poly_blep(pos, dt) - poly_blep((pos + 0.5) % 1.0, dt)
+            }
+            Waveform::Square => rectangle_wave(0.5, pos),
+            Waveform::Triangle => triangle_wave(pos),
+            Waveform::Sawtooth if self.target.band_limited => {
+                // This is the same wave as below, which jumps down halfway
+                // through the cycle, but starting from that jump.
+                let t = (pos + 0.5) % 1.0;
+                2.0 * t - 1.0 - poly_blep(t, band_limit_delta(delta))
+            }
             Waveform::Sawtooth => {
-                if self.pos_in_wave <= 0.5 {
-                    lerp(0.0, 1.0, self.pos_in_wave / 0.5)
+                if pos <= 0.5 {
+                    lerp(0.0, 1.0, pos / 0.5)
                 } else {
-                    lerp(-1.0, 0.0, (self.pos_in_wave - 0.5) / 0.5)
+                    lerp(-1.0, 0.0, (pos - 0.5) / 0.5)
                 }
             }
+            Waveform::Noise => self.noise_value,
+            Waveform::Silence => 0.0,
         }
     }
 
@@ -92,26 +357,94 @@ impl AudioShapeSynthesizer {
     }
 
     pub fn new(target: AudioShape, sample_rate: usize) -> Self {
+        let mut noise = Rng::new(NOISE_SEED);
+        let noise_value = next_noise_value(&mut noise);
         Self {
             sample_rate,
             pos_in_wave: 0.0,
-            volume: 0,
+            volume: 0.0,
             target,
             wave_delta_per_sample: Self::calculate_wave_delta_per_sample(
                 sample_rate,
                 target.frequency,
             ),
             is_active: true,
+            release: Release::Linear,
+            silence_threshold: DEFAULT_SILENCE_THRESHOLD,
+            sustain_samples_left: 0,
+            noise,
+            noise_value,
+            envelope_stage: EnvelopeStage::Release,
+            envelope_step: 0.0,
+            envelope_target_volume: 0,
+            retrigger_fade_step: 0.0,
+            crossfades: [None; MAX_CROSSFADES],
+            target_wave_delta_per_sample: Self::calculate_wave_delta_per_sample(
+                sample_rate,
+                target.frequency,
+            ),
+            smoothing_coefficient: 1.0,
         }
     }
 
+    pub fn set_release(&mut self, release: Release) {
+        self.release = release;
+    }
+
+    /// Changes the sequence that the noise waveform plays.
+    pub fn set_noise_seed(&mut self, seed: u64) {
+        self.noise = Rng::new(seed);
+        self.noise_value = next_noise_value(&mut self.noise);
+    }
+
+    /// Sets the fraction of full volume below which an inactive synth is
+    /// considered to have finished playing.
+    pub fn set_silence_threshold(&mut self, threshold: f64) {
+        self.silence_threshold = threshold;
+    }
+
     pub fn make_inactive(&mut self) {
         self.is_active = false;
-        self.target.volume = 0;
+        if self.sustain_samples_left == 0 {
+            self.target.volume = 0;
+        }
+    }
+
+    /// Keeps the synth sounding, even if it's made inactive, until either
+    /// `release_sustain` is called or the given number of samples have
+    /// been generated, whichever comes first.
+    pub fn sustain(&mut self, max_samples: usize) {
+        self.sustain_samples_left = max_samples;
+    }
+
+    pub fn release_sustain(&mut self) {
+        self.sustain_samples_left = 0;
+        self.end_sustain();
+    }
+
+    fn end_sustain(&mut self) {
+        if !self.is_active {
+            self.target.volume = 0;
+        }
+    }
+
+    /// Restarts the attack: the volume quickly fades to zero (over
+    /// `RETRIGGER_FADE_MS`) and then ramps back up to the target volume.
+    /// The waveform carries on through it, so nothing jumps.
+    pub fn retrigger(&mut self) {
+        let fade_samples = (RETRIGGER_FADE_MS * self.sample_rate as f64 / 1000.0).max(1.0);
+        self.retrigger_fade_step = self.volume / fade_samples;
+        if self.volume == 0.0 {
+            // Nothing to fade out, so make the envelope start a new attack.
+            self.envelope_target_volume = 0;
+        }
     }
 
     pub fn has_finished_playing(&self) -> bool {
-        !self.is_active && self.volume == 0
+        // Silence has no release to wait for, since nothing can be heard.
+        let is_silent = matches!(self.target.waveform, Waveform::Silence)
+            || self.volume / (u8::MAX as f64) < self.silence_threshold;
+        !self.is_active && self.sustain_samples_left == 0 && is_silent
     }
 
     pub fn get_target(&self) -> AudioShape {
@@ -119,27 +452,256 @@ impl AudioShapeSynthesizer {
     }
 
     pub fn update_target(&mut self, target: AudioShape) {
+        let old_delta = self.wave_delta_per_sample;
         self.target = target;
-        self.wave_delta_per_sample =
+        self.target_wave_delta_per_sample =
             Self::calculate_wave_delta_per_sample(self.sample_rate, self.target.frequency);
+        let smoothing_samples = target.frequency_smoothing_ms * self.sample_rate as f64 / 1000.0;
+        // A silent synth has nothing to smooth over, so a new note starts
+        // right on its pitch.
+        if smoothing_samples >= 1.0 && self.volume > 0.0 {
+            self.smoothing_coefficient = 1.0 - exp(-1.0 / smoothing_samples);
+        } else {
+            self.smoothing_coefficient = 1.0;
+            self.wave_delta_per_sample = self.target_wave_delta_per_sample;
+        }
+        let crossfade_samples = (target.crossfade_ms * self.sample_rate as f64 / 1000.0) as usize;
+        if crossfade_samples > 0
+            && old_delta != self.target_wave_delta_per_sample
+            && self.volume > 0.0
+        {
+            // Both oscillators carry on from where the wave is now, and the
+            // old one takes over whatever share of the output it had, so
+            // the fade starts out seamless even if other fades are still
+            // going.
+            let start_weight = 1.0
+                - self
+                    .crossfades
+                    .iter()
+                    .flatten()
+                    .map(Crossfade::weight)
+                    .sum::<f64>();
+            let crossfade = Crossfade {
+                pos_in_wave: self.pos_in_wave,
+                wave_delta_per_sample: old_delta,
+                samples_left: crossfade_samples,
+                total_samples: crossfade_samples,
+                start_weight,
+            };
+            let slot = match self.crossfades.iter().position(Option::is_none) {
+                Some(index) => index,
+                None => (0..MAX_CROSSFADES)
+                    .min_by(|a, b| {
+                        let weight = |i: &usize| self.crossfades[*i].map_or(0.0, |c| c.weight());
+                        weight(a).total_cmp(&weight(b))
+                    })
+                    .unwrap(),
+            };
+            self.crossfades[slot] = Some(crossfade);
+        }
+    }
+
+    /// Eases the wave delta a step towards its target, one-pole style, so
+    /// that frequency changes land smoothly rather than as a jump.
+    fn move_to_target_delta(&mut self) {
+        let distance = self.target_wave_delta_per_sample - self.wave_delta_per_sample;
+        if distance.abs() < 1e-12 {
+            self.wave_delta_per_sample = self.target_wave_delta_per_sample;
+        } else {
+            self.wave_delta_per_sample += distance * self.smoothing_coefficient;
+        }
+    }
+
+    /// Returns the volume the synth is actually heard at, after rounding
+    /// it to the shape's volume steps.
+    fn output_volume(&self) -> f64 {
+        if self.target.volume_steps >= FULL_VOLUME_STEPS {
+            self.volume
+        } else {
+            quantize_volume(self.volume, self.target.volume_steps)
+        }
     }
 
     fn move_to_target_volume(&mut self) {
-        let target = self.target.volume;
-        if self.volume == target {
+        if self.retrigger_fade_step > 0.0 {
+            self.volume -= self.retrigger_fade_step;
+            if self.volume <= 0.0 {
+                self.volume = 0.0;
+                self.retrigger_fade_step = 0.0;
+                // Make the envelope start a new attack.
+                self.envelope_target_volume = 0;
+            }
             return;
         }
-        if self.volume < target {
-            self.volume += 1;
+        match (self.target.volume_mode, self.target.envelope) {
+            (VolumeMode::Stepped { levels }, _) => {
+                self.volume = quantize_volume(self.target.volume as f64, levels as u16);
+            }
+            (VolumeMode::Smooth, Some(envelope)) => self.move_along_envelope(envelope),
+            (VolumeMode::Smooth, None) => self.slew_to_target_volume(),
+        }
+    }
+
+    /// Returns how much the volume needs to move each sample to cover the
+    /// given distance in the given time.
+    fn step_per_sample(&self, distance: f64, ms: f64) -> f64 {
+        let samples = ms * self.sample_rate as f64 / 1000.0;
+        if samples < 1.0 {
+            distance
         } else {
-            self.volume -= 1;
+            distance / samples
+        }
+    }
+
+    fn move_along_envelope(&mut self, envelope: Envelope) {
+        let target = self.target.volume as f64;
+        let sustain = target * envelope.sustain_level.clamp(0.0, 1.0);
+        if self.target.volume != self.envelope_target_volume {
+            // Silencing the synth (including making it inactive) ends the
+            // note, turning up its volume starts a new one, and turning it
+            // down just decays to the new sustain level.
+            if self.target.volume == 0 {
+                self.envelope_stage = EnvelopeStage::Release;
+                self.envelope_step = self.step_per_sample(self.volume, envelope.release_ms);
+            } else if self.target.volume > self.envelope_target_volume {
+                self.envelope_stage = EnvelopeStage::Attack;
+                self.envelope_step = self.step_per_sample(target, envelope.attack_ms);
+            } else {
+                self.envelope_stage = EnvelopeStage::Decay;
+                self.envelope_step =
+                    self.step_per_sample((self.volume - sustain).abs(), envelope.decay_ms);
+            }
+            self.envelope_target_volume = self.target.volume;
+        }
+        match self.envelope_stage {
+            EnvelopeStage::Attack => {
+                self.volume = f64::min(self.volume + self.envelope_step, target);
+                if self.volume >= target {
+                    self.envelope_stage = EnvelopeStage::Decay;
+                    self.envelope_step = self.step_per_sample(target - sustain, envelope.decay_ms);
+                }
+            }
+            EnvelopeStage::Decay => {
+                self.volume = f64::max(self.volume - self.envelope_step, sustain);
+                if self.volume <= sustain {
+                    self.envelope_stage = EnvelopeStage::Sustain;
+                }
+            }
+            EnvelopeStage::Sustain => {
+                self.volume = sustain;
+            }
+            EnvelopeStage::Release => {
+                self.volume = f64::max(self.volume - self.envelope_step, 0.0);
+            }
         }
     }
+
+    fn slew_to_target_volume(&mut self) {
+        let target = self.target.volume as f64;
+        if self.volume < target {
+            self.volume = f64::min(self.volume + 1.0, target);
+        } else if self.volume > target {
+            self.volume = match self.release {
+                Release::Linear => f64::max(self.volume - 1.0, target),
+                Release::Exponential { time_constant_ms } => {
+                    let time_constant_samples = time_constant_ms * self.sample_rate as f64 / 1000.0;
+                    target + (self.volume - target) * exp(-1.0 / time_constant_samples)
+                }
+            };
+        }
+    }
+}
+
+/// An audio shape with independent parameters for the left and right
+/// channels, e.g. a pad that's detuned differently on each side. Unlike
+/// panning a mono shape, this produces a natively stereo signal.
+#[derive(Copy, Clone, Default)]
+pub struct StereoAudioShape {
+    pub left: AudioShape,
+    pub right: AudioShape,
+}
+
+impl StereoAudioShape {
+    /// Places a mono shape in the stereo field, from -1.0 (hard left)
+    /// through 0.0 (center) to 1.0 (hard right). Like a balance control,
+    /// panning only turns down the far side, so a centered shape is at
+    /// full level on both sides, the same as a mono shape.
+    pub fn panned(shape: AudioShape, pan: f64) -> Self {
+        let pan = pan.clamp(-1.0, 1.0);
+        // The result is never negative, so adding 0.5 rounds it (`f64::round`
+        // isn't available without std).
+        let scale_volume = |gain: f64| (shape.volume as f64 * gain + 0.5) as u8;
+        StereoAudioShape {
+            left: AudioShape {
+                volume: scale_volume((1.0 - pan).min(1.0)),
+                ..shape
+            },
+            right: AudioShape {
+                volume: scale_volume((1.0 + pan).min(1.0)),
+                ..shape
+            },
+        }
+    }
+}
+
+pub struct StereoAudioShapeSynthesizer {
+    left: AudioShapeSynthesizer,
+    right: AudioShapeSynthesizer,
+}
+
+impl StereoAudioShapeSynthesizer {
+    pub fn new(target: StereoAudioShape, sample_rate: usize) -> Self {
+        Self {
+            left: AudioShapeSynthesizer::new(target.left, sample_rate),
+            right: AudioShapeSynthesizer::new(target.right, sample_rate),
+        }
+    }
+
+    /// Returns the next (left, right) pair of samples.
+    pub fn next_frame(&mut self) -> (f64, f64) {
+        (self.left.next().unwrap(), self.right.next().unwrap())
+    }
+
+    pub fn make_inactive(&mut self) {
+        self.left.make_inactive();
+        self.right.make_inactive();
+    }
+
+    pub fn set_release(&mut self, release: Release) {
+        self.left.set_release(release);
+        self.right.set_release(release);
+    }
+
+    /// Both channels play the same noise, so that it stays where it's
+    /// panned rather than spreading out.
+    pub fn set_noise_seed(&mut self, seed: u64) {
+        self.left.set_noise_seed(seed);
+        self.right.set_noise_seed(seed);
+    }
+
+    pub fn has_finished_playing(&self) -> bool {
+        self.left.has_finished_playing() && self.right.has_finished_playing()
+    }
+
+    pub fn get_target(&self) -> StereoAudioShape {
+        StereoAudioShape {
+            left: self.left.get_target(),
+            right: self.right.get_target(),
+        }
+    }
+
+    pub fn update_target(&mut self, target: StereoAudioShape) {
+        self.left.update_target(target.left);
+        self.right.update_target(target.right);
+    }
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::synth::{lerp, triangle_wave};
+    use crate::synth::{
+        lerp, quantize_volume, triangle_wave, AudioShape, AudioShapeSynthesizer, Envelope, Release,
+        StereoAudioShape, StereoAudioShapeSynthesizer, VolumeMode, Waveform, TWO_PI,
+    };
 
     #[test]
     fn test_lerp_works() {
@@ -160,4 +722,525 @@ mod tests {
         assert_eq!(triangle_wave(0.75), -1.0);
         assert_eq!(triangle_wave(1.0), 0.0);
     }
+
+    fn samples_until_finished(synth: &mut AudioShapeSynthesizer) -> usize {
+        synth.make_inactive();
+        let mut samples = 0;
+        while !synth.has_finished_playing() {
+            synth.next();
+            samples += 1;
+        }
+        samples
+    }
+
+    fn full_volume_synth() -> AudioShapeSynthesizer {
+        let mut synth = AudioShapeSynthesizer::new(
+            AudioShape {
+                waveform: Waveform::Sine,
+                frequency: 440.0,
+                volume: 255,
+                ..Default::default()
+            },
+            44100,
+        );
+        for _ in 0..255 {
+            synth.next();
+        }
+        synth
+    }
+
+    #[test]
+    fn test_noise_is_bounded_and_irregular() {
+        let mut synth = AudioShapeSynthesizer::new(
+            AudioShape {
+                waveform: Waveform::Noise,
+                frequency: 0.0,
+                volume: 255,
+                ..Default::default()
+            },
+            44100,
+        );
+        let samples: Vec<f64> = synth.by_ref().skip(255).take(1000).collect();
+        assert!(samples.iter().all(|sample| (-1.0..=1.0).contains(sample)));
+        let positive = samples.iter().filter(|sample| **sample > 0.0).count();
+        assert!((400..600).contains(&positive), "{}", positive);
+    }
+
+    #[test]
+    fn test_linear_release_finishes_at_zero() {
+        assert_eq!(samples_until_finished(&mut full_volume_synth()), 255);
+    }
+
+    #[test]
+    fn test_exponential_release_finishes_at_silence_threshold() {
+        let mut synth = full_volume_synth();
+        synth.set_release(Release::Exponential {
+            time_constant_ms: 10.0,
+        });
+        // Falling to -60 dB takes ln(1000) time constants, or about 69 ms.
+        let samples = samples_until_finished(&mut synth);
+        assert!(samples > 3000 && samples < 3100, "{}", samples);
+
+        let mut synth = full_volume_synth();
+        synth.set_release(Release::Exponential {
+            time_constant_ms: 10.0,
+        });
+        synth.set_silence_threshold(0.01);
+        let samples = samples_until_finished(&mut synth);
+        assert!(samples > 2000 && samples < 2070, "{}", samples);
+    }
+
+    #[test]
+    fn test_panned_works() {
+        let shape = AudioShape {
+            waveform: Waveform::Sine,
+            frequency: 440.0,
+            volume: 200,
+            ..Default::default()
+        };
+        let volumes = |pan: f64| {
+            let stereo = StereoAudioShape::panned(shape, pan);
+            (stereo.left.volume, stereo.right.volume)
+        };
+        assert_eq!(volumes(-1.0), (200, 0));
+        assert_eq!(volumes(0.0), (200, 200));
+        assert_eq!(volumes(-0.5), (200, 100));
+        assert_eq!(volumes(1.0), (0, 200));
+        assert_eq!(volumes(-5.0), (200, 0));
+    }
+
+    #[test]
+    fn test_stereo_detuned_shape_differs_between_channels() {
+        let shape = AudioShape {
+            waveform: Waveform::Sine,
+            frequency: 440.0,
+            volume: 255,
+            ..Default::default()
+        };
+        let mut synth = StereoAudioShapeSynthesizer::new(
+            StereoAudioShape {
+                left: shape,
+                right: AudioShape {
+                    frequency: 443.0,
+                    ..shape
+                },
+            },
+            44100,
+        );
+        let frames: Vec<(f64, f64)> = (0..4410).map(|_| synth.next_frame()).collect();
+        let max_difference = frames
+            .iter()
+            .map(|(left, right)| (left - right).abs())
+            .fold(0.0, f64::max);
+        assert!(max_difference > 0.01);
+    }
+
+    #[test]
+    fn test_stereo_identical_shape_matches_between_channels() {
+        let shape = AudioShape {
+            waveform: Waveform::Square,
+            frequency: 440.0,
+            volume: 255,
+            ..Default::default()
+        };
+        let mut synth = StereoAudioShapeSynthesizer::new(
+            StereoAudioShape {
+                left: shape,
+                right: shape,
+            },
+            44100,
+        );
+        for _ in 0..4410 {
+            let (left, right) = synth.next_frame();
+            assert_eq!(left, right);
+        }
+    }
+
+    fn envelope_synth(sample_rate: usize) -> AudioShapeSynthesizer {
+        AudioShapeSynthesizer::new(
+            AudioShape {
+                waveform: Waveform::Sine,
+                frequency: 440.0,
+                volume: 200,
+                envelope: Some(Envelope {
+                    attack_ms: 20.0,
+                    decay_ms: 40.0,
+                    sustain_level: 0.5,
+                    release_ms: 80.0,
+                }),
+                ..Default::default()
+            },
+            sample_rate,
+        )
+    }
+
+    /// Generates the given number of samples, then returns the volume as
+    /// a fraction of the synth's target volume.
+    fn volume_after(synth: &mut AudioShapeSynthesizer, samples: usize) -> f64 {
+        for _ in 0..samples {
+            synth.next();
+        }
+        synth.volume / 200.0
+    }
+
+    fn assert_volume_after(synth: &mut AudioShapeSynthesizer, samples: usize, expected: f64) {
+        let volume = volume_after(synth, samples);
+        assert!(
+            (volume - expected).abs() < 1e-9,
+            "volume is {}, expected {}",
+            volume,
+            expected
+        );
+    }
+
+    fn assert_envelope_works(sample_rate: usize) {
+        let ten_ms = sample_rate / 100;
+        let mut synth = envelope_synth(sample_rate);
+        // Halfway through the attack, then at its peak.
+        assert_volume_after(&mut synth, ten_ms, 0.5);
+        assert_volume_after(&mut synth, ten_ms, 1.0);
+        // Halfway through the decay, then at the sustain level.
+        assert_volume_after(&mut synth, ten_ms * 2, 0.75);
+        assert_volume_after(&mut synth, ten_ms * 2, 0.5);
+        assert_volume_after(&mut synth, ten_ms * 50, 0.5);
+
+        // Halfway through the release, then silent.
+        synth.make_inactive();
+        assert_volume_after(&mut synth, ten_ms * 4, 0.25);
+        assert!(!synth.has_finished_playing());
+        assert_volume_after(&mut synth, ten_ms * 4, 0.0);
+        assert!(synth.has_finished_playing());
+    }
+
+    #[test]
+    fn test_envelope_reaches_each_stage_on_time() {
+        assert_envelope_works(44100);
+        assert_envelope_works(48000);
+    }
+
+    #[test]
+    fn test_envelope_restarts_attack_when_volume_is_raised() {
+        let mut synth = envelope_synth(1000);
+        volume_after(&mut synth, 100);
+        synth.update_target(AudioShape {
+            volume: 0,
+            ..synth.get_target()
+        });
+        assert_volume_after(&mut synth, 80, 0.0);
+        synth.update_target(AudioShape {
+            volume: 200,
+            ..synth.get_target()
+        });
+        assert_volume_after(&mut synth, 10, 0.5);
+    }
+
+    #[test]
+    fn test_quantize_volume_works() {
+        assert_eq!(quantize_volume(0.0, 16), 0.0);
+        assert_eq!(quantize_volume(255.0, 16), 255.0);
+        assert_eq!(quantize_volume(127.0, 16), 119.0);
+        assert_eq!(quantize_volume(130.0, 16), 136.0);
+        assert_eq!(quantize_volume(100.0, 2), 0.0);
+    }
+
+    #[test]
+    fn test_stepped_volume_jumps_immediately() {
+        let mut synth = AudioShapeSynthesizer::new(
+            AudioShape {
+                waveform: Waveform::Square,
+                frequency: 0.0,
+                volume: 255,
+                volume_mode: VolumeMode::CHIPTUNE,
+                ..Default::default()
+            },
+            44100,
+        );
+        assert_eq!(synth.next(), Some(0.0));
+        assert_eq!(synth.next(), Some(1.0));
+        synth.update_target(AudioShape {
+            volume: 127,
+            ..synth.get_target()
+        });
+        synth.next();
+        assert_eq!(synth.next(), Some(119.0 / 255.0));
+        synth.make_inactive();
+        synth.next();
+        assert!(synth.has_finished_playing());
+    }
+
+    #[test]
+    fn test_volume_steps_limit_output_levels() {
+        let mut synth = AudioShapeSynthesizer::new(
+            AudioShape {
+                waveform: Waveform::Square,
+                frequency: 0.0,
+                volume: 255,
+                envelope: Some(Envelope {
+                    attack_ms: 10.0,
+                    decay_ms: 10.0,
+                    sustain_level: 0.5,
+                    release_ms: 10.0,
+                }),
+                volume_steps: 4,
+                ..Default::default()
+            },
+            44100,
+        );
+        let mut outputs: Vec<f64> = (0..44100).map(|_| synth.next().unwrap()).collect();
+        synth.make_inactive();
+        outputs.extend((0..44100).map(|_| synth.next().unwrap()));
+        outputs.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        outputs.dedup();
+        assert_eq!(outputs, vec![0.0, 1.0 / 3.0, 2.0 / 3.0, 1.0]);
+    }
+
+    #[test]
+    fn test_full_volume_steps_leave_volume_alone() {
+        let mut synth = AudioShapeSynthesizer::new(
+            AudioShape {
+                waveform: Waveform::Square,
+                frequency: 0.0,
+                volume: 255,
+                ..Default::default()
+            },
+            44100,
+        );
+        assert_eq!(synth.next(), Some(0.0));
+        assert_eq!(synth.next(), Some(1.0 / 255.0));
+    }
+
+    /// Returns the energy of the synth's output in the upper half of the
+    /// audible range, leaving out the wave's own harmonics, which is to
+    /// say the energy that's been aliased there.
+    fn aliased_energy_in_upper_half(waveform: Waveform, band_limited: bool) -> f64 {
+        const SAMPLE_RATE: usize = 44100;
+        const FREQUENCY: f64 = 1030.0;
+        // A tenth of a second, so every DFT bin is 10 Hz wide.
+        const LEN: usize = SAMPLE_RATE / 10;
+        let synth = AudioShapeSynthesizer::new(
+            AudioShape {
+                waveform,
+                frequency: FREQUENCY,
+                volume: 255,
+                band_limited,
+                ..Default::default()
+            },
+            SAMPLE_RATE,
+        );
+        let samples: Vec<f64> = synth.skip(1000).take(LEN).collect();
+        (LEN / 4..LEN / 2)
+            .filter(|bin| (*bin as f64 * 10.0) % FREQUENCY != 0.0)
+            .map(|bin| {
+                let (mut re, mut im) = (0.0, 0.0);
+                for (i, sample) in samples.iter().enumerate() {
+                    let angle = TWO_PI * (bin * i) as f64 / LEN as f64;
+                    re += sample * angle.cos();
+                    im -= sample * angle.sin();
+                }
+                re * re + im * im
+            })
+            .sum()
+    }
+
+    #[test]
+    fn test_band_limiting_reduces_aliasing() {
+        for waveform in [Waveform::Sawtooth, Waveform::Square] {
+            let naive = aliased_energy_in_upper_half(waveform, false);
+            let band_limited = aliased_energy_in_upper_half(waveform, true);
+            assert!(band_limited < naive / 10.0, "{} vs {}", band_limited, naive);
+        }
+    }
+
+    #[test]
+    fn test_band_limited_waves_match_naive_ones_away_from_jumps() {
+        let render = |waveform, band_limited| -> Vec<f64> {
+            AudioShapeSynthesizer::new(
+                AudioShape {
+                    waveform,
+                    frequency: 100.0,
+                    volume: 255,
+                    volume_mode: VolumeMode::Stepped { levels: 2 },
+                    band_limited,
+                    ..Default::default()
+                },
+                44100,
+            )
+            .take(441)
+            .collect()
+        };
+        for waveform in [Waveform::Sawtooth, Waveform::Square] {
+            let naive = render(waveform, false);
+            let band_limited = render(waveform, true);
+            // Skip the first sample, before the volume has jumped up.
+            for i in [10, 100, 300, 400] {
+                assert!(
+                    (naive[i] - band_limited[i]).abs() < 1e-9,
+                    "sample {}: {} vs {}",
+                    i,
+                    naive[i],
+                    band_limited[i]
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_band_limiting_leaves_frequency_zero_alone() {
+        for waveform in [Waveform::Sawtooth, Waveform::Square] {
+            let render = |band_limited| -> Vec<f64> {
+                AudioShapeSynthesizer::new(
+                    AudioShape {
+                        waveform,
+                        frequency: 0.0,
+                        volume: 255,
+                        band_limited,
+                        ..Default::default()
+                    },
+                    44100,
+                )
+                .take(100)
+                .collect()
+            };
+            assert_eq!(render(true), render(false));
+        }
+    }
+
+    /// Bends the synth's pitch up by a semitone in small steps, a few
+    /// samples apart, and returns the wave delta at every sample until
+    /// shortly after.
+    fn deltas_during_bend(frequency_smoothing_ms: f64) -> Vec<f64> {
+        let mut synth = AudioShapeSynthesizer::new(
+            AudioShape {
+                waveform: Waveform::Sine,
+                frequency: 440.0,
+                volume: 255,
+                volume_mode: VolumeMode::Stepped { levels: 2 },
+                frequency_smoothing_ms,
+                ..Default::default()
+            },
+            44100,
+        );
+        synth.next();
+        let mut deltas = vec![];
+        for step in 1..=16 {
+            synth.update_target(AudioShape {
+                frequency: 440.0 * 2.0_f64.powf(step as f64 / (16.0 * 12.0)),
+                ..synth.get_target()
+            });
+            for _ in 0..32 {
+                synth.next();
+                deltas.push(synth.wave_delta_per_sample);
+            }
+        }
+        // Give the smoothing time to settle on the last step.
+        for _ in 0..441 {
+            synth.next();
+            deltas.push(synth.wave_delta_per_sample);
+        }
+        deltas
+    }
+
+    fn largest_jump(deltas: &[f64]) -> f64 {
+        deltas
+            .windows(2)
+            .map(|pair| (pair[1] - pair[0]).abs())
+            .fold(0.0, f64::max)
+    }
+
+    #[test]
+    fn test_frequency_smoothing_removes_steps_from_rapid_changes() {
+        let stepped = deltas_during_bend(0.0);
+        let smoothed = deltas_during_bend(1.0);
+        assert!(
+            largest_jump(&smoothed) < largest_jump(&stepped) / 10.0,
+            "{} vs {}",
+            largest_jump(&smoothed),
+            largest_jump(&stepped)
+        );
+        // Without smoothing, the delta only moves when the frequency is
+        // set; with it, the delta keeps moving in between.
+        let changes = |deltas: &[f64]| deltas.windows(2).filter(|pair| pair[0] != pair[1]).count();
+        assert_eq!(changes(&stepped), 15);
+        assert!(changes(&smoothed) > stepped.len() / 2);
+        // Either way, the bend ends up in the same place.
+        let last = |deltas: &[f64]| *deltas.last().unwrap();
+        assert!((last(&smoothed) - last(&stepped)).abs() < last(&stepped) * 1e-3);
+    }
+
+    #[test]
+    fn test_frequency_smoothing_leaves_new_notes_alone() {
+        let mut synth = AudioShapeSynthesizer::new(
+            AudioShape {
+                frequency: 440.0,
+                frequency_smoothing_ms: 1.0,
+                ..Default::default()
+            },
+            44100,
+        );
+        synth.update_target(AudioShape {
+            frequency: 880.0,
+            volume: 255,
+            ..synth.get_target()
+        });
+        assert_eq!(synth.wave_delta_per_sample, 880.0 / 44100.0);
+    }
+
+    #[test]
+    fn test_retriggering_a_sounding_note_does_not_click() {
+        let mut synth = AudioShapeSynthesizer::new(
+            AudioShape {
+                waveform: Waveform::Sine,
+                frequency: 440.0,
+                volume: 255,
+                ..Default::default()
+            },
+            44100,
+        );
+        let mut samples: Vec<f64> = synth.by_ref().take(1000).collect();
+        synth.retrigger();
+        let fade = 3 * 44100 / 1000;
+        samples.extend(synth.by_ref().take(1000));
+        let largest_jump = samples
+            .windows(2)
+            .map(|pair| (pair[1] - pair[0]).abs())
+            .fold(0.0, f64::max);
+        // A full-volume 440 Hz sine moves by up to about 0.063 per sample
+        // on its own.
+        assert!(largest_jump < 0.08, "{}", largest_jump);
+        // It still goes silent before the new attack.
+        assert!(samples[1000 + fade + 1].abs() < 0.01);
+        assert!(samples[1999].abs() > 0.0);
+    }
+
+    #[test]
+    fn test_overlapping_crossfades_do_not_jump() {
+        let mut synth = AudioShapeSynthesizer::new(
+            AudioShape {
+                waveform: Waveform::Sine,
+                frequency: 440.0,
+                volume: 255,
+                volume_mode: VolumeMode::Stepped { levels: 2 },
+                crossfade_ms: 5.0,
+                ..Default::default()
+            },
+            44100,
+        );
+        let mut samples: Vec<f64> = synth.by_ref().take(1000).collect();
+        // Each change comes well within the 220-sample fade of the last.
+        for frequency in [700.0, 300.0, 900.0, 250.0] {
+            synth.update_target(AudioShape {
+                frequency,
+                ..synth.get_target()
+            });
+            samples.extend(synth.by_ref().take(37));
+        }
+        samples.extend(synth.by_ref().take(500));
+        let largest_jump = samples[1..]
+            .windows(2)
+            .map(|pair| (pair[1] - pair[0]).abs())
+            .fold(0.0, f64::max);
+        // A full-volume 900 Hz sine moves by up to about 0.13 per sample
+        // on its own.
+        assert!(largest_jump < 0.14, "{}", largest_jump);
+    }
 }
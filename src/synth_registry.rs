@@ -1,11 +1,14 @@
 use std::collections::HashMap;
 
-use crate::synth::{AudioShape, AudioShapeSynthesizer};
+use crate::synth::{
+    AudioShape, AudioShapeSynthesizer, StereoAudioShape, StereoAudioShapeSynthesizer,
+};
 
 pub struct SynthRegistry {
     total_samples: usize,
     latest_id: usize,
     map: HashMap<usize, AudioShapeSynthesizer>,
+    stereo_map: HashMap<usize, StereoAudioShapeSynthesizer>,
 }
 
 impl SynthRegistry {
@@ -14,45 +17,73 @@ impl SynthRegistry {
             total_samples: 0,
             latest_id: 0,
             map: HashMap::new(),
+            stereo_map: HashMap::new(),
         }
     }
 
     pub fn remove_finished_synths(&mut self) {
-        self.map.retain(|_id, synth| {
-            return !synth.has_finished_playing();
-        });
+        self.map.retain(|_id, synth| !synth.has_finished_playing());
+        self.stereo_map
+            .retain(|_id, synth| !synth.has_finished_playing());
     }
 
     pub fn modify<F: FnOnce(&mut AudioShapeSynthesizer)>(&mut self, id: usize, f: F) {
         self.map.entry(id).and_modify(f);
     }
 
-    pub fn insert(&mut self, synth: AudioShapeSynthesizer) -> usize {
+    pub fn modify_stereo<F: FnOnce(&mut StereoAudioShapeSynthesizer)>(&mut self, id: usize, f: F) {
+        self.stereo_map.entry(id).and_modify(f);
+    }
+
+    fn next_id(&mut self) -> usize {
         self.latest_id += 1;
-        let id = self.latest_id;
+        self.latest_id
+    }
+
+    pub fn insert(&mut self, synth: AudioShapeSynthesizer) -> usize {
+        let id = self.next_id();
         let prev_value = self.map.insert(id, synth);
         assert!(prev_value.is_none());
-        return id;
+        id
+    }
+
+    pub fn insert_stereo(&mut self, synth: StereoAudioShapeSynthesizer) -> usize {
+        let id = self.next_id();
+        let prev_value = self.stereo_map.insert(id, synth);
+        assert!(prev_value.is_none());
+        id
     }
 
     pub fn get_shape(&self, id: &usize) -> Option<AudioShape> {
         self.map.get(id).map(|synth| synth.get_target())
     }
 
+    pub fn get_stereo_shape(&self, id: &usize) -> Option<StereoAudioShape> {
+        self.stereo_map.get(id).map(|synth| synth.get_target())
+    }
+
     pub fn get_total_samples(&self) -> usize {
         self.total_samples
     }
 
-    pub fn next_sample(&mut self) -> f64 {
-        let mut value = 0.0;
+    /// Returns the next (left, right) pair of samples of the mix. Mono
+    /// synths contribute equally to both channels.
+    pub fn next_frame(&mut self) -> (f64, f64) {
+        let mut mono = 0.0;
         for (_id, synth) in self.map.iter_mut() {
-            value += synth.next().unwrap();
+            mono += synth.next().unwrap();
+        }
+        let (mut left, mut right) = (mono, mono);
+        for (_id, synth) in self.stereo_map.iter_mut() {
+            let (synth_left, synth_right) = synth.next_frame();
+            left += synth_left;
+            right += synth_right;
         }
         self.total_samples += 1;
-        value
+        (left, right)
     }
 
     pub fn is_empty(&self) -> bool {
-        self.map.is_empty()
+        self.map.is_empty() && self.stereo_map.is_empty()
     }
 }
@@ -4,27 +4,33 @@ use std::{
     task::{Context, Poll},
 };
 
-pub struct Waiter<F: Fn() -> f64> {
+pub struct Waiter<F: Fn() -> f64, G: Fn(f64)> {
     end: f64,
     get_current_time: F,
+    on_pending: G,
 }
 
-impl<F: Fn() -> f64> Waiter<F> {
-    pub fn new(ms: f64, get_current_time: F) -> Self {
+impl<F: Fn() -> f64, G: Fn(f64)> Waiter<F, G> {
+    /// Creates a future that waits for the given number of milliseconds.
+    /// Whenever it's polled before then, `on_pending` is called with the
+    /// time it's waiting for.
+    pub fn new(ms: f64, get_current_time: F, on_pending: G) -> Self {
         Self {
             end: get_current_time() + ms,
             get_current_time,
+            on_pending,
         }
     }
 }
 
-impl<F: Fn() -> f64> Future for Waiter<F> {
+impl<F: Fn() -> f64, G: Fn(f64)> Future for Waiter<F, G> {
     type Output = ();
 
     fn poll(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<()> {
         if (self.get_current_time)() >= self.end {
             Poll::Ready(())
         } else {
+            (self.on_pending)(self.end);
             Poll::Pending
         }
     }
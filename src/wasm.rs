@@ -0,0 +1,77 @@
+// Bindings for running the synth in a browser, e.g. from a Web Audio
+// `AudioWorkletProcessor`. See the README for how to build them.
+
+use wasm_bindgen::prelude::*;
+
+use crate::{
+    beat::{Beat, BeatSettings, FOUR_FOUR},
+    instrument::Instrument,
+    note::MidiNote,
+    player::{FrameRenderer, Player, WAV_SAMPLE_RATE},
+    synth::Waveform,
+};
+
+/// Parses a space-separated list of notes, e.g. "C4 E4 G4", into a melody
+/// of quarter notes.
+fn parse_melody(notes: &str) -> Result<Vec<(MidiNote, Beat)>, JsValue> {
+    notes
+        .split_whitespace()
+        .map(|note| match MidiNote::parse(&note) {
+            Ok(note) => Ok((note, Beat::Quarter)),
+            Err(err) => Err(JsValue::from_str(&format!(
+                "Invalid note '{}': {:?}",
+                note, err
+            ))),
+        })
+        .collect()
+}
+
+async fn melody_program(melody: Vec<(MidiNote, Beat)>, bpm: u64) {
+    let mut instrument = Instrument::new(BeatSettings::new(bpm, FOUR_FOUR), 127, Waveform::Square);
+    instrument.play_melody(&melody).await;
+}
+
+/// Plays a melody a block at a time, at whatever sample rate the audio
+/// context is running at.
+#[wasm_bindgen]
+pub struct WasmSynth {
+    renderer: FrameRenderer,
+}
+
+#[wasm_bindgen]
+impl WasmSynth {
+    #[wasm_bindgen(constructor)]
+    pub fn new(notes: &str, bpm: u32, sample_rate: u32) -> Result<WasmSynth, JsValue> {
+        let melody = parse_melody(notes)?;
+        Ok(WasmSynth {
+            renderer: FrameRenderer::new(sample_rate as usize, melody_program(melody, bpm as u64)),
+        })
+    }
+
+    /// Fills `out` with interleaved stereo samples and returns how many
+    /// frames were written. See `FrameRenderer::render`.
+    pub fn render(&mut self, out: &mut [f32]) -> usize {
+        self.renderer.render(out)
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn finished(&self) -> bool {
+        self.renderer.is_finished()
+    }
+}
+
+/// Renders a whole melody up front to interleaved stereo samples at
+/// `WAV_SAMPLE_RATE`, e.g. to fill an `AudioBuffer`.
+#[wasm_bindgen(js_name = renderNotesToVec)]
+pub fn render_notes_to_vec(notes: &str, bpm: u32) -> Result<Vec<f32>, JsValue> {
+    let melody = parse_melody(notes)?;
+    Ok(Player::render_to_stereo_vec(melody_program(
+        melody, bpm as u64,
+    )))
+}
+
+/// The sample rate that `render_notes_to_vec` renders at.
+#[wasm_bindgen(js_name = wavSampleRate)]
+pub fn wav_sample_rate() -> u32 {
+    WAV_SAMPLE_RATE
+}